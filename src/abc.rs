@@ -0,0 +1,982 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use midix::prelude::*;
+
+use crate::notation;
+use crate::song::{
+    Hand, KeySignatureChange, NoteBlock, Song, SongParts, TempoChange, TimeSignatureChange, Track,
+    odd_measure_signature,
+};
+
+const TICKS_PER_QUARTER_NOTE: u32 = 480;
+const WHOLE_NOTE: u32 = TICKS_PER_QUARTER_NOTE * 4;
+const DEFAULT_VELOCITY: u8 = 80;
+const DRUM_CHANNEL: u32 = 9;
+const MIDDLE_C: u8 = 60;
+/// Semitones above C of the letters C to B.
+const NATURALS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+pub fn is_abc(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("abc"))
+}
+
+struct AbcNote {
+    key: u8,
+    /// Ticks from the start of the measure.
+    offset: u32,
+    length: u32,
+    /// Tied to the next note of the same key.
+    tie: bool,
+}
+
+/// The notes between two bar lines, and how the bar lines around them
+/// repeat.
+#[derive(Default)]
+struct AbcMeasure {
+    notes: Vec<AbcNote>,
+    length: u32,
+    repeat_start: bool,
+    repeat_end: bool,
+    /// The passes through the repeat this measure is played on, for first
+    /// and second endings.
+    ending: Option<Vec<u32>>,
+    /// Changes at the start of the measure.
+    meter: Option<(u8, u8)>,
+    tempo: Option<u32>,
+    key: Option<(i8, bool)>,
+}
+
+impl AbcMeasure {
+    fn is_empty(&self) -> bool {
+        self.notes.is_empty() && self.length == 0
+    }
+}
+
+#[derive(Default)]
+struct Voice {
+    name: Option<String>,
+    bass: bool,
+    measures: Vec<AbcMeasure>,
+    current: AbcMeasure,
+    /// Accidentals so far in the measure, by letter and octave.
+    accidentals: HashMap<(u8, i32), i32>,
+    /// Notes left in a tuplet, and the ratio their lengths are scaled by.
+    tuplet: Option<(u32, u32, u32)>,
+    /// Ratio to scale the next note by, the second half of a broken rhythm.
+    broken: Option<(u32, u32)>,
+    /// Where the last note, chord or rest starts: index of its first note,
+    /// offset and length.
+    last_group: Option<(usize, u32, u32)>,
+    ending: Option<Vec<u32>>,
+}
+
+impl Voice {
+    /// Ends the measure at a bar line. The bar line's repeat end belongs to
+    /// the measure before it, its repeat start and ending to the next one.
+    fn bar(&mut self, repeat_end: bool, repeat_start: bool, closes_ending: bool) {
+        if self.current.is_empty() {
+            if repeat_end && let Some(last) = self.measures.last_mut() {
+                last.repeat_end = true;
+            }
+        } else {
+            self.current.repeat_end |= repeat_end;
+            let measure = std::mem::take(&mut self.current);
+            self.measures.push(measure);
+        }
+        if repeat_end || repeat_start || closes_ending {
+            self.ending = None;
+        }
+        self.current.repeat_start |= repeat_start;
+        self.current.ending = self.ending.clone();
+        self.accidentals.clear();
+        self.broken = None;
+        self.last_group = None;
+    }
+
+    fn start_ending(&mut self, passes: Vec<u32>) {
+        self.ending = Some(passes);
+        self.current.ending = self.ending.clone();
+    }
+
+    /// Adds a chord (a rest with no keys) of `length` ticks, before tuplets
+    /// and broken rhythms.
+    fn push_group(&mut self, keys: &[u8], mut length: u32) {
+        if let Some((num, den)) = self.broken.take() {
+            length = length * num / den;
+        }
+        if let Some((p, q, remaining)) = self.tuplet {
+            length = length * q / p;
+            self.tuplet = (remaining > 1).then_some((p, q, remaining - 1));
+        }
+        let offset = self.current.length;
+        self.last_group = Some((self.current.notes.len(), offset, length));
+        for key in keys {
+            self.current.notes.push(AbcNote {
+                key: *key,
+                offset,
+                length,
+                tie: false,
+            });
+        }
+        self.current.length += length;
+    }
+
+    /// `>` or `<` after a note, `count` of them: the note before gets longer
+    /// and the next one shorter, or the other way around.
+    fn break_rhythm(&mut self, longer_first: bool, count: u32) {
+        let Some((index, offset, length)) = self.last_group else {
+            return;
+        };
+        let den = 1 << count;
+        let (first, second) = ((den * 2 - 1, den), (1, den));
+        let (first, second) = if longer_first {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        let new_length = length * first.0 / first.1;
+        for note in self.current.notes[index..].iter_mut() {
+            note.length = new_length;
+        }
+        self.current.length = offset + new_length;
+        self.last_group = Some((index, offset, new_length));
+        self.broken = Some(second);
+    }
+
+    fn tie_last_group(&mut self) {
+        if let Some((index, _, _)) = self.last_group {
+            for note in self.current.notes[index..].iter_mut() {
+                note.tie = true;
+            }
+        }
+    }
+
+    /// Ties the notes of the last chord with one of `keys`.
+    fn tie_last_group_keys(&mut self, keys: &[u8]) {
+        if let Some((index, _, _)) = self.last_group {
+            for note in self.current.notes[index..].iter_mut() {
+                note.tie |= keys.contains(&note.key);
+            }
+        }
+    }
+}
+
+/// Key signature of a `K:` field, as sharps (flats when negative) and
+/// whether it's minor. Modes count from the major key of their tonic.
+fn parse_key(field: &str) -> Option<(i8, bool)> {
+    let field = field.trim();
+    // a field with only a clef in it keeps the key
+    if field.split_whitespace().next()?.contains('=') {
+        return None;
+    }
+    let mut chars = field.chars().peekable();
+    let tonic = chars.next()?.to_ascii_uppercase();
+    let mut fifths: i32 = match tonic {
+        'C' => 0,
+        'G' => 1,
+        'D' => 2,
+        'A' => 3,
+        'E' => 4,
+        'B' => 5,
+        'F' => -1,
+        _ => return None,
+    };
+    match chars.peek() {
+        Some('#') => {
+            fifths += 7;
+            chars.next();
+        }
+        Some('b') => {
+            fifths -= 7;
+            chars.next();
+        }
+        _ => (),
+    }
+    let mode: String = chars
+        .take_while(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    let (shift, minor) = match mode.get(..3.min(mode.len())).unwrap_or("") {
+        "" | "maj" | "ion" => (0, false),
+        "m" | "min" | "aeo" => (-3, true),
+        "mix" => (-1, false),
+        "dor" => (-2, false),
+        "phr" => (-4, false),
+        "lyd" => (1, false),
+        "loc" => (-5, false),
+        _ => (0, false),
+    };
+    Some(((fifths + shift).clamp(-7, 7) as i8, minor))
+}
+
+/// `M:` field, `C` being common time and `C|` cut time.
+fn parse_meter(field: &str) -> Option<(u8, u8)> {
+    match field.trim() {
+        "C" => Some((4, 4)),
+        "C|" => Some((2, 2)),
+        field => {
+            let (num, den) = field.split_once('/')?;
+            let num = num
+                .split('+')
+                .map(|n| n.trim().parse::<u8>().ok())
+                .sum::<Option<u8>>()?;
+            Some((num, den.trim().parse().ok()?))
+        }
+    }
+}
+
+/// A fraction like `1/8`, of a whole note.
+fn parse_fraction(text: &str) -> Option<(u32, u32)> {
+    let (num, den) = text.trim().split_once('/')?;
+    Some((num.trim().parse().ok()?, den.trim().parse().ok()?))
+}
+
+/// `Q:` field as microseconds per quarter note, the beat being the unit
+/// note length when the field doesn't say.
+fn parse_tempo(field: &str, unit: (u32, u32)) -> Option<u32> {
+    // text in quotes, like "Allegro", says nothing exact
+    let field: String = field.split('"').step_by(2).collect();
+    let (beat, bpm) = match field.split_once('=') {
+        Some((beat, bpm)) => {
+            let beat = beat
+                .split_whitespace()
+                .filter_map(parse_fraction)
+                .fold((0, 1), |(n, d), (bn, bd)| (n * bd + bn * d, d * bd));
+            let beat = if beat.0 == 0 { unit } else { beat };
+            (beat, bpm.trim().parse::<f32>().ok()?)
+        }
+        None => (unit, field.trim().parse::<f32>().ok()?),
+    };
+    let quarters_per_beat = 4. * beat.0 as f32 / beat.1 as f32;
+    (bpm > 0.).then(|| (60_000_000. / (bpm * quarters_per_beat)) as u32)
+}
+
+/// `p` notes in the time of `q`, the default `q` of `(5`, `(7` and `(9`
+/// depending on whether the meter is compound.
+fn tuplet_ratio(p: u32, meter: (u8, u8)) -> u32 {
+    match p {
+        2 | 4 | 8 => 3,
+        3 | 6 => 2,
+        _ if meter.0.is_multiple_of(3) && meter.0 > 3 => 3,
+        _ => 2,
+    }
+}
+
+fn parse_number(chars: &[char], i: &mut usize) -> Option<u32> {
+    let start = *i;
+    while *i < chars.len() && chars[*i].is_ascii_digit() {
+        *i += 1;
+    }
+    chars[start..*i].iter().collect::<String>().parse().ok()
+}
+
+/// Length after a note, like `2`, `/`, `//`, `3/2`, as a ratio of the unit
+/// note length.
+fn parse_length(chars: &[char], i: &mut usize) -> (u32, u32) {
+    let num = parse_number(chars, i).unwrap_or(1);
+    let mut den = 1;
+    while *i < chars.len() && chars[*i] == '/' {
+        *i += 1;
+        den *= parse_number(chars, i).unwrap_or(2);
+    }
+    (num, den)
+}
+
+/// Ending passes after a bar line, like `1`, `1,3` or `1-2`.
+fn parse_ending(chars: &[char], i: &mut usize) -> Vec<u32> {
+    let mut passes = vec![];
+    while let Some(first) = parse_number(chars, i) {
+        if *i < chars.len() && chars[*i] == '-' {
+            *i += 1;
+            let last = parse_number(chars, i).unwrap_or(first);
+            passes.extend(first..=last);
+        } else {
+            passes.push(first);
+        }
+        if *i < chars.len() && chars[*i] == ',' {
+            *i += 1;
+        } else {
+            break;
+        }
+    }
+    passes
+}
+
+/// Everything the tune says before and between its notes.
+struct Tune {
+    title: Option<String>,
+    composer: Option<String>,
+    meter: (u8, u8),
+    unit: Option<(u32, u32)>,
+    sharps: i8,
+    voices: Vec<Voice>,
+    voice_ids: Vec<String>,
+    current_voice: usize,
+}
+
+impl Tune {
+    fn unit(&self) -> (u32, u32) {
+        self.unit.unwrap_or(
+            if (self.meter.0 as f32 / self.meter.1.max(1) as f32) < 0.75 {
+                (1, 16)
+            } else {
+                (1, 8)
+            },
+        )
+    }
+
+    fn voice(&mut self) -> &mut Voice {
+        &mut self.voices[self.current_voice]
+    }
+
+    /// Switches to the voice of a `V:` field, adding it the first time,
+    /// with its name and clef.
+    fn select_voice(&mut self, field: &str) {
+        let mut words = field.split_whitespace();
+        let Some(id) = words.next() else {
+            return;
+        };
+        let index = match self.voice_ids.iter().position(|v| v == id) {
+            Some(index) => index,
+            // notes before the first V: field belong to the first voice
+            None if self.voice_ids.is_empty() && !self.voices.is_empty() => {
+                self.voice_ids.push(id.to_string());
+                0
+            }
+            None => {
+                self.voice_ids.push(id.to_string());
+                self.voices.push(Voice::default());
+                self.voices.len() - 1
+            }
+        };
+        self.current_voice = index;
+
+        let rest = field[field.find(id).unwrap_or(0) + id.len()..].to_string();
+        if rest.contains("clef=bass") || rest.contains("clef=F") {
+            self.voices[index].bass = true;
+        }
+        for key in ["name=", "nm="] {
+            if let Some(start) = rest.find(key) {
+                let name = rest[start + key.len()..].trim_start_matches('"');
+                let end = name.find('"').unwrap_or(name.len());
+                self.voices[index].name = Some(name[..end].trim().to_string());
+            }
+        }
+    }
+
+    /// A field in the middle of the tune, on a line of its own or inline in
+    /// brackets.
+    fn field(&mut self, name: char, value: &str) {
+        match name {
+            'M' => {
+                if let Some(meter) = parse_meter(value) {
+                    self.meter = meter;
+                    self.voice().current.meter = Some(meter);
+                }
+            }
+            'L' => self.unit = parse_fraction(value),
+            'Q' => {
+                let unit = self.unit();
+                if let Some(tempo) = parse_tempo(value, unit) {
+                    self.voice().current.tempo = Some(tempo);
+                }
+            }
+            'K' => {
+                if let Some(key) = parse_key(value) {
+                    self.sharps = key.0;
+                    self.voice().current.key = Some(key);
+                }
+                if value.contains("clef=bass") {
+                    self.voice().bass = true;
+                }
+            }
+            'V' => self.select_voice(value),
+            'T' if self.title.is_none() => self.title = Some(value.trim().to_string()),
+            'C' if self.composer.is_none() => self.composer = Some(value.trim().to_string()),
+            _ => (),
+        }
+    }
+
+    /// The MIDI key of a note, its accidental carrying on through the
+    /// measure.
+    fn key_of(&mut self, letter: char, accidental: Option<i32>, octave_marks: i32) -> Option<u8> {
+        let index = "CDEFGAB".find(letter.to_ascii_uppercase())? as u8;
+        let octave = if letter.is_ascii_lowercase() { 5 } else { 4 } + octave_marks;
+        let sharps = self.sharps;
+        let voice = self.voice();
+        let alteration = match accidental {
+            Some(alteration) => {
+                voice.accidentals.insert((index, octave), alteration);
+                alteration
+            }
+            None => voice
+                .accidentals
+                .get(&(index, octave))
+                .copied()
+                .unwrap_or_else(|| {
+                    if !notation::key_signature_letters(sharps).contains(&index) {
+                        0
+                    } else if sharps > 0 {
+                        1
+                    } else {
+                        -1
+                    }
+                }),
+        };
+        u8::try_from((octave + 1) * 12 + NATURALS[index as usize] + alteration)
+            .ok()
+            .filter(|key| *key < 128)
+    }
+
+    /// A note's pitch, from its accidentals to its octave marks, `i` on its
+    /// first character. None if it isn't a note.
+    fn parse_pitch(&mut self, chars: &[char], i: &mut usize) -> Option<u8> {
+        let start = *i;
+        let mut accidental = None;
+        while *i < chars.len() && matches!(chars[*i], '^' | '_' | '=') {
+            let step = match chars[*i] {
+                '^' => 1,
+                '_' => -1,
+                _ => 0,
+            };
+            accidental = Some(accidental.unwrap_or(0) + step);
+            *i += 1;
+        }
+        let letter = chars
+            .get(*i)
+            .copied()
+            .filter(|c| "CDEFGABcdefgab".contains(*c));
+        let Some(letter) = letter else {
+            *i = start;
+            return None;
+        };
+        *i += 1;
+        let mut octave_marks = 0;
+        while *i < chars.len() {
+            match chars[*i] {
+                '\'' => octave_marks += 1,
+                ',' => octave_marks -= 1,
+                _ => break,
+            }
+            *i += 1;
+        }
+        self.key_of(letter, accidental, octave_marks)
+    }
+
+    fn note_length(&self, (num, den): (u32, u32)) -> u32 {
+        let unit = self.unit();
+        WHOLE_NOTE * unit.0 * num / (unit.1 * den).max(1)
+    }
+
+    /// One line of notes.
+    fn parse_line(&mut self, line: &str) {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            match c {
+                '%' => break,
+                // chord symbols and annotations, decorations and grace notes
+                '"' | '!' | '+' | '{' => {
+                    let close = if c == '{' { '}' } else { c };
+                    i += 1;
+                    while i < chars.len() && chars[i] != close {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                '[' if chars.get(i + 2) == Some(&':') && chars[i + 1].is_ascii_alphabetic() => {
+                    let end = chars[i..]
+                        .iter()
+                        .position(|c| *c == ']')
+                        .map_or(chars.len(), |p| i + p);
+                    let value: String = chars[i + 3..end].iter().collect();
+                    self.field(chars[i + 1], &value);
+                    i = end + 1;
+                }
+                '[' if chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                    i += 1;
+                    let passes = parse_ending(&chars, &mut i);
+                    self.voice().start_ending(passes);
+                }
+                '[' if chars.get(i + 1) != Some(&'|') => {
+                    i += 1;
+                    let mut keys = vec![];
+                    let mut tied = vec![];
+                    let mut length = None;
+                    while i < chars.len() && chars[i] != ']' {
+                        if let Some(key) = self.parse_pitch(&chars, &mut i) {
+                            keys.push(key);
+                            let note_length = parse_length(&chars, &mut i);
+                            length.get_or_insert(note_length);
+                            // a tie inside the chord is the note's own
+                            if chars.get(i) == Some(&'-') {
+                                tied.push(key);
+                                i += 1;
+                            }
+                        } else {
+                            i += 1;
+                        }
+                    }
+                    i += 1;
+                    let outer = parse_length(&chars, &mut i);
+                    let inner = length.unwrap_or((1, 1));
+                    let length = self.note_length((inner.0 * outer.0, inner.1 * outer.1));
+                    self.voice().push_group(&keys, length);
+                    self.voice().tie_last_group_keys(&tied);
+                }
+                // `[|` is a bar line
+                '|' | ':' | '[' => {
+                    let start = i;
+                    while i < chars.len() && matches!(chars[i], '|' | ':' | ']' | '[') {
+                        // a bracket starts an inline field or an ending
+                        if chars[i] == '['
+                            && chars.get(i + 1).is_some_and(|c| c.is_ascii_alphanumeric())
+                        {
+                            break;
+                        }
+                        i += 1;
+                    }
+                    let bar: String = chars[start..i].iter().collect();
+                    let repeat_end = bar.starts_with(':');
+                    let repeat_start = bar.ends_with(':');
+                    let closes_ending = bar.contains("||") || bar.contains(']');
+                    self.voice().bar(repeat_end, repeat_start, closes_ending);
+                    if chars.get(i).is_some_and(|c| c.is_ascii_digit()) {
+                        let passes = parse_ending(&chars, &mut i);
+                        self.voice().start_ending(passes);
+                    }
+                }
+                '(' if chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                    i += 1;
+                    let p = parse_number(&chars, &mut i).unwrap_or(3);
+                    let mut q = tuplet_ratio(p, self.meter);
+                    let mut r = p;
+                    if chars.get(i) == Some(&':') {
+                        i += 1;
+                        q = parse_number(&chars, &mut i).unwrap_or(q);
+                        if chars.get(i) == Some(&':') {
+                            i += 1;
+                            r = parse_number(&chars, &mut i).unwrap_or(r);
+                        }
+                    }
+                    self.voice().tuplet = Some((p, q, r));
+                }
+                '>' | '<' => {
+                    let start = i;
+                    while i < chars.len() && chars[i] == c {
+                        i += 1;
+                    }
+                    self.voice().break_rhythm(c == '>', (i - start) as u32);
+                }
+                '-' => {
+                    self.voice().tie_last_group();
+                    i += 1;
+                }
+                'z' | 'x' => {
+                    i += 1;
+                    let length = self.note_length(parse_length(&chars, &mut i));
+                    self.voice().push_group(&[], length);
+                }
+                'Z' | 'X' => {
+                    i += 1;
+                    let measures = parse_number(&chars, &mut i).unwrap_or(1);
+                    let length = WHOLE_NOTE * self.meter.0 as u32 / self.meter.1.max(1) as u32;
+                    // a measure of rest each, the bar line after them
+                    // ending the last one
+                    for measure in 0..measures {
+                        if measure > 0 {
+                            self.voice().bar(false, false, false);
+                        }
+                        self.voice().push_group(&[], length);
+                    }
+                }
+                _ => {
+                    if let Some(key) = self.parse_pitch(&chars, &mut i) {
+                        let length = self.note_length(parse_length(&chars, &mut i));
+                        self.voice().push_group(&[key], length);
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The order the measures are played in, repeats and endings written out.
+fn expand(measures: &[AbcMeasure]) -> Vec<&AbcMeasure> {
+    let mut played = vec![];
+    let mut repeat_start = 0;
+    let mut pass = 1;
+    let mut i = 0;
+    while i < measures.len() {
+        let measure = &measures[i];
+        if measure.repeat_start && i != repeat_start {
+            repeat_start = i;
+            pass = 1;
+        }
+        if let Some(ending) = &measure.ending
+            && !ending.contains(&pass)
+        {
+            i += 1;
+            continue;
+        }
+        played.push(measure);
+        // the last ending says how many passes there are, two otherwise
+        let passes = measures[i + 1..]
+            .iter()
+            .take_while(|m| !m.repeat_start)
+            .filter_map(|m| m.ending.as_ref())
+            .chain(measure.ending.as_ref())
+            .flatten()
+            .max()
+            .copied()
+            .unwrap_or(2)
+            .max(2);
+        if measure.repeat_end && pass < passes {
+            pass += 1;
+            i = repeat_start;
+            continue;
+        }
+        // past the last ending, or the end of a repeat, a new one starts
+        let ending_closes =
+            measure.ending.is_some() && measures.get(i + 1).is_none_or(|m| m.ending.is_none());
+        if measure.repeat_end || ending_closes {
+            repeat_start = i + 1;
+            pass = 1;
+        }
+        i += 1;
+    }
+    played
+}
+
+/// Reads the first tune of an ABC file into a song, repeats and endings
+/// written out. Voices become hands, a voice in the bass clef being the
+/// left one.
+pub fn read(path: &Path) -> Result<Song, Box<dyn Error>> {
+    let text = fs::read_to_string(path)
+        .map_err(|why| format!("could not read {}: {}", path.display(), why))?;
+    parse(&text, &path.display().to_string())
+}
+
+/// A song out of an ABC file's text, `display` naming it in errors.
+fn parse(text: &str, display: &str) -> Result<Song, Box<dyn Error>> {
+    let mut tune = Tune {
+        title: None,
+        composer: None,
+        meter: (4, 4),
+        unit: None,
+        sharps: 0,
+        voices: vec![Voice::default()],
+        voice_ids: vec![],
+        current_voice: 0,
+    };
+    let mut in_header = true;
+    let mut tunes = 0;
+    for line in text.lines() {
+        let line = line.trim_end().trim_end_matches('\\');
+        let is_field = line.len() >= 2
+            && line.as_bytes()[1] == b':'
+            && line.as_bytes()[0].is_ascii_alphabetic();
+        if is_field {
+            let (name, value) = (line.as_bytes()[0] as char, &line[2..]);
+            if name == 'X' {
+                tunes += 1;
+                if tunes > 1 {
+                    break;
+                }
+                continue;
+            }
+            // the tune's notes start out in the first voice, whichever
+            // voices the header went through
+            if name == 'K' && in_header {
+                in_header = false;
+                tune.current_voice = 0;
+            }
+            // lyrics and words aren't played
+            if !matches!(name, 'w' | 'W') {
+                tune.field(name, value);
+            }
+        } else if !in_header && !line.starts_with('%') {
+            tune.parse_line(line);
+        }
+    }
+    if in_header {
+        return Err(format!("no tune in {}, the K: field starts one", display).into());
+    }
+    for voice in tune.voices.iter_mut() {
+        voice.bar(false, false, false);
+    }
+
+    let mut parts = SongParts {
+        ticks_per_quarter_note: TICKS_PER_QUARTER_NOTE as u16,
+        title: tune.title.clone(),
+        composer: tune.composer.clone(),
+        ..Default::default()
+    };
+
+    // by tick, a short measure's signature giving way to the meter's after it
+    let mut signatures: BTreeMap<u32, (u8, u8)> = BTreeMap::new();
+    let any_bass = tune.voices.iter().any(|v| v.bass);
+    let single = tune
+        .voices
+        .iter()
+        .filter(|v| !v.measures.is_empty())
+        .count()
+        == 1;
+    for (index, voice) in tune.voices.iter().enumerate() {
+        let mut track = Track {
+            name: voice.name.clone(),
+            instrument: None,
+            notes: 0,
+        };
+        let channel = if index as u32 >= DRUM_CHANNEL {
+            (index as u32 + 1).min(15)
+        } else {
+            index as u32
+        };
+        let hand = |key: u8| {
+            if any_bass {
+                if voice.bass { Hand::Left } else { Hand::Right }
+            } else if single {
+                if key >= MIDDLE_C {
+                    Hand::Right
+                } else {
+                    Hand::Left
+                }
+            } else if index == 0 {
+                Hand::Right
+            } else {
+                Hand::Left
+            }
+        };
+
+        // notes still tied to the next one, by key
+        let mut tied: HashMap<u8, usize> = HashMap::new();
+        let mut start = 0;
+        let (mut meter, mut tempo, mut key_signature) = (None, None, None);
+        for measure in expand(&voice.measures) {
+            // the first voice leads on meter, tempo and key, repeats
+            // bringing back changes that are already in effect
+            if index == 0 {
+                if let Some(signature) = measure.meter
+                    && meter != Some(signature)
+                {
+                    meter = measure.meter;
+                    signatures.insert(start, signature);
+                }
+                // a pickup, or a short measure before a repeat, gets a time
+                // signature of its own for the measures after it to line up
+                let signature = meter.unwrap_or((4, 4));
+                if let Some(odd) =
+                    odd_measure_signature(measure.length, TICKS_PER_QUARTER_NOTE, signature)
+                {
+                    signatures.insert(start, odd);
+                    signatures.insert(start + measure.length, signature);
+                    // a tune starting on a pickup counts its measures after it
+                    if start == 0
+                        && (odd.0 as u32 * signature.1 as u32) < signature.0 as u32 * odd.1 as u32
+                    {
+                        parts.measure_numbers.push((0, 0));
+                    }
+                }
+                if let Some(micros_per_quarter_note) = measure.tempo
+                    && tempo != Some(micros_per_quarter_note)
+                {
+                    tempo = measure.tempo;
+                    parts.tempo_changes.push(TempoChange {
+                        delta: start,
+                        time: 0,
+                        micros_per_quarter_note,
+                    });
+                }
+                if let Some((sharps, minor)) = measure.key
+                    && key_signature != Some((sharps, minor))
+                {
+                    key_signature = measure.key;
+                    parts.key_signatures.push(KeySignatureChange {
+                        delta: start,
+                        time: 0,
+                        sharps,
+                        minor,
+                    });
+                }
+            }
+
+            for note in measure.notes.iter() {
+                let delta = start + note.offset;
+                let stop = delta + note.length;
+                if let Some(index) = tied.remove(&note.key)
+                    && parts.note_blocks[index].stop_delta == Some(delta)
+                {
+                    parts.note_blocks[index].stop_delta = Some(stop);
+                    if note.tie {
+                        tied.insert(note.key, index);
+                    }
+                    continue;
+                }
+                let key = Key::from_databyte(note.key)?;
+                if note.tie {
+                    tied.insert(note.key, parts.note_blocks.len());
+                }
+                track.notes += 1;
+                parts.note_blocks.push(NoteBlock {
+                    octave: key.octave(),
+                    note: key.note(),
+                    key,
+                    velocity: DEFAULT_VELOCITY,
+                    hand: hand(note.key),
+                    start_delta: delta,
+                    stop_delta: Some(stop),
+                    start_time: 0,
+                    stop_time: None,
+                    channel_number: channel,
+                    finger: None,
+                });
+            }
+            start += measure.length;
+        }
+        if track.notes > 0 {
+            parts.tracks.push(track);
+        }
+    }
+
+    // a tune says its meter and tempo up front, or goes by the defaults
+    signatures.entry(0).or_insert((4, 4));
+    // a meter given again changes nothing
+    let mut last = None;
+    for (delta, (numerator, denominator)) in signatures {
+        if last != Some((numerator, denominator)) {
+            parts.time_signatures.push(TimeSignatureChange {
+                delta,
+                time: 0,
+                numerator,
+                denominator,
+                clocks_per_click: 24,
+                notated_32nds_per_quarter: 8,
+            });
+        }
+        last = Some((numerator, denominator));
+    }
+    if !parts.tempo_changes.iter().any(|tc| tc.delta == 0) {
+        parts.tempo_changes.push(TempoChange {
+            delta: 0,
+            time: 0,
+            micros_per_quarter_note: 500_000,
+        });
+    }
+
+    Ok(Song::from_parts(parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tune in 4/4 of quarter notes, `body` starting with its `K:` field.
+    fn tune(body: &str) -> Song {
+        parse(&format!("X:1\nT:Test\nM:4/4\nL:1/4\n{}\n", body), "test").unwrap()
+    }
+
+    /// Key, start and stop in ticks of every note.
+    fn notes(song: &Song) -> Vec<(u8, u32, u32)> {
+        let mut notes: Vec<_> = song
+            .note_blocks()
+            .map(|b| (b.key.byte(), b.start_delta, b.stop_delta.unwrap()))
+            .collect();
+        notes.sort_by_key(|(key, start, _)| (*start, *key));
+        notes
+    }
+
+    fn keys(song: &Song) -> Vec<u8> {
+        notes(song).iter().map(|(key, ..)| *key).collect()
+    }
+
+    #[test]
+    fn a_pickup_is_measure_zero() {
+        let song = tune("K:C\nG | c d e f | g4 |]");
+        let measures: Vec<_> = song
+            .measures()
+            .iter()
+            .map(|m| (m.number, m.delta, m.numerator, m.denominator))
+            .collect();
+        assert_eq!(
+            measures[..3],
+            [(0, 0, 1, 4), (1, 480, 4, 4), (2, 2400, 4, 4)]
+        );
+        assert_eq!(notes(&song)[..2], [(67, 0, 480), (72, 480, 960)]);
+    }
+
+    #[test]
+    fn repeats_are_played_twice() {
+        let song = tune("K:C\n|: C D E F :| G4 |]");
+        assert_eq!(keys(&song), [60, 62, 64, 65, 60, 62, 64, 65, 67]);
+        assert_eq!(notes(&song)[8], (67, 3840, 5760));
+    }
+
+    #[test]
+    fn endings_are_played_on_their_pass() {
+        let song = tune("K:C\n|: C4 |1 D4 :|2 E4 |]");
+        assert_eq!(keys(&song), [60, 62, 60, 64]);
+    }
+
+    #[test]
+    fn broken_rhythm_dots_one_note_and_halves_the_other() {
+        let song = tune("L:1/8\nK:C\nC>D E<F G4 |]");
+        assert_eq!(
+            notes(&song)[..4],
+            [(60, 0, 360), (62, 360, 480), (64, 480, 600), (65, 600, 960)]
+        );
+    }
+
+    #[test]
+    fn triplets_fit_three_notes_in_the_time_of_two() {
+        let song = tune("L:1/8\nK:C\n(3CDE F2 G4 |]");
+        assert_eq!(
+            notes(&song)[..4],
+            [(60, 0, 160), (62, 160, 320), (64, 320, 480), (65, 480, 960)]
+        );
+    }
+
+    #[test]
+    fn ties_join_notes_over_the_bar_line() {
+        let song = tune("K:C\nC2 G2- | G2 A2 |]");
+        assert_eq!(
+            notes(&song),
+            [(60, 0, 960), (67, 960, 2880), (69, 2880, 3840)]
+        );
+    }
+
+    #[test]
+    fn ties_inside_a_chord_hold_only_their_note() {
+        let song = tune("K:C\n[C-E]2 [CG]2 |]");
+        assert_eq!(notes(&song), [(60, 0, 1920), (64, 0, 960), (67, 960, 1920)]);
+    }
+
+    #[test]
+    fn accidentals_last_until_the_bar_line() {
+        let song = tune("K:C\n^F G F z | F G _B B | [K:G] F =F F z |]");
+        assert_eq!(keys(&song), [66, 67, 66, 65, 67, 70, 70, 66, 65, 65]);
+    }
+
+    #[test]
+    fn multi_measure_rests_rest_a_measure_each() {
+        let song = tune("K:C\nZ3 | C4 |]");
+        assert_eq!(notes(&song), [(60, 5760, 7680)]);
+        assert_eq!(song.measures()[3].delta, 5760);
+    }
+
+    #[test]
+    fn voices_in_the_bass_clef_are_the_left_hand() {
+        let song = tune("V:1 name=\"Right\"\nV:2 clef=bass\nK:C\n[V:1] c4 |]\n[V:2] C,4 |]");
+        let hands: Vec<_> = song.note_blocks().map(|b| (b.key.byte(), b.hand)).collect();
+        assert!(hands.contains(&(72, Hand::Right)));
+        assert!(hands.contains(&(48, Hand::Left)));
+        assert_eq!(song.tracks()[0].name.as_deref(), Some("Right"));
+    }
+}
@@ -0,0 +1,158 @@
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SizedSample, Stream, StreamConfig};
+
+use crate::player::{self, MidiSink, SharedMidiSink};
+use crate::song::Song;
+
+/// Something that produces mono audio.
+pub trait Render {
+    fn render(&mut self, out: &mut [f32]);
+}
+
+/// A running audio stream, sound stops when it's dropped.
+pub struct AudioOutput {
+    _stream: Stream,
+    pub sink: SharedMidiSink,
+}
+
+/// Opens the default audio output and keeps feeding it from a sink built
+/// for the device's sample rate by `make_sink`.
+pub fn start<S, F>(make_sink: F) -> Result<AudioOutput, Box<dyn Error>>
+where
+    S: Render + MidiSink + 'static,
+    F: FnOnce(u32) -> Result<S, Box<dyn Error>>,
+{
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .ok_or("no audio output device available")?;
+    let supported_config = device.default_output_config()?;
+    let sample_format = supported_config.sample_format();
+    let config: StreamConfig = supported_config.into();
+
+    let sink = Arc::new(Mutex::new(make_sink(config.sample_rate.0)?));
+
+    let stream = match sample_format {
+        cpal::SampleFormat::F32 => build_stream::<f32, S>(&device, &config, sink.clone())?,
+        cpal::SampleFormat::I16 => build_stream::<i16, S>(&device, &config, sink.clone())?,
+        cpal::SampleFormat::U16 => build_stream::<u16, S>(&device, &config, sink.clone())?,
+        format => return Err(format!("unsupported sample format {}", format).into()),
+    };
+    stream.play()?;
+
+    Ok(AudioOutput {
+        _stream: stream,
+        sink,
+    })
+}
+
+fn build_stream<T, S>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    sink: Arc<Mutex<S>>,
+) -> Result<Stream, Box<dyn Error>>
+where
+    T: SizedSample + FromSample<f32>,
+    S: Render + Send + 'static,
+{
+    let channels = config.channels as usize;
+    let mut mono = vec![];
+
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            mono.resize(data.len() / channels, 0.);
+            sink.lock().unwrap().render(&mut mono);
+            for (frame, sample) in data.chunks_mut(channels).zip(mono.iter()) {
+                for out in frame.iter_mut() {
+                    *out = T::from_sample(*sample);
+                }
+            }
+        },
+        |why| println!("audio output error: {}", why),
+        None,
+    )?;
+
+    Ok(stream)
+}
+
+/// Plays the whole song through `sink` and writes the result as a 16-bit
+/// mono WAV file, without touching any audio device.
+pub fn render_song_to_wav<S>(
+    song: &Song,
+    sink: S,
+    sample_rate: u32,
+    path: &Path,
+) -> Result<(), Box<dyn Error>>
+where
+    S: Render + MidiSink + 'static,
+{
+    let sink = Arc::new(Mutex::new(sink));
+    let mut song_player = player::SongPlayer::new(vec![sink.clone()]);
+
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+
+    let block_len = 64;
+    let mut block = vec![0.; block_len];
+    let mut rendered: u64 = 0;
+    // leave room for the last notes' release
+    let end_time = song.duration() + 1_000_000;
+
+    loop {
+        let now = (rendered * 1_000_000 / sample_rate as u64) as u32;
+        if now > end_time {
+            break;
+        }
+        song_player.update(song, now);
+        sink.lock().unwrap().render(&mut block);
+        for sample in block.iter() {
+            writer.write_sample((sample * i16::MAX as f32) as i16)?;
+        }
+        rendered += block_len as u64;
+    }
+
+    writer.finalize()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::tests::{note, song};
+    use crate::synth::Synth;
+
+    #[test]
+    fn rendered_notes_start_where_the_tempo_map_puts_them() {
+        let sample_rate = 8000;
+        // a quarter note at 120 bpm, then one at 240 bpm: the note starts at
+        // 0.75 seconds
+        let song = song(vec![note(69, 960, 1440)], &[(0, 500_000), (480, 250_000)]);
+        let path = std::env::temp_dir().join(format!("piano1-{}-render.wav", std::process::id()));
+        render_song_to_wav(&song, Synth::new(sample_rate), sample_rate, &path).unwrap();
+        let samples: Result<Vec<i16>, _> = hound::WavReader::open(&path)
+            .unwrap()
+            .into_samples()
+            .collect();
+        let _ = std::fs::remove_file(&path);
+
+        let first = samples.unwrap().iter().position(|s| *s != 0).unwrap();
+        let expected = sample_rate as usize * 3 / 4;
+        // the player catches up once per block of 64 samples
+        assert!(
+            (expected..expected + 64).contains(&first),
+            "first sound at frame {}, expected {}",
+            first,
+            expected
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use std::time::Duration;
+
+use macroquad::prelude::*;
+
+use crate::player::{self, SharedMidiSink};
+
+const BEATS_PER_MINUTE: u64 = 100;
+const COUNT_IN_BEATS: usize = 4;
+const MEASURED_BEATS: usize = 16;
+const CLICK_CHANNEL: u8 = 9;
+const CLICK_KEY: u8 = 76;
+
+pub struct CalibrationResult {
+    /// Average time between a beat and the tap answering it, in seconds.
+    pub offset: f32,
+    /// Standard deviation of the tap offsets, in seconds.
+    pub jitter: f32,
+    pub taps: usize,
+}
+
+/// Metronome the player taps along to. The average distance between beats
+/// and taps is what the keyboard, USB and display add on top of the song
+/// clock.
+pub struct Calibration {
+    /// Engine clock time the metronome started at; taps are stamped on the
+    /// same clock as the notes played in a song.
+    started: Duration,
+    elapsed: Duration,
+    beat_interval: Duration,
+    outputs: Vec<SharedMidiSink>,
+    clicked_beats: usize,
+    taps: Vec<Duration>,
+    result: Option<CalibrationResult>,
+    finished: bool,
+}
+
+impl Calibration {
+    pub fn new(outputs: Vec<SharedMidiSink>, now: Duration) -> Self {
+        Self {
+            started: now,
+            elapsed: Duration::ZERO,
+            beat_interval: Duration::from_millis(60_000 / BEATS_PER_MINUTE),
+            outputs,
+            clicked_beats: 0,
+            taps: vec![],
+            result: None,
+            finished: false,
+        }
+    }
+
+    fn total_beats(&self) -> usize {
+        COUNT_IN_BEATS + MEASURED_BEATS
+    }
+
+    // the first beat comes one interval after start, so the first click
+    // doesn't catch the player unprepared
+    fn beat_time(&self, beat: usize) -> Duration {
+        self.beat_interval * (beat as u32 + 1)
+    }
+
+    pub fn tap(&mut self, at: Duration) {
+        if !self.finished {
+            self.taps.push(at.saturating_sub(self.started));
+        }
+    }
+
+    pub fn result(&self) -> Option<&CalibrationResult> {
+        self.result.as_ref()
+    }
+
+    pub fn update(&mut self, now: Duration) {
+        if self.finished {
+            return;
+        }
+
+        self.elapsed = now.saturating_sub(self.started);
+        let elapsed = self.elapsed;
+        while self.clicked_beats < self.total_beats()
+            && elapsed >= self.beat_time(self.clicked_beats)
+        {
+            player::send(
+                &self.outputs,
+                &[player::NOTE_OFF | CLICK_CHANNEL, CLICK_KEY, 0],
+            );
+            player::send(
+                &self.outputs,
+                &[player::NOTE_ON | CLICK_CHANNEL, CLICK_KEY, 100],
+            );
+            self.clicked_beats += 1;
+        }
+
+        if elapsed >= self.beat_time(self.total_beats()) {
+            player::send(
+                &self.outputs,
+                &[player::NOTE_OFF | CLICK_CHANNEL, CLICK_KEY, 0],
+            );
+            self.result = self.compute_result();
+            self.finished = true;
+        }
+    }
+
+    fn compute_result(&self) -> Option<CalibrationResult> {
+        let interval = self.beat_interval.as_secs_f32();
+        let mut offsets = vec![];
+        for tap in self.taps.iter() {
+            let beat = (tap.as_secs_f32() / interval - 1.).round();
+            if beat < COUNT_IN_BEATS as f32 || beat >= self.total_beats() as f32 {
+                continue;
+            }
+            offsets.push(tap.as_secs_f32() - self.beat_time(beat as usize).as_secs_f32());
+        }
+
+        // with too few taps the average says more about the player than the device
+        if offsets.len() < MEASURED_BEATS / 2 {
+            return None;
+        }
+
+        let offset = offsets.iter().sum::<f32>() / offsets.len() as f32;
+        let variance =
+            offsets.iter().map(|o| (o - offset).powi(2)).sum::<f32>() / offsets.len() as f32;
+
+        Some(CalibrationResult {
+            offset,
+            jitter: variance.sqrt(),
+            taps: offsets.len(),
+        })
+    }
+
+    pub fn draw(&self) {
+        clear_background(BLACK);
+
+        let center_x = screen_width() / 2.;
+        let center_y = screen_height() / 2.;
+
+        if !self.finished {
+            let since_beat = self
+                .clicked_beats
+                .checked_sub(1)
+                .map(|beat| self.elapsed.saturating_sub(self.beat_time(beat)));
+            let flash = since_beat.is_some_and(|d| d < Duration::from_millis(120));
+            let counting_in = self.clicked_beats <= COUNT_IN_BEATS;
+
+            draw_circle(
+                center_x,
+                center_y,
+                80.,
+                match (flash, counting_in) {
+                    (true, true) => YELLOW,
+                    (true, false) => RED,
+                    (false, _) => DARKGRAY,
+                },
+            );
+            draw_text(
+                if counting_in {
+                    "get ready, then tap any key on every beat"
+                } else {
+                    "tap any key on every beat"
+                },
+                center_x - 250.,
+                center_y + 150.,
+                32.,
+                WHITE,
+            );
+            return;
+        }
+
+        match &self.result {
+            Some(result) => {
+                draw_text(
+                    format!(
+                        "latency: {:.0}ms, jitter: {:.0}ms ({} taps)",
+                        result.offset * 1_000.,
+                        result.jitter * 1_000.,
+                        result.taps
+                    ),
+                    center_x - 250.,
+                    center_y,
+                    32.,
+                    WHITE,
+                );
+                draw_text(
+                    "Enter to save, Esc to cancel",
+                    center_x - 250.,
+                    center_y + 40.,
+                    32.,
+                    GRAY,
+                );
+            }
+            None => {
+                draw_text(
+                    "not enough taps, Esc to go back",
+                    center_x - 250.,
+                    center_y,
+                    32.,
+                    WHITE,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn late_taps_give_the_latency() {
+        let started = Duration::from_secs(5);
+        let mut calibration = Calibration::new(vec![], started);
+        for beat in 0..COUNT_IN_BEATS + MEASURED_BEATS {
+            let click = started + calibration.beat_time(beat);
+            calibration.update(click);
+            // a little early and late in turn, 30ms late on average
+            let jitter = if beat % 2 == 0 { 20 } else { 40 };
+            calibration.tap(click + Duration::from_millis(jitter));
+        }
+        calibration.update(started + calibration.beat_time(COUNT_IN_BEATS + MEASURED_BEATS));
+
+        let result = calibration.result().unwrap();
+        assert_eq!(result.taps, MEASURED_BEATS);
+        assert!((result.offset - 0.030).abs() < 1e-4);
+        assert!((result.jitter - 0.010).abs() < 1e-4);
+    }
+}
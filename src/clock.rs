@@ -0,0 +1,171 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Where the engine gets the time from. Only differences between readings
+/// matter.
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+/// The wall clock.
+pub struct SystemClock {
+    started: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// A clock that only moves when told to, for running the engine without a
+/// window. Clones share the same time.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Rc<Cell<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            now: Rc::new(Cell::new(Duration::ZERO)),
+        }
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+/// Song time in seconds, worked out from where it was anchored rather than
+/// summed up frame by frame, so it can't drift from the clock it follows.
+pub struct SongClock {
+    running: bool,
+    tempo: f32,
+    anchor: Duration,
+    anchor_time: f32,
+}
+
+impl SongClock {
+    pub fn new() -> Self {
+        Self {
+            running: false,
+            tempo: 1.,
+            anchor: Duration::ZERO,
+            anchor_time: 0.,
+        }
+    }
+
+    /// The song time at clock time `now`. Times before the last anchor give
+    /// the anchored song time.
+    pub fn time(&self, now: Duration) -> f32 {
+        if self.running {
+            self.anchor_time + now.saturating_sub(self.anchor).as_secs_f32() * self.tempo
+        } else {
+            self.anchor_time
+        }
+    }
+
+    pub fn tempo(&self) -> f32 {
+        self.tempo
+    }
+
+    pub fn start(&mut self, now: Duration) {
+        if !self.running {
+            self.anchor = now;
+            self.running = true;
+        }
+    }
+
+    pub fn stop(&mut self, now: Duration) {
+        if self.running {
+            self.anchor_time = self.time(now);
+            self.running = false;
+        }
+    }
+
+    pub fn seek(&mut self, time: f32, now: Duration) {
+        self.anchor = now;
+        self.anchor_time = time;
+    }
+
+    /// Song seconds per clock second, 1 being the written tempo.
+    pub fn set_tempo(&mut self, tempo: f32, now: Duration) {
+        self.anchor_time = self.time(now);
+        self.anchor = now;
+        self.tempo = tempo;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(seconds: f32) -> Duration {
+        Duration::from_secs_f32(seconds)
+    }
+
+    #[test]
+    fn song_time_follows_the_clock_only_while_running() {
+        let mut clock = SongClock::new();
+        assert_eq!(clock.time(secs(5.)), 0.);
+
+        clock.start(secs(1.));
+        assert_eq!(clock.time(secs(3.)), 2.);
+        // before the anchor the song hasn't moved yet
+        assert_eq!(clock.time(secs(0.5)), 0.);
+
+        clock.stop(secs(4.));
+        assert_eq!(clock.time(secs(10.)), 3.);
+        clock.start(secs(10.));
+        assert_eq!(clock.time(secs(11.)), 4.);
+    }
+
+    #[test]
+    fn tempo_changes_keep_the_time_reached() {
+        let mut clock = SongClock::new();
+        clock.start(Duration::ZERO);
+        clock.set_tempo(0.5, secs(2.));
+        assert_eq!(clock.time(secs(2.)), 2.);
+        assert_eq!(clock.time(secs(4.)), 3.);
+        assert_eq!(clock.tempo(), 0.5);
+
+        // a paused clock keeps the tempo for when it runs again
+        clock.stop(secs(4.));
+        clock.set_tempo(2., secs(6.));
+        assert_eq!(clock.time(secs(7.)), 3.);
+        clock.start(secs(8.));
+        assert_eq!(clock.time(secs(9.)), 5.);
+    }
+
+    #[test]
+    fn seeking_moves_the_song_time_running_or_not() {
+        let mut clock = SongClock::new();
+        clock.seek(10., secs(1.));
+        assert_eq!(clock.time(secs(2.)), 10.);
+
+        clock.start(secs(2.));
+        clock.seek(20., secs(3.));
+        assert_eq!(clock.time(secs(3.)), 20.);
+        assert_eq!(clock.time(secs(4.)), 21.);
+    }
+}
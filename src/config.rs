@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// `$XDG_CONFIG_HOME/pianotrainer`, falling back to `~/.config/pianotrainer`.
+pub fn config_dir() -> PathBuf {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".config"),
+    };
+    base.join("pianotrainer")
+}
+
+fn latency_file() -> PathBuf {
+    config_dir().join("latency")
+}
+
+fn read_latencies() -> HashMap<String, i64> {
+    let mut latencies = HashMap::new();
+    if let Ok(content) = fs::read_to_string(latency_file()) {
+        for line in content.lines() {
+            if let Some((device, micros)) = line.rsplit_once('\t')
+                && let Ok(micros) = micros.parse()
+            {
+                latencies.insert(device.to_string(), micros);
+            }
+        }
+    }
+    latencies
+}
+
+/// Latency compensation for the given input device, in seconds.
+pub fn load_latency(device: &str) -> Option<f32> {
+    read_latencies()
+        .get(device)
+        .map(|micros| *micros as f32 / 1_000_000.)
+}
+
+pub fn save_latency(device: &str, seconds: f32) -> io::Result<()> {
+    let mut latencies = read_latencies();
+    latencies.insert(device.to_string(), (seconds * 1_000_000.) as i64);
+
+    let mut devices: Vec<_> = latencies.keys().collect();
+    devices.sort();
+    let content: String = devices
+        .into_iter()
+        .map(|device| format!("{}\t{}\n", device, latencies[device]))
+        .collect();
+
+    fs::create_dir_all(config_dir())?;
+    fs::write(latency_file(), content)
+}
+
+/// `$XDG_DATA_HOME/pianotrainer`, falling back to `~/.local/share/pianotrainer`.
+pub fn data_dir() -> PathBuf {
+    let base = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".local/share"),
+    };
+    base.join("pianotrainer")
+}
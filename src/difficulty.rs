@@ -0,0 +1,271 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::song::{Hand, Song};
+
+const BLACK_PITCH_CLASSES: [u8; 5] = [1, 3, 6, 8, 10];
+/// Onset gaps are rounded to this fraction of a quarter note before their
+/// variety is measured, so played-in files don't look needlessly complex.
+const RHYTHM_GRID: u32 = 24;
+
+/// How hard a song is to play, as a score and what it is made of.
+#[derive(Clone, Copy, Default)]
+pub struct Difficulty {
+    pub notes_per_second: f32,
+    pub max_polyphony: usize,
+    /// Widest chord a single hand plays, in semitones.
+    pub hand_span: u8,
+    /// Average distance a hand moves from one onset to the next, in
+    /// semitones.
+    pub mean_jump: f32,
+    pub black_key_ratio: f32,
+    /// Entropy, in bits, of the gaps between onsets. 0 for a steady pulse.
+    pub rhythmic_complexity: f32,
+    /// Beats per minute, averaged over the song.
+    pub tempo: f32,
+    /// From 0 to 10.
+    pub score: f32,
+}
+
+impl Difficulty {
+    pub fn of(song: &Song) -> Self {
+        let blocks: Vec<_> = song.note_blocks().collect();
+        if blocks.is_empty() {
+            return Self::default();
+        }
+
+        let first_start = blocks.iter().map(|b| b.start_time).min().unwrap_or(0);
+        let playing = (song.duration().saturating_sub(first_start) as f32 / 1_000_000.).max(1.);
+        let notes_per_second = blocks.len() as f32 / playing;
+
+        // notes sounding at once, stops going before starts at the same time
+        let mut changes: Vec<(u32, i32)> = vec![];
+        for block in blocks.iter() {
+            changes.push((block.start_time, 1));
+            changes.push((block.stop_time.unwrap_or(block.start_time), -1));
+        }
+        changes.sort();
+        let mut sounding = 0;
+        let mut max_polyphony = 0;
+        for (_, change) in changes {
+            sounding += change;
+            max_polyphony = max_polyphony.max(sounding as usize);
+        }
+
+        // keys starting together, per hand and start time
+        let mut groups: BTreeMap<(Hand, u32), Vec<u8>> = BTreeMap::new();
+        for block in blocks.iter() {
+            groups
+                .entry((block.hand, block.start_time))
+                .or_default()
+                .push(block.key.byte());
+        }
+        let hand_span = groups
+            .values()
+            .map(|keys| keys.iter().max().unwrap() - keys.iter().min().unwrap())
+            .max()
+            .unwrap_or(0);
+
+        let mut jumps = vec![];
+        for hand in [Hand::Left, Hand::Right] {
+            let hand_groups: Vec<&Vec<u8>> = groups
+                .range((hand, 0)..=(hand, u32::MAX))
+                .map(|(_, keys)| keys)
+                .collect();
+            for pair in hand_groups.windows(2) {
+                let jump = pair[1]
+                    .iter()
+                    .flat_map(|key| pair[0].iter().map(|previous| key.abs_diff(*previous)))
+                    .min()
+                    .unwrap_or(0);
+                jumps.push(jump as f32);
+            }
+        }
+        let mean_jump = if jumps.is_empty() {
+            0.
+        } else {
+            jumps.iter().sum::<f32>() / jumps.len() as f32
+        };
+
+        let black_keys = blocks
+            .iter()
+            .filter(|b| BLACK_PITCH_CLASSES.contains(&(b.key.byte() % 12)))
+            .count();
+        let black_key_ratio = black_keys as f32 / blocks.len() as f32;
+
+        let rhythmic_complexity = rhythmic_complexity(song);
+        let tempo = mean_tempo(song);
+
+        let score = 3. * (notes_per_second / 8.).min(1.)
+            + (max_polyphony.saturating_sub(1) as f32 / 5.).min(1.)
+            + 1.5 * (hand_span as f32 / 12.).min(1.)
+            + 1.5 * (mean_jump / 12.).min(1.)
+            + (black_key_ratio / 0.5).min(1.)
+            + 1.5 * (rhythmic_complexity / 3.).min(1.)
+            + 0.5 * ((tempo - 60.) / 120.).clamp(0., 1.);
+
+        Self {
+            notes_per_second,
+            max_polyphony,
+            hand_span,
+            mean_jump,
+            black_key_ratio,
+            rhythmic_complexity,
+            tempo,
+            score,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{:.1} notes/s, up to {} at once, span {} semitones, jumps of {:.1}, {:.0}% black keys, rhythm {:.1} bits, {:.0} bpm",
+            self.notes_per_second,
+            self.max_polyphony,
+            self.hand_span,
+            self.mean_jump,
+            self.black_key_ratio * 100.,
+            self.rhythmic_complexity,
+            self.tempo
+        )
+    }
+
+    /// The breakdown as comma separated numbers, for the library cache.
+    pub fn to_field(self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{}",
+            self.score,
+            self.notes_per_second,
+            self.max_polyphony,
+            self.hand_span,
+            self.mean_jump,
+            self.black_key_ratio,
+            self.rhythmic_complexity,
+            self.tempo
+        )
+    }
+
+    pub fn from_field(field: &str) -> Option<Self> {
+        let mut values = field.split(',');
+        Some(Self {
+            score: values.next()?.parse().ok()?,
+            notes_per_second: values.next()?.parse().ok()?,
+            max_polyphony: values.next()?.parse().ok()?,
+            hand_span: values.next()?.parse().ok()?,
+            mean_jump: values.next()?.parse().ok()?,
+            black_key_ratio: values.next()?.parse().ok()?,
+            rhythmic_complexity: values.next()?.parse().ok()?,
+            tempo: values.next()?.parse().ok()?,
+        })
+    }
+}
+
+fn rhythmic_complexity(song: &Song) -> f32 {
+    let grid = (song.ticks_per_quarter_note() as u32 / RHYTHM_GRID).max(1);
+    let mut onsets: Vec<u32> = song.note_blocks().map(|b| b.start_delta).collect();
+    onsets.sort();
+    onsets.dedup();
+
+    let mut gaps: HashMap<u32, u32> = HashMap::new();
+    for pair in onsets.windows(2) {
+        let gap = ((pair[1] - pair[0]) as f32 / grid as f32).round() as u32;
+        if gap > 0 {
+            *gaps.entry(gap).or_insert(0) += 1;
+        }
+    }
+    let total: u32 = gaps.values().sum();
+    gaps.values()
+        .map(|count| {
+            let p = *count as f32 / total as f32;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Beats per minute, weighted by how long each tempo lasts.
+fn mean_tempo(song: &Song) -> f32 {
+    let duration = song.duration();
+    let mut micros_per_quarter_note = 500_000;
+    let mut since = 0;
+    let mut weighted = 0.;
+    for tc in song.tempo_changes() {
+        let time = tc.time.min(duration);
+        weighted += (time - since) as f32 * 60_000_000. / micros_per_quarter_note as f32;
+        since = time;
+        micros_per_quarter_note = tc.micros_per_quarter_note.max(1);
+    }
+    weighted += (duration - since) as f32 * 60_000_000. / micros_per_quarter_note as f32;
+    if duration == 0 {
+        return 60_000_000. / micros_per_quarter_note as f32;
+    }
+    weighted / duration as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::tests::{note, song};
+
+    const SCALE: [u8; 8] = [60, 62, 64, 65, 67, 69, 71, 72];
+
+    fn scale() -> Song {
+        let notes = SCALE
+            .iter()
+            .enumerate()
+            .map(|(i, key)| note(*key, i as u32 * 480, i as u32 * 480 + 480))
+            .collect();
+        song(notes, &[(0, 500_000)])
+    }
+
+    /// Eighth note chords in both hands, mostly on black keys.
+    fn chords() -> Song {
+        let right = [[61, 66, 70, 73], [63, 68, 72, 75], [66, 70, 73, 78]];
+        let left = [[37, 44, 49], [30, 37, 42], [39, 46, 51]];
+        let mut notes = vec![];
+        for i in 0..16 {
+            let start = i * 240;
+            for key in right[i as usize % 3] {
+                notes.push(note(key, start, start + 240));
+            }
+            for key in left[(i as usize + 1) % 3] {
+                let mut block = note(key, start, start + 240);
+                block.hand = Hand::Left;
+                notes.push(block);
+            }
+        }
+        song(notes, &[(0, 400_000)])
+    }
+
+    #[test]
+    fn dense_two_hand_chords_score_above_a_scale() {
+        let scale = Difficulty::of(&scale());
+        let chords = Difficulty::of(&chords());
+
+        assert!(
+            scale.score < chords.score,
+            "{} vs {}",
+            scale.score,
+            chords.score
+        );
+        assert_eq!(scale.max_polyphony, 1);
+        assert_eq!(chords.max_polyphony, 7);
+        assert_eq!(scale.hand_span, 0);
+        assert_eq!(scale.black_key_ratio, 0.);
+        assert!(chords.black_key_ratio > 0.5);
+    }
+
+    #[test]
+    fn a_steady_pulse_has_no_rhythmic_complexity() {
+        let difficulty = Difficulty::of(&scale());
+        assert_eq!(difficulty.rhythmic_complexity, 0.);
+        assert_eq!(difficulty.tempo, 120.);
+    }
+
+    #[test]
+    fn the_breakdown_reads_back_from_its_field() {
+        let difficulty = Difficulty::of(&chords());
+        let read = Difficulty::from_field(&difficulty.to_field()).unwrap();
+        assert_eq!(read.score, difficulty.score);
+        assert_eq!(read.max_polyphony, difficulty.max_polyphony);
+        assert_eq!(read.tempo, difficulty.tempo);
+        assert!(Difficulty::from_field("1,2,3").is_none());
+    }
+}
@@ -0,0 +1,778 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use midix::prelude::{FromLiveEventBytes, Key, LiveEvent, VoiceEvent};
+
+use crate::clock::{Clock, SongClock};
+use crate::config;
+use crate::grading::{self, Grade};
+use crate::history::PracticeRecord;
+use crate::player;
+use crate::recorder;
+use crate::session::{SessionEvent, SessionLog};
+use crate::smf;
+use crate::song;
+use crate::utils;
+use crate::weakness::Weaknesses;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GameMode {
+    Play,
+    LearnBlocking,
+    Unset,
+}
+
+impl GameMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameMode::Play => "play",
+            GameMode::LearnBlocking => "blocking-learn",
+            GameMode::Unset => "unset",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [GameMode::Play, GameMode::LearnBlocking, GameMode::Unset]
+            .into_iter()
+            .find(|mode| mode.name() == name)
+    }
+}
+
+const MIN_TEMPO: f32 = 0.25;
+pub(crate) const MAX_TEMPO: f32 = 2.;
+
+/// A key the player pressed, placed in song time.
+pub struct PlayedNote {
+    pub key: Key,
+    pub start: f32,
+    pub stop: Option<f32>,
+    /// Index of the span of song time the key was pressed in.
+    pub pass: usize,
+}
+
+/// The game itself: song position, modes and what the player pressed.
+/// Knows nothing about drawing, so it runs the same with or without a
+/// window.
+pub struct Engine {
+    song: song::Song,
+    clock: Box<dyn Clock>,
+    song_clock: SongClock,
+    /// Clock time at which the MIDI input's timestamps start.
+    stamp_origin: Option<Duration>,
+    last_update: Duration,
+    mode: GameMode,
+    play: bool,
+    time_offset: f32,
+    awaiting_piano_input: bool,
+    awaiting_keys: Option<HashSet<Key>>,
+    active_piano_keys: HashSet<Key>,
+    active_piano_keys_history: utils::ActiveKeysHistory,
+    next_group: Vec<song::NoteBlock>,
+    player: Option<player::SongPlayer>,
+    input_device: Option<String>,
+    latency: f32,
+    last_hit_offset: Option<f32>,
+    recorder: Option<recorder::Recorder>,
+    record_dir: PathBuf,
+    record_format: smf::Format,
+    session_log: Option<SessionLog>,
+    /// Everything played since the song was loaded or reset.
+    performance: Vec<PlayedNote>,
+    /// Stretches of song time, in seconds, that went by while playing.
+    spans: Vec<(f32, f32)>,
+    /// Index of the first span of the current attempt.
+    attempt_start: usize,
+    practiced: Duration,
+    /// Song time span, in seconds, played over and over.
+    loop_range: Option<(f32, f32)>,
+    finished_attempts: Vec<PracticeRecord>,
+    weaknesses: Weaknesses,
+}
+
+impl Engine {
+    pub fn new(song: song::Song, clock: Box<dyn Clock>) -> Engine {
+        let last_update = clock.now();
+        let mut engine = Engine {
+            song,
+            clock,
+            last_update,
+            song_clock: SongClock::new(),
+            stamp_origin: None,
+            mode: GameMode::Unset,
+            play: false,
+            time_offset: 0.,
+            awaiting_piano_input: false,
+            awaiting_keys: None,
+            active_piano_keys: HashSet::new(),
+            active_piano_keys_history: utils::ActiveKeysHistory::new(),
+            next_group: vec![],
+            player: None,
+            input_device: None,
+            latency: 0.,
+            last_hit_offset: None,
+            recorder: None,
+            record_dir: PathBuf::from("."),
+            record_format: smf::Format::MultiTrack,
+            session_log: None,
+            performance: vec![],
+            spans: vec![],
+            attempt_start: 0,
+            practiced: Duration::ZERO,
+            loop_range: None,
+            finished_attempts: vec![],
+            weaknesses: Weaknesses::new(),
+        };
+        engine.set_mode(GameMode::Play);
+        engine
+    }
+
+    pub fn song(&self) -> &song::Song {
+        &self.song
+    }
+
+    pub fn mode(&self) -> GameMode {
+        self.mode
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.play
+    }
+
+    /// Song time in seconds.
+    pub fn time_offset(&self) -> f32 {
+        self.time_offset
+    }
+
+    pub fn active_piano_keys(&self) -> &HashSet<Key> {
+        &self.active_piano_keys
+    }
+
+    pub fn active_piano_keys_history(&self) -> &utils::ActiveKeysHistory {
+        &self.active_piano_keys_history
+    }
+
+    pub fn latency(&self) -> f32 {
+        self.latency
+    }
+
+    pub fn last_hit_offset(&self) -> Option<f32> {
+        self.last_hit_offset
+    }
+
+    pub fn performance(&self) -> &[PlayedNote] {
+        &self.performance
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Grades the current attempt.
+    pub fn grade(&self) -> Grade {
+        grading::grade(
+            &self.song,
+            &self.performance,
+            &self.spans,
+            self.attempt_start,
+        )
+    }
+
+    /// Grades everything played since the song was loaded or reset, for
+    /// showing.
+    pub fn grade_all(&self) -> Grade {
+        grading::grade(&self.song, &self.performance, &self.spans, 0)
+    }
+
+    /// Closes the current attempt, keeping its record if anything was
+    /// practiced. What was played stays on show.
+    fn finish_attempt(&mut self) {
+        if self.spans[self.attempt_start..]
+            .iter()
+            .all(|(from, to)| from == to)
+        {
+            return;
+        }
+
+        let grade = self.grade();
+        println!(
+            "accuracy: {:.0}% ({} hits, {} misses, {} wrong)",
+            grade.accuracy() * 100.,
+            grade.hits(),
+            grade.misses(),
+            grade.wrong()
+        );
+        self.weaknesses.add_grade(&self.song, &grade);
+        self.finished_attempts.push(PracticeRecord {
+            date: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            mode: self.mode,
+            tempo: self.song_clock.tempo(),
+            accuracy: grade.accuracy(),
+            practiced: self.practiced.as_secs_f32(),
+            measure_errors: grade.measure_errors,
+            note_errors: grade.note_errors,
+        });
+        // whatever is played from here on belongs to the next attempt
+        self.spans.push((self.time_offset, self.time_offset));
+        self.attempt_start = self.spans.len() - 1;
+        self.practiced = Duration::ZERO;
+    }
+
+    /// Records of every attempt so far, the current one included.
+    pub fn take_practice_records(&mut self) -> Vec<PracticeRecord> {
+        self.finish_attempt();
+        std::mem::take(&mut self.finished_attempts)
+    }
+
+    /// Mistakes of the attempts taken out with `take_practice_records`.
+    pub fn take_weaknesses(&mut self) -> Weaknesses {
+        std::mem::take(&mut self.weaknesses)
+    }
+
+    pub fn reset(&mut self) {
+        self.finish_attempt();
+        self.mode = GameMode::Unset;
+        self.play = false;
+        self.time_offset = 0.;
+        let now = self.clock.now();
+        self.song_clock.stop(now);
+        self.song_clock.seek(0., now);
+        self.awaiting_piano_input = false;
+        self.awaiting_keys = None;
+        self.active_piano_keys.clear();
+        self.active_piano_keys_history.clear();
+        self.next_group = vec![];
+        self.performance.clear();
+        self.spans.clear();
+        self.attempt_start = 0;
+        self.set_mode(GameMode::Play);
+        if let Some(player) = self.player.as_mut() {
+            player.seek(&self.song, 0);
+        }
+    }
+
+    /// Swaps in another song and starts over from its beginning. Take the
+    /// practice records of the old song first.
+    pub fn load_song(&mut self, song: song::Song) {
+        self.finish_attempt();
+        self.song = song;
+        self.loop_range = None;
+        self.reset();
+    }
+
+    pub fn set_player(&mut self, player: player::SongPlayer) {
+        self.player = Some(player);
+    }
+
+    pub fn player(&self) -> Option<&player::SongPlayer> {
+        self.player.as_ref()
+    }
+
+    pub fn player_mut(&mut self) -> Option<&mut player::SongPlayer> {
+        self.player.as_mut()
+    }
+
+    pub fn set_input_device(&mut self, device: String) {
+        self.latency = config::load_latency(&device).unwrap_or(0.);
+        self.input_device = Some(device);
+    }
+
+    /// Sets the input latency and remembers it for the input device.
+    pub fn set_latency(&mut self, latency: f32) {
+        self.apply(SessionEvent::Latency(latency));
+        if let Some(device) = &self.input_device
+            && let Err(why) = config::save_latency(device, latency)
+        {
+            println!("could not save latency for {}: {}", device, why);
+        }
+    }
+
+    pub fn set_recording(&mut self, dir: PathBuf, format: smf::Format) {
+        self.record_dir = dir;
+        self.record_format = format;
+    }
+
+    pub fn toggle_recording(&mut self) {
+        let Some(recorder) = self.recorder.take() else {
+            self.recorder = Some(recorder::Recorder::new());
+            return;
+        };
+        if recorder.is_empty() {
+            return;
+        }
+
+        let path = recorder::take_path(&self.record_dir);
+        match recorder.save(&path, self.record_format) {
+            Ok(_) => println!("saved take to {}", path.display()),
+            Err(why) => println!("could not save take to {}: {}", path.display(), why),
+        }
+    }
+
+    pub fn set_session_log(&mut self, session_log: SessionLog) {
+        self.session_log = Some(session_log);
+    }
+
+    /// Applies a player action or input, writing it to the session log
+    /// first. Replays go through here as well.
+    pub fn apply(&mut self, event: SessionEvent) {
+        if let Some(session_log) = self.session_log.as_mut() {
+            session_log.log(self.song_clock.time(self.clock.now()), &event);
+        }
+
+        match event {
+            SessionEvent::Midi(stamp, message) => self.on_midi_message(stamp, &message),
+            SessionEvent::TogglePlay => self.toggle_play(),
+            SessionEvent::Mode(mode) => self.set_mode(mode),
+            SessionEvent::Seek(amount) => self.skip_blocks(amount),
+            SessionEvent::Reset => self.reset(),
+            SessionEvent::Tempo(tempo) => self.set_tempo(tempo),
+            SessionEvent::Loop(range) => self.set_loop(range),
+            SessionEvent::Latency(latency) => self.latency = latency,
+        }
+    }
+
+    /// Where on our clock an input timestamp falls. Messages can't arrive
+    /// before they were stamped, so the earliest arrival seen so far gives
+    /// the closest estimate of where the stamps start.
+    pub fn stamp_to_clock(&mut self, stamp: u64) -> Duration {
+        let stamp = Duration::from_micros(stamp);
+        let origin = self.clock.now().saturating_sub(stamp);
+        let origin = match self.stamp_origin {
+            Some(stamp_origin) => stamp_origin.min(origin),
+            None => origin,
+        };
+        self.stamp_origin = Some(origin);
+        origin + stamp
+    }
+
+    /// Every raw message from the input, with its timestamp in microseconds.
+    /// Notes are placed in the song at the time they were stamped, not when
+    /// they got here.
+    fn on_midi_message(&mut self, stamp: u64, message: &[u8]) {
+        let at = self.stamp_to_clock(stamp);
+        if let Some(recorder) = self.recorder.as_mut() {
+            let song_time = self
+                .play
+                .then(|| (self.song_clock.time(at) * 1_000_000.) as u64);
+            recorder.record(stamp, song_time, message);
+        }
+
+        // virtual ports get whatever the other side sends,
+        // clock and sysex included
+        if let Ok(LiveEvent::ChannelVoice(cv)) = LiveEvent::from_bytes(message) {
+            let event = cv.event();
+            if let VoiceEvent::NoteOn { key, .. } = event {
+                self.piano_key_down_at(*key, at);
+            } else if let VoiceEvent::NoteOff { key, .. } = event {
+                self.piano_key_up_at(*key, at);
+            }
+        }
+    }
+
+    /// The time on the engine's clock, which input timestamps are placed on.
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    pub fn toggle_play(&mut self) {
+        self.play = !self.play;
+        self.sync_song_clock(self.clock.now());
+    }
+
+    pub fn pause(&mut self) {
+        self.play = false;
+        self.sync_song_clock(self.clock.now());
+    }
+
+    pub fn tempo(&self) -> f32 {
+        self.song_clock.tempo()
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.song_clock
+            .set_tempo(tempo.clamp(MIN_TEMPO, MAX_TEMPO), self.clock.now());
+    }
+
+    pub fn loop_range(&self) -> Option<(f32, f32)> {
+        self.loop_range
+    }
+
+    /// Loops over `range` from its start, or stops looping.
+    pub fn set_loop(&mut self, range: Option<(f32, f32)>) {
+        self.loop_range = range;
+        if let Some((from, _)) = range {
+            self.seek_to(from);
+        }
+    }
+
+    fn seek_to(&mut self, time: f32) {
+        self.time_offset = time;
+        // whatever is played from here on belongs to a new pass
+        self.spans.push((time, time));
+        self.song_clock.seek(time, self.clock.now());
+        self.move_to_group_after((time * 1_000_000.) as u32);
+        if self.mode == GameMode::LearnBlocking {
+            self.awaiting_piano_input = false;
+        }
+        if let Some(player) = self.player.as_mut() {
+            player.seek(&self.song, (time * 1_000_000.) as u32);
+        }
+    }
+
+    /// Runs or stops the song clock at `at`, depending on whether the song
+    /// should be moving.
+    fn sync_song_clock(&mut self, at: Duration) {
+        if self.play
+            && (self.mode == GameMode::Play
+                || (self.mode == GameMode::LearnBlocking && !self.awaiting_piano_input))
+        {
+            self.song_clock.start(at);
+        } else {
+            self.song_clock.stop(at);
+        }
+    }
+
+    /// Catches the song up with the clock.
+    pub fn update(&mut self) {
+        let now = self.clock.now();
+        if self.play {
+            self.practiced += now.saturating_sub(self.last_update);
+        }
+        self.last_update = now;
+
+        self.active_piano_keys_history.autoclean();
+
+        let previous_time = self.time_offset;
+        self.sync_song_clock(now);
+        self.time_offset = self.song_clock.time(now);
+
+        if self.mode == GameMode::LearnBlocking
+            && !self.awaiting_piano_input
+            && let Some(due) = self
+                .next_group
+                .first()
+                .map(|block| block.start_time as f32 / 1_000_000. - self.latency)
+            && self.time_offset > due
+        {
+            // TODO: if we already know that the keys were recently pressed we might
+            // just continue without interruptions, for smoorther play
+            self.awaiting_piano_input = true;
+
+            // stop exactly where the group is due, however late this update is
+            self.song_clock.stop(now);
+            self.song_clock.seek(due, now);
+            self.time_offset = due;
+        }
+
+        // a loop's end is where that pass stops, wherever this update landed
+        let reached = match self.loop_range {
+            Some((_, to)) => self.time_offset.min(to),
+            None => self.time_offset,
+        };
+        if reached > previous_time {
+            match self.spans.last_mut() {
+                Some(span) if span.1 == previous_time => span.1 = reached,
+                _ => self.spans.push((previous_time, reached)),
+            }
+        }
+
+        if let Some((from, to)) = self.loop_range
+            && self.time_offset >= to
+        {
+            self.seek_to(from);
+        }
+
+        if let Some(player) = self.player.as_mut() {
+            if self.play {
+                player.update(&self.song, (self.time_offset * 1_000_000.) as u32);
+            } else {
+                player.pause();
+            }
+        }
+    }
+
+    /// How far from its note a key pressed at `song_time` is. Latency is
+    /// made up for by the hit line, drawn `latency` ahead of the song clock:
+    /// a press that lands when the note touches it is on time, so it's
+    /// compared with the note's start as is.
+    fn hit_offset(&self, key: Key, song_time: f32) -> Option<f32> {
+        self.song
+            .note_blocks()
+            .filter(|b| b.key == key)
+            .map(|b| song_time - b.start_time as f32 / 1_000_000.)
+            .min_by(|a, b| a.abs().total_cmp(&b.abs()))
+    }
+
+    pub fn on_piano_key_down(&mut self, key: Key) {
+        self.piano_key_down_at(key, self.clock.now());
+    }
+
+    fn piano_key_down_at(&mut self, key: Key, at: Duration) {
+        let song_time = self.song_clock.time(at);
+        if self.play {
+            self.last_hit_offset = self.hit_offset(key, song_time);
+        }
+        self.performance.push(PlayedNote {
+            key,
+            start: song_time,
+            stop: None,
+            pass: self.spans.len().saturating_sub(1),
+        });
+        self.active_piano_keys.insert(key);
+        self.active_piano_keys_history.insert(key);
+        if self.awaiting_piano_input
+            && self.active_piano_keys == self.awaiting_keys.clone().unwrap()
+        {
+            self.awaiting_piano_input = false;
+            let awaited = self.next_group.first().map_or(0, |block| block.start_time);
+            self.move_to_group_after(awaited);
+            self.sync_song_clock(at);
+
+            // clear to make sure that successive piano key strokes
+            // won't be polluted with previous ones
+            self.active_piano_keys_history.clear();
+        }
+    }
+
+    pub fn on_piano_key_up(&mut self, key: Key) {
+        self.piano_key_up_at(key, self.clock.now());
+    }
+
+    fn piano_key_up_at(&mut self, key: Key, at: Duration) {
+        let song_time = self.song_clock.time(at);
+        if let Some(played) = self
+            .performance
+            .iter_mut()
+            .rev()
+            .find(|p| p.key == key && p.stop.is_none())
+        {
+            played.stop = Some(song_time);
+        }
+        self.active_piano_keys.remove(&key);
+    }
+
+    pub fn set_mode(&mut self, mode: GameMode) {
+        if self.mode != mode {
+            self.mode = mode;
+        }
+        self.move_to_next_group();
+        if self.mode == GameMode::Play {
+            self.awaiting_piano_input = false;
+        }
+        self.sync_song_clock(self.clock.now());
+    }
+
+    fn move_to_prev_group(&mut self) {
+        self.next_group = match self.song.prev((self.time_offset * 1_000_000.) as u32) {
+            Some(v) => v.to_vec(),
+            None => vec![],
+        };
+        self.awaiting_keys = Some(
+            self.next_group
+                .iter()
+                .map(|b| b.key)
+                .collect::<HashSet<Key>>(),
+        );
+    }
+
+    fn move_to_next_group(&mut self) {
+        self.move_to_group_after((self.time_offset * 1_000_000.) as u32);
+    }
+
+    fn move_to_group_after(&mut self, time: u32) {
+        self.next_group = match self.song.next(time) {
+            Some(v) => v.to_vec(),
+            None => vec![],
+        };
+        self.awaiting_keys = Some(
+            self.next_group
+                .iter()
+                .map(|b| b.key)
+                .collect::<HashSet<Key>>(),
+        );
+    }
+
+    pub fn skip_blocks(&mut self, amount: i32) {
+        self.pause();
+
+        for _ in 0..amount.unsigned_abs() {
+            if amount < 0 {
+                self.move_to_prev_group();
+            } else {
+                self.move_to_next_group();
+            }
+            // past the last group, or in a song without notes, there's
+            // nowhere to go
+            if let Some(block) = self.next_group.first() {
+                self.time_offset = block.start_time as f32 / 1_000_000.;
+            }
+        }
+        self.song_clock.seek(self.time_offset, self.clock.now());
+        self.spans.push((self.time_offset, self.time_offset));
+
+        if let Some(player) = self.player.as_mut() {
+            player.seek(&self.song, (self.time_offset * 1_000_000.) as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::song::tests::{note, song};
+
+    /// An engine at 120 bpm, where a quarter note is half a second, on a
+    /// clock that only moves when told to.
+    fn engine(notes: Vec<song::NoteBlock>) -> (Engine, ManualClock) {
+        let clock = ManualClock::new();
+        let engine = Engine::new(song(notes, &[(0, 500_000)]), Box::new(clock.clone()));
+        (engine, clock)
+    }
+
+    /// Moves the clock on by `seconds`, updating every 10ms like frames would.
+    fn run_for(engine: &mut Engine, clock: &ManualClock, seconds: f32) {
+        for _ in 0..(seconds * 100.).round() as u32 {
+            clock.advance(Duration::from_millis(10));
+            engine.update();
+        }
+    }
+
+    /// Sends a message from the MIDI input, stamped now.
+    fn midi(engine: &mut Engine, clock: &ManualClock, message: &[u8]) {
+        let stamp = clock.now().as_micros() as u64;
+        engine.apply(SessionEvent::Midi(stamp, message.to_vec()));
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.001,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn note_on_in_time_is_a_hit() {
+        let (mut engine, clock) = engine(vec![note(60, 480, 960), note(64, 960, 1440)]);
+        engine.apply(SessionEvent::TogglePlay);
+        run_for(&mut engine, &clock, 0.5);
+        midi(&mut engine, &clock, &[0x90, 60, 100]);
+        assert_near(engine.last_hit_offset().unwrap(), 0.);
+        run_for(&mut engine, &clock, 0.25);
+        midi(&mut engine, &clock, &[0x80, 60, 0]);
+        run_for(&mut engine, &clock, 1.);
+
+        let grade = engine.grade();
+        assert_eq!((grade.hits(), grade.misses(), grade.wrong()), (1, 1, 0));
+    }
+
+    #[test]
+    fn finished_attempts_stay_on_show() {
+        let (mut engine, clock) = engine(vec![note(60, 480, 960)]);
+        engine.apply(SessionEvent::TogglePlay);
+        run_for(&mut engine, &clock, 0.5);
+        midi(&mut engine, &clock, &[0x90, 60, 100]);
+        run_for(&mut engine, &clock, 0.2);
+
+        let records = engine.take_practice_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].accuracy, 1.);
+        assert_eq!(engine.performance().len(), 1);
+        assert_eq!(engine.grade_all().hits(), 1);
+        assert!(engine.grade().judgments.is_empty());
+    }
+
+    #[test]
+    fn learn_blocking_waits_for_the_whole_chord() {
+        let (mut engine, clock) = engine(vec![
+            note(60, 480, 960),
+            note(64, 480, 960),
+            note(67, 960, 1440),
+        ]);
+        engine.apply(SessionEvent::Mode(GameMode::LearnBlocking));
+        engine.apply(SessionEvent::TogglePlay);
+        run_for(&mut engine, &clock, 1.);
+        assert_eq!(engine.time_offset(), 0.5);
+
+        midi(&mut engine, &clock, &[0x90, 60, 100]);
+        run_for(&mut engine, &clock, 0.2);
+        assert_eq!(engine.time_offset(), 0.5);
+
+        midi(&mut engine, &clock, &[0x90, 64, 100]);
+        run_for(&mut engine, &clock, 0.2);
+        assert_near(engine.time_offset(), 0.7);
+
+        // and waits again at the next note
+        run_for(&mut engine, &clock, 1.);
+        assert_eq!(engine.time_offset(), 1.);
+    }
+
+    #[test]
+    fn seek_and_tempo_move_the_song_time() {
+        let (mut engine, clock) = engine(vec![
+            note(60, 480, 960),
+            note(62, 960, 1440),
+            note(64, 1440, 1920),
+        ]);
+        engine.apply(SessionEvent::Seek(2));
+        assert_eq!(engine.time_offset(), 1.);
+        engine.apply(SessionEvent::Seek(-1));
+        assert_eq!(engine.time_offset(), 0.5);
+        // there's nothing to go to past the last note
+        engine.apply(SessionEvent::Seek(5));
+        assert_eq!(engine.time_offset(), 1.5);
+
+        engine.apply(SessionEvent::Tempo(0.5));
+        engine.apply(SessionEvent::TogglePlay);
+        run_for(&mut engine, &clock, 0.4);
+        assert_near(engine.time_offset(), 1.7);
+        engine.apply(SessionEvent::Tempo(2.));
+        run_for(&mut engine, &clock, 0.1);
+        assert_near(engine.time_offset(), 1.9);
+    }
+
+    #[test]
+    fn takes_are_saved_in_song_time() {
+        let dir = std::env::temp_dir().join(format!("piano1-takes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (mut engine, clock) = engine(vec![note(60, 3840, 4320)]);
+        engine.set_recording(dir.clone(), smf::Format::MultiTrack);
+        engine.apply(SessionEvent::Tempo(0.5));
+        engine.toggle_recording();
+        engine.apply(SessionEvent::TogglePlay);
+
+        // at half speed, a second on the clock is a quarter note in the song
+        run_for(&mut engine, &clock, 1.);
+        midi(&mut engine, &clock, &[0x90, 62, 100]);
+        run_for(&mut engine, &clock, 1.);
+        midi(&mut engine, &clock, &[0x80, 62, 0]);
+        engine.toggle_recording();
+
+        let take = std::fs::read_dir(&dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let song = song::Song::read(&take).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let notes: Vec<_> = song
+            .note_blocks()
+            .map(|block| (block.key.byte(), block.start_delta, block.stop_delta))
+            .collect();
+        assert_eq!(notes, vec![(62, 960, Some(1920))]);
+    }
+
+    #[test]
+    fn seeking_a_song_without_notes_stays_put() {
+        let (mut engine, _clock) = engine(vec![]);
+        engine.apply(SessionEvent::Seek(1));
+        engine.apply(SessionEvent::Seek(-1));
+        assert_eq!(engine.time_offset(), 0.);
+    }
+}
@@ -0,0 +1,733 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::notation::{Accidental, NoteValue, Score, ScoreMeasure, ScoreNote, Staff};
+use crate::song::Song;
+
+const STAVES: [Staff; 2] = [Staff::Treble, Staff::Bass];
+const LETTERS: [&str; 7] = ["C", "D", "E", "F", "G", "A", "B"];
+
+/// Voices a staff is written in at most. Chords past them cut the chord
+/// before them short, as in a single voice.
+const MAX_VOICES: usize = 4;
+/// LilyPond's stem directions for each voice of a staff.
+const VOICE_DIRECTIONS: [&str; MAX_VOICES] =
+    ["\\voiceOne", "\\voiceTwo", "\\voiceThree", "\\voiceFour"];
+
+/// Something a voice does for a while: play a chord, or rest when there are
+/// no notes.
+struct Event<'a> {
+    length: u32,
+    notes: Vec<&'a ScoreNote>,
+    /// Lasts to the end of the measure, where its notes can be tied over.
+    ends_measure: bool,
+}
+
+impl Event<'_> {
+    /// Keys tied over into the next measure.
+    fn tied_over(&self) -> HashSet<u8> {
+        self.notes
+            .iter()
+            .filter(|note| self.ends_measure && note.tie_start)
+            .map(|note| note.key)
+            .collect()
+    }
+}
+
+/// Notes of a staff starting together and lasting as long.
+struct Chord<'a> {
+    position: u32,
+    length: u32,
+    notes: Vec<&'a ScoreNote>,
+}
+
+/// One staff of a measure.
+struct StaffMeasure<'a> {
+    /// Events of every voice, each filling the measure.
+    voices: Vec<Vec<Event<'a>>>,
+    /// Keys tied over from the measure before.
+    tied_in: HashSet<u8>,
+}
+
+/// Whether piece `piece` of the `pieces` a note is split into is tied to
+/// the one before and to the one after. The first and last pieces are tied
+/// over the bar lines when the note is and the voice holds it that far,
+/// `tied_in` being the keys tied over from the measure before.
+fn ties(
+    note: &ScoreNote,
+    event: &Event,
+    (piece, pieces): (usize, usize),
+    tied_in: &HashSet<u8>,
+) -> (bool, bool) {
+    let stop = piece > 0 || (note.tie_stop && tied_in.contains(&note.key));
+    let start = piece + 1 < pieces || (event.ends_measure && note.tie_start);
+    (stop, start)
+}
+
+/// One staff of a measure in voices, so that every note keeps its length:
+/// a chord goes to the first voice done with the chord before. Notes tied
+/// over from the measure before go on in the voice `tied_voices` gives
+/// for their key. The gaps between chords are rests.
+fn staff_voices<'a>(
+    measure: &'a ScoreMeasure,
+    staff: Staff,
+    tied_voices: &HashMap<u8, usize>,
+) -> Vec<Vec<Event<'a>>> {
+    let end = measure.sixteenths();
+    let mut chords: Vec<Chord> = vec![];
+    for note in measure.notes.iter().filter(|n| n.staff == staff) {
+        let position = note.position.min(end - 1);
+        let length = note.length.clamp(1, end - position);
+        match chords
+            .iter_mut()
+            .find(|c| c.position == position && c.length == length)
+        {
+            Some(chord) => chord.notes.push(note),
+            None => chords.push(Chord {
+                position,
+                length,
+                notes: vec![note],
+            }),
+        }
+    }
+    // shorter chords first, so a voice goes on with what follows them
+    chords.sort_by_key(|c| (c.position, c.length));
+
+    let mut voices: Vec<Vec<Chord>> = vec![];
+    for chord in chords {
+        let position = chord.position;
+        let free = |voice: &Vec<Chord>| {
+            voice
+                .last()
+                .is_none_or(|last| last.position + last.length <= position)
+        };
+        let tied_voice = chord
+            .notes
+            .iter()
+            .filter(|note| note.tie_stop)
+            .find_map(|note| tied_voices.get(&note.key).copied());
+        let index = match tied_voice {
+            Some(index) if voices.get(index).is_none_or(free) => index,
+            _ => voices.iter().position(free).unwrap_or(voices.len()),
+        };
+        if index < MAX_VOICES {
+            voices.resize_with(voices.len().max(index + 1), Vec::new);
+            voices[index].push(chord);
+            continue;
+        }
+        // out of voices, the last one's chord is cut short
+        let voice = &mut voices[MAX_VOICES - 1];
+        match voice.last_mut() {
+            Some(last) if last.position == position => last.notes.extend(chord.notes),
+            Some(last) => {
+                last.length = position - last.position;
+                voice.push(chord);
+            }
+            None => voice.push(chord),
+        }
+    }
+    if voices.is_empty() {
+        voices.push(vec![]);
+    }
+
+    let rest = |length, ends_measure| Event {
+        length,
+        notes: vec![],
+        ends_measure,
+    };
+    voices
+        .into_iter()
+        .map(|chords| {
+            let mut events = vec![];
+            let mut time = 0;
+            for chord in chords {
+                if chord.position > time {
+                    events.push(rest(chord.position - time, false));
+                }
+                time = chord.position + chord.length;
+                events.push(Event {
+                    length: chord.length,
+                    notes: chord.notes,
+                    ends_measure: time == end,
+                });
+            }
+            if time < end {
+                events.push(rest(end - time, true));
+            }
+            events
+        })
+        .collect()
+}
+
+/// Every measure of one staff, in voices.
+fn staff_measures(score: &Score, staff: Staff) -> Vec<StaffMeasure<'_>> {
+    let mut tied_voices: HashMap<u8, usize> = HashMap::new();
+    score
+        .measures
+        .iter()
+        .map(|measure| {
+            let voices = staff_voices(measure, staff, &tied_voices);
+            let tied_in = tied_voices.keys().copied().collect();
+            tied_voices = voices
+                .iter()
+                .enumerate()
+                .flat_map(|(index, events)| {
+                    let tied_over = events.last().map(Event::tied_over).unwrap_or_default();
+                    tied_over.into_iter().map(move |key| (key, index))
+                })
+                .collect();
+            StaffMeasure { voices, tied_in }
+        })
+        .collect()
+}
+
+/// Sharps (flats when negative) of the key the measure is in, and whether
+/// it is minor.
+fn key_of(song: &Song, measure: &ScoreMeasure) -> (i8, bool) {
+    let minor = song
+        .key_signature_at(measure.time)
+        .is_some_and(|ks| ks.minor);
+    (measure.sharps, minor)
+}
+
+/// Quarter notes per minute of the tempo changes within the measure.
+fn tempos_in(song: &Song, measure: &ScoreMeasure, next: Option<&ScoreMeasure>) -> Vec<u32> {
+    song.tempo_changes()
+        .iter()
+        .filter(|tc| tc.time >= measure.time && next.is_none_or(|next| tc.time < next.time))
+        .map(|tc| (60_000_000. / tc.micros_per_quarter_note as f32).round() as u32)
+        .collect()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn musicxml_type(value: NoteValue) -> &'static str {
+    match value {
+        NoteValue::Whole => "whole",
+        NoteValue::Half => "half",
+        NoteValue::Quarter => "quarter",
+        NoteValue::Eighth => "eighth",
+        NoteValue::Sixteenth => "16th",
+    }
+}
+
+/// Writes the song as a MusicXML score of one piano part, the right hand on
+/// the upper staff and the left on the lower one, in sixteenths.
+pub fn write_musicxml(song: &Song, path: &Path) -> std::io::Result<()> {
+    let score = Score::of(song);
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n");
+    xml.push_str("<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">\n");
+    xml.push_str("<score-partwise version=\"4.0\">\n");
+    if let Some(title) = song.title() {
+        let _ = writeln!(
+            xml,
+            "  <work><work-title>{}</work-title></work>",
+            escape(title)
+        );
+    }
+    let mut identification = String::new();
+    if let Some(composer) = song.composer() {
+        let _ = write!(
+            identification,
+            "<creator type=\"composer\">{}</creator>",
+            escape(composer)
+        );
+    }
+    // MIDI files credit the composer in the copyright notice
+    if let Some(copyright) = song.copyright()
+        && song.composer() != Some(copyright)
+    {
+        let _ = write!(identification, "<rights>{}</rights>", escape(copyright));
+    }
+    if !identification.is_empty() {
+        let _ = writeln!(xml, "  <identification>{}</identification>", identification);
+    }
+    xml.push_str("  <part-list><score-part id=\"P1\"><part-name>Piano</part-name></score-part></part-list>\n");
+    xml.push_str("  <part id=\"P1\">\n");
+
+    let staves = STAVES.map(|staff| staff_measures(&score, staff));
+    let mut signature = None;
+    let mut key = None;
+    for (i, measure) in score.measures.iter().enumerate() {
+        let _ = writeln!(xml, "    <measure number=\"{}\">", measure.number);
+
+        let time = (measure.numerator, measure.denominator);
+        let (sharps, minor) = key_of(song, measure);
+        let key_element = format!(
+            "<key><fifths>{}</fifths><mode>{}</mode></key>",
+            sharps,
+            if minor { "minor" } else { "major" }
+        );
+        if i == 0 {
+            let _ = writeln!(
+                xml,
+                "      <attributes><divisions>4</divisions>{}<time><beats>{}</beats><beat-type>{}</beat-type></time><staves>2</staves><clef number=\"1\"><sign>G</sign><line>2</line></clef><clef number=\"2\"><sign>F</sign><line>4</line></clef></attributes>",
+                key_element, time.0, time.1
+            );
+        } else {
+            let mut attributes = String::new();
+            if key != Some((sharps, minor)) {
+                attributes.push_str(&key_element);
+            }
+            if signature != Some(time) {
+                let _ = write!(
+                    attributes,
+                    "<time><beats>{}</beats><beat-type>{}</beat-type></time>",
+                    time.0, time.1
+                );
+            }
+            if !attributes.is_empty() {
+                let _ = writeln!(xml, "      <attributes>{}</attributes>", attributes);
+            }
+        }
+        signature = Some(time);
+        key = Some((sharps, minor));
+
+        for bpm in tempos_in(song, measure, score.measures.get(i + 1)) {
+            let _ = writeln!(
+                xml,
+                "      <direction placement=\"above\"><direction-type><metronome><beat-unit>quarter</beat-unit><per-minute>{}</per-minute></metronome></direction-type><sound tempo=\"{}\"/></direction>",
+                bpm, bpm
+            );
+        }
+
+        for (staff_index, staff_measures) in staves.iter().enumerate() {
+            let number = staff_index + 1;
+            let staff_measure = &staff_measures[i];
+            for (v, events) in staff_measure.voices.iter().enumerate() {
+                // every voice but the first starts over at the start of the measure
+                if staff_index > 0 || v > 0 {
+                    let _ = writeln!(
+                        xml,
+                        "      <backup><duration>{}</duration></backup>",
+                        measure.sixteenths()
+                    );
+                }
+                let voice = staff_index * MAX_VOICES + v + 1;
+
+                for event in events.iter() {
+                    let pieces = NoteValue::split(event.length);
+                    for (p, (value, dotted)) in pieces.iter().enumerate() {
+                        let duration = value.sixteenths_dotted(*dotted);
+                        let dot = if *dotted { "<dot/>" } else { "" };
+                        if event.notes.is_empty() {
+                            let _ = writeln!(
+                                xml,
+                                "      <note><rest/><duration>{}</duration><voice>{}</voice><type>{}</type>{}<staff>{}</staff></note>",
+                                duration,
+                                voice,
+                                musicxml_type(*value),
+                                dot,
+                                number
+                            );
+                            continue;
+                        }
+
+                        for (n, note) in event.notes.iter().enumerate() {
+                            let (tie_stop, tie_start) =
+                                ties(note, event, (p, pieces.len()), &staff_measure.tied_in);
+                            let (letter, alteration, octave) = note.pitch();
+                            let mut element = String::from("      <note>");
+                            if n > 0 {
+                                element.push_str("<chord/>");
+                            }
+                            element.push_str("<pitch>");
+                            let _ = write!(element, "<step>{}</step>", LETTERS[letter as usize]);
+                            if alteration != 0 {
+                                let _ = write!(element, "<alter>{}</alter>", alteration);
+                            }
+                            let _ = write!(
+                                element,
+                                "<octave>{}</octave></pitch><duration>{}</duration>",
+                                octave, duration
+                            );
+                            if tie_stop {
+                                element.push_str("<tie type=\"stop\"/>");
+                            }
+                            if tie_start {
+                                element.push_str("<tie type=\"start\"/>");
+                            }
+                            let _ = write!(
+                                element,
+                                "<voice>{}</voice><type>{}</type>{}",
+                                voice,
+                                musicxml_type(*value),
+                                dot
+                            );
+                            if p == 0
+                                && let Some(accidental) = note.accidental
+                            {
+                                let name = match accidental {
+                                    Accidental::Sharp => "sharp",
+                                    Accidental::Flat => "flat",
+                                    Accidental::Natural => "natural",
+                                };
+                                let _ = write!(element, "<accidental>{}</accidental>", name);
+                            }
+                            let _ = write!(element, "<staff>{}</staff>", number);
+
+                            let mut notations = String::new();
+                            if tie_stop {
+                                notations.push_str("<tied type=\"stop\"/>");
+                            }
+                            if tie_start {
+                                notations.push_str("<tied type=\"start\"/>");
+                            }
+                            if p == 0
+                                && let Some(finger) = note.finger
+                            {
+                                let _ = write!(
+                                    notations,
+                                    "<technical><fingering>{}</fingering></technical>",
+                                    finger
+                                );
+                            }
+                            if !notations.is_empty() {
+                                let _ = write!(element, "<notations>{}</notations>", notations);
+                            }
+                            element.push_str("</note>");
+                            xml.push_str(&element);
+                            xml.push('\n');
+                        }
+                    }
+                }
+            }
+        }
+
+        xml.push_str("    </measure>\n");
+    }
+
+    xml.push_str("  </part>\n</score-partwise>\n");
+    fs::write(path, xml)
+}
+
+fn lilypond_pitch(note: &ScoreNote) -> String {
+    const NAMES: [&str; 7] = ["c", "d", "e", "f", "g", "a", "b"];
+    let (letter, alteration, octave) = note.pitch();
+    let mut pitch = NAMES[letter as usize].to_string();
+    match alteration {
+        1 => pitch.push_str("is"),
+        2 => pitch.push_str("isis"),
+        -1 => pitch.push_str("es"),
+        -2 => pitch.push_str("eses"),
+        _ => (),
+    }
+    // c' is middle C
+    let marks = octave - 3;
+    let mark = if marks > 0 { "'" } else { "," };
+    pitch.push_str(&mark.repeat(marks.unsigned_abs() as usize));
+    pitch
+}
+
+fn lilypond_duration(value: NoteValue, dotted: bool) -> String {
+    let duration = match value {
+        NoteValue::Whole => "1",
+        NoteValue::Half => "2",
+        NoteValue::Quarter => "4",
+        NoteValue::Eighth => "8",
+        NoteValue::Sixteenth => "16",
+    };
+    if dotted {
+        format!("{}.", duration)
+    } else {
+        duration.to_string()
+    }
+}
+
+/// The key's tonic, as LilyPond names it.
+fn lilypond_tonic(sharps: i8, minor: bool) -> &'static str {
+    const MAJOR: [&str; 15] = [
+        "ces", "ges", "des", "aes", "ees", "bes", "f", "c", "g", "d", "a", "e", "b", "fis", "cis",
+    ];
+    const MINOR: [&str; 15] = [
+        "aes", "ees", "bes", "f", "c", "g", "d", "a", "e", "b", "fis", "cis", "gis", "dis", "ais",
+    ];
+    let index = (sharps.clamp(-7, 7) + 7) as usize;
+    if minor { MINOR[index] } else { MAJOR[index] }
+}
+
+/// Writes the song as LilyPond source for a piano staff, the right hand on
+/// the upper staff and the left on the lower one, in sixteenths.
+pub fn write_lilypond(song: &Song, path: &Path) -> std::io::Result<()> {
+    let score = Score::of(song);
+
+    let mut ly = String::from("\\version \"2.24.0\"\n\n\\header {\n");
+    if let Some(title) = song.title() {
+        let _ = writeln!(ly, "  title = \"{}\"", title.replace('"', "\\\""));
+    }
+    if let Some(composer) = song.composer() {
+        let _ = writeln!(ly, "  composer = \"{}\"", composer.replace('"', "\\\""));
+    }
+    if let Some(copyright) = song.copyright()
+        && song.composer() != Some(copyright)
+    {
+        let _ = writeln!(ly, "  copyright = \"{}\"", copyright.replace('"', "\\\""));
+    }
+    ly.push_str("}\n\n\\new PianoStaff <<\n");
+
+    for staff in STAVES {
+        let clef = match staff {
+            Staff::Treble => "treble",
+            Staff::Bass => "bass",
+        };
+        let measures = staff_measures(&score, staff);
+        let voices = measures.iter().map(|m| m.voices.len()).max().unwrap_or(1);
+        let _ = writeln!(ly, "  \\new Staff <<");
+        for (v, direction) in VOICE_DIRECTIONS.iter().take(voices).enumerate() {
+            // stems go up in the first voice and down in the second once
+            // there are several
+            if voices > 1 {
+                let _ = writeln!(ly, "    \\new Voice {{ {}", direction);
+            } else {
+                let _ = writeln!(ly, "    \\new Voice {{");
+            }
+            if v == 0 {
+                let _ = writeln!(ly, "      \\clef {}", clef);
+            }
+
+            let mut signature = None;
+            let mut key = None;
+            for (i, (measure, staff_measure)) in score.measures.iter().zip(&measures).enumerate() {
+                ly.push_str("      ");
+                // the key, time and tempo go with the first voice
+                if v == 0 {
+                    let (sharps, minor) = key_of(song, measure);
+                    if key != Some((sharps, minor)) {
+                        let _ = write!(
+                            ly,
+                            "\\key {} {} ",
+                            lilypond_tonic(sharps, minor),
+                            if minor { "\\minor" } else { "\\major" }
+                        );
+                    }
+                    key = Some((sharps, minor));
+                    let time = (measure.numerator, measure.denominator);
+                    if signature != Some(time) {
+                        let _ = write!(ly, "\\time {}/{} ", time.0, time.1);
+                    }
+                    signature = Some(time);
+                    if staff == Staff::Treble {
+                        for bpm in tempos_in(song, measure, score.measures.get(i + 1)) {
+                            let _ = write!(ly, "\\tempo 4 = {} ", bpm);
+                        }
+                    }
+                }
+
+                let Some(events) = staff_measure
+                    .voices
+                    .get(v)
+                    .filter(|events| v == 0 || events.iter().any(|e| !e.notes.is_empty()))
+                else {
+                    // voices with nothing to play in the measure are left out
+                    for (value, dotted) in NoteValue::split(measure.sixteenths()) {
+                        let _ = write!(ly, "s{} ", lilypond_duration(value, dotted));
+                    }
+                    let _ = writeln!(ly, "| % {}", measure.number);
+                    continue;
+                };
+                for event in events.iter() {
+                    let pieces = NoteValue::split(event.length);
+                    for (p, (value, dotted)) in pieces.iter().enumerate() {
+                        let duration = lilypond_duration(*value, *dotted);
+                        let tie = |note: &ScoreNote| {
+                            let (_, tie_start) =
+                                ties(note, event, (p, pieces.len()), &staff_measure.tied_in);
+                            if tie_start { "~" } else { "" }
+                        };
+                        // fingering only on the first of tied notes
+                        let finger = |note: &ScoreNote| match note.finger {
+                            Some(finger) if p == 0 => format!("-{}", finger),
+                            _ => String::new(),
+                        };
+                        match event.notes.as_slice() {
+                            [] => {
+                                let _ = write!(ly, "r{} ", duration);
+                            }
+                            [note] => {
+                                let pitch = lilypond_pitch(note);
+                                let _ = write!(
+                                    ly,
+                                    "{}{}{}{} ",
+                                    pitch,
+                                    duration,
+                                    finger(note),
+                                    tie(note)
+                                );
+                            }
+                            // chords tie note by note, as only some may be held
+                            notes => {
+                                let pitches: Vec<String> = notes
+                                    .iter()
+                                    .map(|note| {
+                                        format!(
+                                            "{}{}{}",
+                                            lilypond_pitch(note),
+                                            finger(note),
+                                            tie(note)
+                                        )
+                                    })
+                                    .collect();
+                                let _ = write!(ly, "<{}>{} ", pitches.join(" "), duration);
+                            }
+                        }
+                    }
+                }
+                let _ = writeln!(ly, "| % {}", measure.number);
+            }
+            ly.push_str("    }\n");
+        }
+        ly.push_str("  >>\n");
+    }
+
+    ly.push_str(">>\n");
+    fs::write(path, ly)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::musicxml;
+    use crate::song::KeySignatureChange;
+    use crate::song::tests::{note, parts, song};
+
+    fn temp_path(name: &str, extension: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "piano1-{}-{}.{}",
+            name,
+            std::process::id(),
+            extension
+        ))
+    }
+
+    /// The song written as MusicXML and read back, with the XML.
+    fn round_trip(name: &str, song: &Song) -> (Song, String) {
+        let path = temp_path(name, "musicxml");
+        write_musicxml(song, &path).unwrap();
+        let xml = fs::read_to_string(&path).unwrap();
+        let read = musicxml::read(&path);
+        fs::remove_file(&path).unwrap();
+        (read.unwrap(), xml)
+    }
+
+    /// Key, start and stop in ticks of every note.
+    fn notes(song: &Song) -> Vec<(u8, u32, Option<u32>)> {
+        let mut notes: Vec<_> = song
+            .note_blocks()
+            .map(|b| (b.key.byte(), b.start_delta, b.stop_delta))
+            .collect();
+        notes.sort_by_key(|(key, start, _)| (*start, *key));
+        notes
+    }
+
+    #[test]
+    fn notes_tied_over_the_bar_line_read_back_whole() {
+        let written = song(
+            vec![
+                note(60, 0, 1440),
+                note(67, 1440, 2400),
+                note(64, 1920, 2400),
+            ],
+            &[(0, 500_000)],
+        );
+        let (read, _) = round_trip("tie", &written);
+        assert_eq!(notes(&read), notes(&written));
+    }
+
+    #[test]
+    fn odd_lengths_are_tied_values() {
+        // five sixteenths, a quarter tied to a sixteenth
+        let written = song(vec![note(60, 0, 600), note(62, 600, 960)], &[(0, 500_000)]);
+        let (read, xml) = round_trip("five-sixteenths", &written);
+        assert_eq!(notes(&read), notes(&written));
+        assert!(xml.contains("<tie type=\"start\"/><voice>1</voice><type>quarter</type>"));
+    }
+
+    #[test]
+    fn held_notes_keep_their_length_under_later_chords() {
+        // a whole note under quarter note chords, and a chord of a half and
+        // a quarter note
+        let written = song(
+            vec![
+                note(48, 0, 1920),
+                note(64, 0, 480),
+                note(67, 0, 480),
+                note(65, 480, 960),
+                note(69, 480, 960),
+                note(60, 960, 1920),
+                note(64, 960, 1440),
+            ],
+            &[(0, 500_000)],
+        );
+        let (read, xml) = round_trip("voices", &written);
+        assert_eq!(notes(&read), notes(&written));
+        assert!(xml.contains("<voice>2</voice>"));
+    }
+
+    #[test]
+    fn key_changes_are_written_where_they_happen() {
+        let mut written = parts(
+            vec![note(63, 0, 1920), note(66, 1920, 3840)],
+            &[(0, 500_000)],
+        );
+        written.key_signatures = vec![
+            KeySignatureChange {
+                delta: 0,
+                time: 0,
+                sharps: -3,
+                minor: false,
+            },
+            KeySignatureChange {
+                delta: 1920,
+                time: 0,
+                sharps: 2,
+                minor: true,
+            },
+        ];
+        let written = Song::from_parts(written);
+
+        let (read, xml) = round_trip("keys", &written);
+        assert_eq!(notes(&read), notes(&written));
+        let keys: Vec<_> = read
+            .key_signatures()
+            .iter()
+            .map(|ks| (ks.delta, ks.sharps, ks.minor))
+            .collect();
+        assert_eq!(keys, vec![(0, -3, false), (1920, 2, true)]);
+        // the E flat is in the key, the F sharp too
+        assert!(!xml.contains("<accidental>"));
+
+        let path = temp_path("keys", "ly");
+        write_lilypond(&written, &path).unwrap();
+        let ly = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(ly.contains("\\key ees \\major \\time 4/4 \\tempo 4 = 120 ees'1 | % 1"));
+        assert!(ly.contains("\\key b \\minor fis'1 | % 2"));
+    }
+
+    #[test]
+    fn lilypond_pitches_count_octaves_from_middle_c() {
+        let written = song(
+            [60, 61, 48, 35, 84]
+                .iter()
+                .enumerate()
+                .map(|(i, key)| note(*key, i as u32 * 480, i as u32 * 480 + 480))
+                .collect(),
+            &[(0, 500_000)],
+        );
+        let score = Score::of(&written);
+        let pitches: Vec<String> = score.measures[..2]
+            .iter()
+            .flat_map(|m| m.notes.iter().map(lilypond_pitch))
+            .collect();
+        assert_eq!(pitches, ["c'", "cis'", "c", "b,,", "c'''"]);
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::keyboard;
+use crate::song::{Hand, Song};
+
+/// More notes than fingers, such chords are left alone.
+const MAX_CHORD: usize = 5;
+/// Microseconds between chords after which the hand has had time to move freely.
+const FREE_MOVE_GAP: u32 = 1_000_000;
+
+/// `<song file>.fingering`, next to the song.
+pub fn sidecar_path(song_path: &Path) -> PathBuf {
+    let mut name = song_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".fingering");
+    song_path.with_file_name(name)
+}
+
+/// Reads the song's fingering file, lines of `<tick> <key> <finger>`, and
+/// puts the fingers on the notes. Returns how many notes got one.
+pub fn load(song: &mut Song, song_path: &Path) -> io::Result<usize> {
+    let content = fs::read_to_string(sidecar_path(song_path))?;
+
+    let mut fingers = HashMap::new();
+    for line in content.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if let [tick, key, finger] = words[..]
+            && let (Ok(tick), Ok(key), Ok(finger)) =
+                (tick.parse::<u32>(), key.parse::<u8>(), finger.parse::<u8>())
+            && (1..=5).contains(&finger)
+        {
+            fingers.insert((tick, key), finger);
+        }
+    }
+
+    let mut count = 0;
+    for block in song.note_blocks_mut() {
+        if let Some(finger) = fingers.get(&(block.start_delta, block.key.byte())) {
+            block.finger = Some(*finger);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Comfortable stretch, in semitones, between a lower and a higher finger.
+fn max_span(low: u8, high: u8) -> i32 {
+    match (low, high) {
+        (1, 2) => 5,
+        (1, 3) => 7,
+        (1, 4) => 9,
+        (1, 5) => 10,
+        (2, 3) => 3,
+        (2, 4) => 5,
+        (2, 5) => 7,
+        (3, 4) => 3,
+        (3, 5) => 5,
+        (4, 5) => 3,
+        _ => 0,
+    }
+}
+
+/// Cost of `distance` semitones upwards between a lower and a higher
+/// finger, without crossing.
+fn stretch_cost(distance: i32, low: u8, high: u8) -> f32 {
+    let span = max_span(low, high);
+    let cramped = (high - low) as i32 - 1;
+    if distance > span {
+        (distance - span) as f32 * 2.
+    } else if distance < cramped {
+        (cramped - distance) as f32 * 0.5
+    } else {
+        0.
+    }
+}
+
+/// Cost of playing `to` with `to_finger` right after `from` with
+/// `from_finger`, on the same hand.
+fn step_cost(hand: Hand, from: u8, from_finger: u8, to: u8, to_finger: u8) -> f32 {
+    // as if on the right hand, where higher fingers play higher keys
+    let distance = match hand {
+        Hand::Right => to as i32 - from as i32,
+        Hand::Left => from as i32 - to as i32,
+    };
+
+    let mut cost = if from_finger == to_finger {
+        if distance == 0 {
+            0.
+        } else {
+            4. + distance.abs() as f32
+        }
+    } else if distance == 0 {
+        // changing fingers on a repeated key
+        1.
+    } else if (to_finger > from_finger) == (distance > 0) {
+        let (low, high) = (from_finger.min(to_finger), from_finger.max(to_finger));
+        stretch_cost(distance.abs(), low, high)
+    } else if (from_finger == 1 && to_finger != 5) || (to_finger == 1 && from_finger != 5) {
+        // passing the thumb under, or a finger over the thumb, which the
+        // little finger is too short for
+        2. + (distance.abs() - 5).max(0) as f32 * 2.
+    } else {
+        8. + distance.abs() as f32
+    };
+
+    if !keyboard::is_white(to) {
+        cost += match to_finger {
+            1 => 2.,
+            5 => 1.,
+            _ => 0.,
+        };
+    }
+    cost
+}
+
+/// Every way to put fingers on a chord of `keys`, lowest key first: the
+/// right hand's fingers go up with the keys, the left hand's down.
+fn chord_fingerings(hand: Hand, keys: &[u8]) -> Vec<Vec<u8>> {
+    let mut fingerings = vec![];
+    for mask in 0u8..32 {
+        if mask.count_ones() as usize != keys.len() {
+            continue;
+        }
+        let mut fingers: Vec<u8> = (1..=5).filter(|f| mask & (1 << (f - 1)) != 0).collect();
+        if hand == Hand::Left {
+            fingers.reverse();
+        }
+        fingerings.push(fingers);
+    }
+    fingerings
+}
+
+/// How far the fingers stretch within a chord.
+fn chord_cost(keys: &[u8], fingers: &[u8]) -> f32 {
+    keys.windows(2)
+        .zip(fingers.windows(2))
+        .map(|(k, f)| {
+            let (low, high) = (f[0].min(f[1]), f[0].max(f[1]));
+            stretch_cost((k[1] - k[0]) as i32, low, high)
+        })
+        .sum()
+}
+
+/// Moving between two chords, by where the outer fingers go.
+fn transition_cost(hand: Hand, from: (&[u8], &[u8]), to: (&[u8], &[u8]), gap: u32) -> f32 {
+    let (from_keys, from_fingers) = from;
+    let (to_keys, to_fingers) = to;
+    let last = |v: &[u8]| v[v.len() - 1];
+    let cost = (step_cost(
+        hand,
+        from_keys[0],
+        from_fingers[0],
+        to_keys[0],
+        to_fingers[0],
+    ) + step_cost(
+        hand,
+        last(from_keys),
+        last(from_fingers),
+        last(to_keys),
+        last(to_fingers),
+    )) / 2.;
+    if gap > FREE_MOVE_GAP {
+        cost * 0.2
+    } else {
+        cost
+    }
+}
+
+/// The cheapest fingering for one hand's chords, in order, by dynamic
+/// programming over every chord's possible fingerings. Fingers already
+/// `given` for a note, by start time and key, are kept.
+fn suggest_run(
+    hand: Hand,
+    chords: &[(u32, Vec<u8>)],
+    given: &HashMap<(u32, u8), u8>,
+) -> Vec<Vec<u8>> {
+    if chords.is_empty() {
+        return vec![];
+    }
+    let options: Vec<Vec<Vec<u8>>> = chords
+        .iter()
+        .map(|(time, keys)| {
+            let all = chord_fingerings(hand, keys);
+            let keeping_given: Vec<Vec<u8>> = all
+                .iter()
+                .filter(|fingers| {
+                    keys.iter().zip(fingers.iter()).all(|(key, finger)| {
+                        given
+                            .get(&(*time, *key))
+                            .is_none_or(|given| given == finger)
+                    })
+                })
+                .cloned()
+                .collect();
+            // given fingers no fingering agrees with, like a crossing
+            // within a chord, are kept but don't limit the rest
+            if keeping_given.is_empty() {
+                all
+            } else {
+                keeping_given
+            }
+        })
+        .collect();
+
+    // cheapest cost of each option and the option before it
+    let mut costs: Vec<Vec<(f32, usize)>> = vec![
+        options[0]
+            .iter()
+            .map(|fingers| (chord_cost(&chords[0].1, fingers), 0))
+            .collect(),
+    ];
+    for i in 1..chords.len() {
+        let gap = chords[i].0 - chords[i - 1].0;
+        let row = options[i]
+            .iter()
+            .map(|fingers| {
+                let own = chord_cost(&chords[i].1, fingers);
+                options[i - 1]
+                    .iter()
+                    .enumerate()
+                    .map(|(j, previous)| {
+                        let cost = costs[i - 1][j].0
+                            + transition_cost(
+                                hand,
+                                (&chords[i - 1].1, previous),
+                                (&chords[i].1, fingers),
+                                gap,
+                            );
+                        (cost + own, j)
+                    })
+                    .min_by(|a, b| a.0.total_cmp(&b.0))
+                    .unwrap()
+            })
+            .collect();
+        costs.push(row);
+    }
+
+    let mut best = costs[chords.len() - 1]
+        .iter()
+        .enumerate()
+        .min_by(|a, b| a.1.0.total_cmp(&b.1.0))
+        .map(|(i, _)| i)
+        .unwrap();
+    let mut fingerings = vec![];
+    for i in (0..chords.len()).rev() {
+        fingerings.push(options[i][best].clone());
+        best = costs[i][best].1;
+    }
+    fingerings.reverse();
+    fingerings
+}
+
+/// Puts suggested fingers on the notes of the song that have none, weighing
+/// how far the fingers have to stretch and how awkwardly they cross. The
+/// fingers notes already have are worked around.
+pub fn suggest(song: &mut Song) {
+    // keys starting together, per hand and start time
+    let mut groups: BTreeMap<(Hand, u32), Vec<u8>> = BTreeMap::new();
+    let mut given: HashMap<Hand, HashMap<(u32, u8), u8>> = HashMap::new();
+    for block in song.note_blocks() {
+        groups
+            .entry((block.hand, block.start_time))
+            .or_default()
+            .push(block.key.byte());
+        if let Some(finger) = block.finger {
+            given
+                .entry(block.hand)
+                .or_default()
+                .insert((block.start_time, block.key.byte()), finger);
+        }
+    }
+    for keys in groups.values_mut() {
+        keys.sort();
+        keys.dedup();
+    }
+
+    let mut fingers: HashMap<(Hand, u32, u8), u8> = HashMap::new();
+    for hand in [Hand::Left, Hand::Right] {
+        let given = given.remove(&hand).unwrap_or_default();
+        // chords too big for a hand break the runs the fingering is
+        // suggested for
+        let mut run: Vec<(u32, Vec<u8>)> = vec![];
+        let hand_groups = groups
+            .range((hand, 0)..=(hand, u32::MAX))
+            .map(|((_, time), keys)| (*time, keys.clone()))
+            .chain([(u32::MAX, vec![0; MAX_CHORD + 1])]);
+        for (time, keys) in hand_groups {
+            if keys.len() <= MAX_CHORD {
+                run.push((time, keys));
+                continue;
+            }
+            for ((time, keys), chord_fingers) in run.iter().zip(suggest_run(hand, &run, &given)) {
+                for (key, finger) in keys.iter().zip(chord_fingers) {
+                    fingers.insert((hand, *time, *key), finger);
+                }
+            }
+            run.clear();
+        }
+    }
+
+    for block in song.note_blocks_mut() {
+        if block.finger.is_none() {
+            block.finger = fingers
+                .get(&(block.hand, block.start_time, block.key.byte()))
+                .copied();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::tests::{note, song};
+
+    /// Right hand eighth notes at 120 bpm, fingers given where not `None`.
+    fn suggested(keys: &[(u8, Option<u8>)]) -> Vec<u8> {
+        let notes = keys
+            .iter()
+            .enumerate()
+            .map(|(i, (key, finger))| {
+                let mut block = note(*key, i as u32 * 240, i as u32 * 240 + 240);
+                block.finger = *finger;
+                block
+            })
+            .collect();
+        let mut song = song(notes, &[(0, 500_000)]);
+        suggest(&mut song);
+        song.note_blocks().map(|b| b.finger.unwrap()).collect()
+    }
+
+    #[test]
+    fn a_scale_passes_the_thumb_under() {
+        let scale = [60, 62, 64, 65, 67, 69, 71, 72].map(|key| (key, None));
+        let fingers = suggested(&scale);
+        assert_eq!(fingers[0], 1);
+        // fingers go up one by one, but for the thumb passing under once,
+        // after the third or fourth finger
+        let mut passes = 0;
+        for pair in fingers.windows(2) {
+            if pair[1] == 1 {
+                assert!([3, 4].contains(&pair[0]), "{:?}", fingers);
+                passes += 1;
+            } else {
+                assert_eq!(pair[1], pair[0] + 1, "{:?}", fingers);
+            }
+        }
+        assert_eq!(passes, 1, "{:?}", fingers);
+    }
+
+    #[test]
+    fn a_stretch_past_the_hand_changes_fingers() {
+        // an octave is further than any one finger moves in an eighth
+        let fingers = suggested(&[(60, None), (72, None), (60, None)]);
+        assert_ne!(fingers[0], fingers[1]);
+        assert!(fingers[0] < fingers[1]);
+    }
+
+    #[test]
+    fn given_fingers_are_kept_and_worked_around() {
+        // starting the scale on the second finger moves the thumb to E
+        let mut scale = [60, 62, 64, 65, 67, 69, 71, 72].map(|key| (key, None));
+        scale[0].1 = Some(2);
+        let fingers = suggested(&scale);
+        assert_eq!(fingers[0], 2);
+        assert_eq!(fingers[1], 3);
+        assert!(fingers[2..4].contains(&1));
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+
+use midix::prelude::Key;
+
+use crate::engine::PlayedNote;
+use crate::song::{NoteBlock, Song};
+
+/// How far from its note, in seconds, a key may be pressed and still count.
+pub const HIT_WINDOW: f32 = 0.15;
+/// Hits later than this, in seconds, count as played late.
+pub const LATE_THRESHOLD: f32 = 0.06;
+
+pub enum Verdict {
+    /// Played, this many seconds late (early when negative).
+    Hit(f32),
+    /// Not played at all.
+    Miss,
+    /// Played where the song has no such note.
+    Wrong,
+}
+
+pub struct Judgment {
+    pub key: Key,
+    /// Song time of the note, or of the key press for wrong notes.
+    pub time: f32,
+    pub verdict: Verdict,
+    /// The song note judged, none for wrong notes.
+    pub note: Option<NoteBlock>,
+}
+
+pub struct Grade {
+    pub judgments: Vec<Judgment>,
+    /// Misses and wrong notes per measure, counting from 1.
+    pub measure_errors: BTreeMap<usize, u32>,
+    /// Misses and late hits per song note, by start time and key.
+    pub note_errors: BTreeMap<(u32, u8), u32>,
+}
+
+impl Grade {
+    pub fn hits(&self) -> usize {
+        self.count(|v| matches!(v, Verdict::Hit(_)))
+    }
+
+    pub fn misses(&self) -> usize {
+        self.count(|v| matches!(v, Verdict::Miss))
+    }
+
+    pub fn wrong(&self) -> usize {
+        self.count(|v| matches!(v, Verdict::Wrong))
+    }
+
+    fn count(&self, f: impl Fn(&Verdict) -> bool) -> usize {
+        self.judgments.iter().filter(|j| f(&j.verdict)).count()
+    }
+
+    /// Hits out of every note that was due or played, from 0 to 1.
+    pub fn accuracy(&self) -> f32 {
+        if self.judgments.is_empty() {
+            return 0.;
+        }
+        self.hits() as f32 / self.judgments.len() as f32
+    }
+}
+
+/// Judges what was played against the song notes starting in `spans`, the
+/// stretches of song time (in seconds) that actually went by while playing.
+/// Each span is judged on its own, so every pass of a loop counts. Spans
+/// before `first_pass` are left out.
+pub fn grade(
+    song: &Song,
+    performance: &[PlayedNote],
+    spans: &[(f32, f32)],
+    first_pass: usize,
+) -> Grade {
+    let mut judgments = vec![];
+    let mut note_errors = BTreeMap::new();
+
+    for (pass, (from, to)) in spans.iter().enumerate().skip(first_pass) {
+        // a span's end is where the song stands, so whatever is due there
+        // hasn't been missed yet
+        let in_span = |time: f32| time >= *from && time < *to;
+
+        let mut played: Vec<&PlayedNote> = performance
+            .iter()
+            .filter(|p| p.pass == pass && in_span(p.start))
+            .collect();
+        let mut pass_judgments = vec![];
+
+        let mut due: Vec<_> = song
+            .note_blocks()
+            .filter(|b| in_span(b.start_time as f32 / 1_000_000.))
+            .collect();
+        due.sort_by_key(|b| b.start_time);
+
+        for block in due {
+            let start = block.start_time as f32 / 1_000_000.;
+            let closest = played
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| p.key == block.key && (p.start - start).abs() <= HIT_WINDOW)
+                .min_by(|(_, a), (_, b)| {
+                    (a.start - start).abs().total_cmp(&(b.start - start).abs())
+                })
+                .map(|(i, _)| i);
+            let verdict = match closest {
+                Some(i) => Verdict::Hit(played.remove(i).start - start),
+                None => Verdict::Miss,
+            };
+            match verdict {
+                Verdict::Hit(offset) if offset <= LATE_THRESHOLD => (),
+                _ => {
+                    *note_errors
+                        .entry((block.start_time, block.key.byte()))
+                        .or_insert(0) += 1
+                }
+            }
+            pass_judgments.push(Judgment {
+                key: block.key,
+                time: start,
+                verdict,
+                note: Some(block.clone()),
+            });
+        }
+
+        for p in played {
+            pass_judgments.push(Judgment {
+                key: p.key,
+                time: p.start,
+                verdict: Verdict::Wrong,
+                note: None,
+            });
+        }
+        pass_judgments.sort_by(|a, b| a.time.total_cmp(&b.time));
+        judgments.extend(pass_judgments);
+    }
+
+    let measure_starts = song.measure_starts();
+    let mut measure_errors = BTreeMap::new();
+    for judgment in judgments.iter() {
+        if let Verdict::Hit(_) = judgment.verdict {
+            continue;
+        }
+        let time = (judgment.time * 1_000_000.) as u32;
+        let measure = measure_starts
+            .partition_point(|start| *start <= time)
+            .max(1);
+        *measure_errors.entry(measure).or_insert(0) += 1;
+    }
+
+    Grade {
+        judgments,
+        measure_errors,
+        note_errors,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::tests::{note, song};
+
+    fn played(key: u8, start: f32, pass: usize) -> PlayedNote {
+        PlayedNote {
+            key: Key::from_databyte(key).unwrap(),
+            start,
+            stop: None,
+            pass,
+        }
+    }
+
+    #[test]
+    fn notes_are_hit_missed_or_wrong() {
+        // quarter notes half a second apart, four to a measure
+        let song = song(
+            vec![
+                note(60, 0, 480),
+                note(62, 480, 960),
+                note(64, 960, 1440),
+                note(65, 1920, 2400),
+            ],
+            &[(0, 500_000)],
+        );
+        let performance = [
+            played(60, 0.02, 0),
+            // late enough to be remembered as an error
+            played(62, 0.6, 0),
+            played(67, 1.0, 0),
+            // outside the hit window
+            played(65, 2.2, 0),
+        ];
+        let grade = grade(&song, &performance, &[(0., 3.)], 0);
+
+        assert_eq!((grade.hits(), grade.misses(), grade.wrong()), (2, 2, 2));
+        assert_eq!(grade.accuracy(), 2. / 6.);
+        assert_eq!(
+            grade.note_errors.into_iter().collect::<Vec<_>>(),
+            vec![
+                ((500_000, 62), 1),
+                ((1_000_000, 64), 1),
+                ((2_000_000, 65), 1)
+            ]
+        );
+        // the 64 and 67 in the first measure, the 65 twice in the second
+        assert_eq!(
+            grade.measure_errors.into_iter().collect::<Vec<_>>(),
+            vec![(1, 2), (2, 2)]
+        );
+    }
+
+    #[test]
+    fn each_pass_is_judged_on_its_own() {
+        let song = song(vec![note(60, 0, 480)], &[(0, 500_000)]);
+        let performance = [played(60, 0., 0), played(60, 0., 2)];
+        let spans = [(0., 0.5), (0., 0.5), (0., 0.5)];
+
+        let grade = grade(&song, &performance, &spans, 0);
+        assert_eq!((grade.hits(), grade.misses()), (2, 1));
+        let grade = super::grade(&song, &performance, &spans, 2);
+        assert_eq!((grade.hits(), grade.misses()), (1, 0));
+    }
+}
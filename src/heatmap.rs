@@ -0,0 +1,90 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::history::PracticeRecord;
+use crate::song::Song;
+
+/// Where a song went wrong across all past attempts, for tinting the
+/// timeline.
+pub struct Heatmap {
+    /// Start and end of every measure, in seconds.
+    measures: Vec<(f32, f32)>,
+    measure_errors: BTreeMap<usize, u32>,
+    note_errors: HashMap<(u32, u8), u32>,
+    max_measure_errors: u32,
+    max_note_errors: u32,
+}
+
+impl Heatmap {
+    pub fn new(song: &Song, records: &[PracticeRecord]) -> Self {
+        let starts = song.measure_starts();
+        let end = song.duration().max(starts.last().copied().unwrap_or(0));
+        let measures = starts
+            .iter()
+            .zip(starts.iter().skip(1).chain([end].iter()))
+            .map(|(from, to)| (*from as f32 / 1_000_000., *to as f32 / 1_000_000.))
+            .collect();
+
+        let mut measure_errors = BTreeMap::new();
+        let mut note_errors = HashMap::new();
+        for record in records {
+            for (measure, count) in record.measure_errors.iter() {
+                *measure_errors.entry(*measure).or_insert(0) += count;
+            }
+            for (note, count) in record.note_errors.iter() {
+                *note_errors.entry(*note).or_insert(0) += count;
+            }
+        }
+
+        Self {
+            measures,
+            max_measure_errors: measure_errors.values().copied().max().unwrap_or(0),
+            max_note_errors: note_errors.values().copied().max().unwrap_or(0),
+            measure_errors,
+            note_errors,
+        }
+    }
+
+    /// Every measure as `(from, to, heat)`, heat going from 0 to 1.
+    pub fn measures(&self) -> impl Iterator<Item = (f32, f32, f32)> + '_ {
+        self.measures.iter().enumerate().map(|(i, (from, to))| {
+            let errors = self.measure_errors.get(&(i + 1)).copied().unwrap_or(0);
+            (*from, *to, heat(errors, self.max_measure_errors))
+        })
+    }
+
+    /// How often the note starting at `start_time` on `key` went wrong,
+    /// from 0 to 1.
+    pub fn note_heat(&self, start_time: u32, key: u8) -> f32 {
+        let errors = self
+            .note_errors
+            .get(&(start_time, key))
+            .copied()
+            .unwrap_or(0);
+        heat(errors, self.max_note_errors)
+    }
+
+    /// The measure around `time` if anything in it ever went wrong.
+    pub fn hot_measure_at(&self, time: f32) -> Option<(f32, f32)> {
+        let (index, (from, to)) = self
+            .measures
+            .iter()
+            .enumerate()
+            .find(|(_, (from, to))| time >= *from && time < *to)?;
+        let measure_hot = self
+            .measure_errors
+            .get(&(index + 1))
+            .is_some_and(|e| *e > 0);
+        let note_hot = self.note_errors.iter().any(|((start, _), errors)| {
+            let start = *start as f32 / 1_000_000.;
+            *errors > 0 && start >= *from && start < *to
+        });
+        (measure_hot || note_hot).then_some((*from, *to))
+    }
+}
+
+fn heat(errors: u32, max_errors: u32) -> f32 {
+    if max_errors == 0 {
+        return 0.;
+    }
+    errors as f32 / max_errors as f32
+}
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::engine::GameMode;
+
+/// One practice attempt at a song, from start or reset to reset or quit.
+pub struct PracticeRecord {
+    /// Seconds since the Unix epoch.
+    pub date: u64,
+    pub mode: GameMode,
+    pub tempo: f32,
+    pub accuracy: f32,
+    /// Seconds spent with the song playing.
+    pub practiced: f32,
+    /// Misses and wrong notes per measure, counting from 1.
+    pub measure_errors: BTreeMap<usize, u32>,
+    /// Misses and late hits per song note, by start time and key.
+    pub note_errors: BTreeMap<(u32, u8), u32>,
+}
+
+impl PracticeRecord {
+    fn to_line(&self) -> String {
+        let errors: Vec<String> = self
+            .measure_errors
+            .iter()
+            .map(|(measure, count)| format!("{}:{}", measure, count))
+            .collect();
+        let note_errors: Vec<String> = self
+            .note_errors
+            .iter()
+            .map(|((start, key), count)| format!("{}/{}:{}", start, key, count))
+            .collect();
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.date,
+            self.mode.name(),
+            self.tempo,
+            self.accuracy,
+            self.practiced,
+            errors.join(","),
+            note_errors.join(",")
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        // records written before notes were tracked have no note errors
+        let (date, mode, tempo, accuracy, practiced, errors, notes) = match fields[..] {
+            [date, mode, tempo, accuracy, practiced, errors] => {
+                (date, mode, tempo, accuracy, practiced, errors, "")
+            }
+            [date, mode, tempo, accuracy, practiced, errors, notes] => {
+                (date, mode, tempo, accuracy, practiced, errors, notes)
+            }
+            _ => return None,
+        };
+        let mut measure_errors = BTreeMap::new();
+        for error in errors.split(',').filter(|e| !e.is_empty()) {
+            let (measure, count) = error.split_once(':')?;
+            measure_errors.insert(measure.parse().ok()?, count.parse().ok()?);
+        }
+        let mut note_errors = BTreeMap::new();
+        for error in notes.split(',').filter(|e| !e.is_empty()) {
+            let (note, count) = error.split_once(':')?;
+            let (start, key) = note.split_once('/')?;
+            note_errors.insert(
+                (start.parse().ok()?, key.parse().ok()?),
+                count.parse().ok()?,
+            );
+        }
+        Some(Self {
+            date: date.parse().ok()?,
+            mode: GameMode::from_name(mode)?,
+            tempo: tempo.parse().ok()?,
+            accuracy: accuracy.parse().ok()?,
+            practiced: practiced.parse().ok()?,
+            measure_errors,
+            note_errors,
+        })
+    }
+}
+
+/// FNV-1a, which unlike the standard library's hasher is the same on every
+/// build, as file names made from it have to be.
+fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// `<data dir>/history/<song file name>-<path hash>.tsv`, one record per
+/// line. Songs with the same name in different directories get their own.
+fn history_path(song_path: &Path) -> PathBuf {
+    let name = song_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let full_path = fs::canonicalize(song_path).unwrap_or_else(|_| song_path.to_path_buf());
+    let hash = stable_hash(full_path.to_string_lossy().as_bytes());
+    config::data_dir()
+        .join("history")
+        .join(format!("{}-{:016x}.tsv", name, hash))
+}
+
+pub fn append(song_path: &Path, records: &[PracticeRecord]) -> io::Result<()> {
+    let path = history_path(song_path);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    for record in records {
+        writeln!(file, "{}", record.to_line())?;
+    }
+    Ok(())
+}
+
+/// Every record kept for the song, oldest first.
+pub fn load(song_path: &Path) -> Vec<PracticeRecord> {
+    let path = history_path(song_path);
+    let Ok(content) = fs::read_to_string(&path) else {
+        return vec![];
+    };
+
+    let mut records = vec![];
+    for (line_number, line) in content.lines().enumerate() {
+        match PracticeRecord::from_line(line) {
+            Some(record) => records.push(record),
+            None => println!("{}:{}: bad record", path.display(), line_number + 1),
+        }
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_read_back_from_their_lines() {
+        let record = PracticeRecord {
+            date: 1_700_000_000,
+            mode: GameMode::LearnBlocking,
+            tempo: 0.75,
+            accuracy: 0.5,
+            practiced: 42.5,
+            measure_errors: BTreeMap::from([(1, 2), (7, 1)]),
+            note_errors: BTreeMap::from([((500_000, 60), 3)]),
+        };
+        let read = PracticeRecord::from_line(&record.to_line()).unwrap();
+        assert_eq!(read.date, record.date);
+        assert_eq!(read.mode, record.mode);
+        assert_eq!(read.tempo, record.tempo);
+        assert_eq!(read.accuracy, record.accuracy);
+        assert_eq!(read.practiced, record.practiced);
+        assert_eq!(read.measure_errors, record.measure_errors);
+        assert_eq!(read.note_errors, record.note_errors);
+    }
+
+    #[test]
+    fn older_records_without_note_errors_still_read() {
+        let read = PracticeRecord::from_line("1700000000\tplay\t1\t0.9\t10\t3:1").unwrap();
+        assert_eq!(read.measure_errors, BTreeMap::from([(3, 1)]));
+        assert!(read.note_errors.is_empty());
+    }
+
+    #[test]
+    fn bad_records_are_rejected() {
+        for line in [
+            "",
+            "1700000000\tplay\t1\t0.9",
+            "1700000000\tdance\t1\t0.9\t10\t",
+            "1700000000\tplay\t1\t0.9\t10\t3-1",
+            "1700000000\tplay\t1\t0.9\t10\t\t500000:1",
+        ] {
+            assert!(PracticeRecord::from_line(line).is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn songs_with_the_same_name_keep_separate_histories() {
+        let a = history_path(Path::new("/songs/a/song.mid"));
+        let b = history_path(Path::new("/songs/b/song.mid"));
+        assert_ne!(a, b);
+        assert_eq!(a, history_path(Path::new("/songs/a/song.mid")));
+        assert!(
+            a.file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("song.mid-")
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use crate::song::Song;
+
+/// Lowest and highest key of the common keyboard sizes.
+const SIZES: [(u32, u8, u8); 5] = [
+    (25, 48, 72),  // C3 to C5
+    (49, 36, 84),  // C2 to C6
+    (61, 36, 96),  // C2 to C7
+    (76, 28, 103), // E1 to G7
+    (88, 21, 108), // A0 to C8
+];
+
+pub fn is_white(key: u8) -> bool {
+    matches!(key % 12, 0 | 2 | 4 | 5 | 7 | 9 | 11)
+}
+
+/// A stretch of keys, both ends included, as MIDI key numbers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyRange {
+    pub low: u8,
+    pub high: u8,
+}
+
+impl KeyRange {
+    /// The keys of a keyboard with `keys` keys, if it is one of the common
+    /// sizes.
+    pub fn of_size(keys: u32) -> Option<Self> {
+        SIZES
+            .iter()
+            .find(|(size, _, _)| *size == keys)
+            .map(|(_, low, high)| Self {
+                low: *low,
+                high: *high,
+            })
+    }
+
+    pub fn sizes() -> impl Iterator<Item = u32> {
+        SIZES.iter().map(|(size, _, _)| *size)
+    }
+
+    /// The keys the song plays, if it plays any.
+    pub fn of_song(song: &Song) -> Option<Self> {
+        let low = song.note_blocks().map(|b| b.key.byte()).min()?;
+        let high = song.note_blocks().map(|b| b.key.byte()).max()?;
+        Some(Self { low, high })
+    }
+
+    /// Number of keys in the range.
+    pub fn size(&self) -> u32 {
+        (self.high - self.low) as u32 + 1
+    }
+
+    pub fn contains(&self, key: u8) -> bool {
+        key >= self.low && key <= self.high
+    }
+
+    pub fn union(&self, other: &KeyRange) -> Self {
+        Self {
+            low: self.low.min(other.low),
+            high: self.high.max(other.high),
+        }
+    }
+
+    /// Widened evenly to at least `keys` keys, then out to white keys at
+    /// both ends, as keyboards start and end on them.
+    pub fn widened(&self, keys: u8) -> Self {
+        let mut low = self.low;
+        let mut high = self.high;
+        while high - low + 1 < keys {
+            low = low.saturating_sub(1);
+            high = high.saturating_add(1).min(127);
+            if low == 0 && high == 127 {
+                break;
+            }
+        }
+        while !is_white(low) {
+            low -= 1;
+        }
+        while !is_white(high) {
+            high += 1;
+        }
+        Self { low, high }
+    }
+
+    pub fn white_keys(&self) -> u32 {
+        (self.low..=self.high).filter(|key| is_white(*key)).count() as u32
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::config;
+use crate::difficulty::Difficulty;
+use crate::song::Song;
+
+const EXTENSIONS: [&str; 7] = ["mid", "midi", "kar", "musicxml", "mxl", "xml", "abc"];
+
+/// What the picker shows about a song, read from the file once and cached.
+#[derive(Clone)]
+pub struct SongInfo {
+    pub path: PathBuf,
+    /// Modification time of the file, in seconds since the epoch, so
+    /// changed files are read again.
+    pub modified: u64,
+    pub title: String,
+    pub composer: String,
+    pub track_names: Vec<String>,
+    /// In seconds.
+    pub duration: f32,
+    pub key: String,
+    pub notes: usize,
+    pub difficulty: Difficulty,
+}
+
+impl SongInfo {
+    fn read(path: &Path, modified: u64) -> Option<Self> {
+        let song = match Song::read(path) {
+            Ok(song) => song,
+            Err(why) => {
+                println!("skipping {}: {}", path.display(), why);
+                return None;
+            }
+        };
+        let file_stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        Some(Self {
+            path: path.to_path_buf(),
+            modified,
+            title: song.title().map(str::to_string).unwrap_or(file_stem),
+            composer: song.composer().unwrap_or_default().to_string(),
+            track_names: song
+                .tracks()
+                .iter()
+                .filter_map(|track| track.name.clone())
+                .collect(),
+            duration: song.duration() as f32 / 1_000_000.,
+            key: song
+                .key_signatures()
+                .first()
+                .map(|ks| ks.name())
+                .unwrap_or_default(),
+            notes: song.note_count(),
+            difficulty: Difficulty::of(&song),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        let track_names: Vec<String> = self.track_names.iter().map(|n| clean(n)).collect();
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.path.display(),
+            self.modified,
+            clean(&self.title),
+            clean(&self.composer),
+            self.duration,
+            self.key,
+            self.notes,
+            self.difficulty.to_field(),
+            track_names.join("|")
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        Some(Self {
+            path: PathBuf::from(fields.next()?),
+            modified: fields.next()?.parse().ok()?,
+            title: fields.next()?.to_string(),
+            composer: fields.next()?.to_string(),
+            duration: fields.next()?.parse().ok()?,
+            key: fields.next()?.to_string(),
+            notes: fields.next()?.parse().ok()?,
+            difficulty: Difficulty::from_field(fields.next()?)?,
+            track_names: fields
+                .next()?
+                .split('|')
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+
+    /// Whether every word of `query` is found in the title, the composer, a
+    /// track name or the file name, ignoring case.
+    pub fn matches(&self, query: &str) -> bool {
+        let haystack = format!(
+            "{} {} {} {}",
+            self.title,
+            self.composer,
+            self.track_names.join(" "),
+            self.path.display()
+        )
+        .to_lowercase();
+        query
+            .to_lowercase()
+            .split_whitespace()
+            .all(|word| haystack.contains(word))
+    }
+}
+
+/// Keeps free text from breaking the cache's tab separated lines.
+fn clean(text: &str) -> String {
+    text.replace(['\t', '\n', '\r', '|'], " ")
+}
+
+/// `<config dir>/library`, one directory to scan per line.
+fn dirs_file() -> PathBuf {
+    config::config_dir().join("library")
+}
+
+/// `<data dir>/library.tsv`, one song per line.
+fn cache_file() -> PathBuf {
+    config::data_dir().join("library.tsv")
+}
+
+/// The configured directories followed by `extra`.
+pub fn dirs(extra: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_to_string(dirs_file())
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect();
+    dirs.extend(extra.iter().cloned());
+    dirs
+}
+
+fn find_songs(dir: &Path, songs: &mut Vec<(PathBuf, u64)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        println!("could not read library directory {}", dir.display());
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            find_songs(&path, songs);
+            continue;
+        }
+        let is_song = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()));
+        if is_song {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            songs.push((path, modified));
+        }
+    }
+}
+
+fn load_cache() -> HashMap<PathBuf, SongInfo> {
+    fs::read_to_string(cache_file())
+        .unwrap_or_default()
+        .lines()
+        .filter_map(SongInfo::from_line)
+        .map(|info| (info.path.clone(), info))
+        .collect()
+}
+
+fn save_cache(songs: &[SongInfo]) -> io::Result<()> {
+    let content: String = songs.iter().map(SongInfo::to_line).collect();
+    fs::create_dir_all(config::data_dir())?;
+    fs::write(cache_file(), content)
+}
+
+/// Every song found under `dirs`, only reading the files the cache doesn't
+/// know about or that changed since.
+pub fn scan(dirs: &[PathBuf]) -> Vec<SongInfo> {
+    let mut found = vec![];
+    for dir in dirs {
+        find_songs(dir, &mut found);
+    }
+    found.sort();
+    found.dedup();
+
+    let mut cache = load_cache();
+    let songs: Vec<SongInfo> = found
+        .into_iter()
+        .filter_map(|(path, modified)| match cache.remove(&path) {
+            Some(info) if info.modified == modified => Some(info),
+            _ => SongInfo::read(&path, modified),
+        })
+        .collect();
+
+    if let Err(why) = save_cache(&songs) {
+        println!("could not save library cache: {}", why);
+    }
+    songs
+}
@@ -1,78 +1,540 @@
 use clap::Parser;
 use macroquad::prelude::*;
-use midir::{Ignore, MidiInput};
-use midix::prelude::{FromLiveEventBytes, Key, LiveEvent, Note, Octave, VoiceEvent};
+use midix::prelude::{Key, Note, Octave};
 use std::error::Error;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
+mod abc;
+mod audio;
+mod calibration;
+mod clock;
+mod config;
+mod difficulty;
+mod engine;
+mod export;
+mod fingering;
+mod grading;
+mod heatmap;
+mod history;
+mod keyboard;
+mod library;
+mod musicxml;
+mod notation;
+mod picker;
+mod player;
+mod ports;
+mod progress;
+mod recorder;
 mod screen;
+mod session;
+mod sheet;
+mod smf;
 mod song;
+mod soundfont;
+mod statistics;
+mod synth;
 mod utils;
+mod weakness;
 
 #[derive(Parser)]
 struct Cli {
-    midi_path: PathBuf,
-    #[arg(long = "midi-port")]
-    midi_port: String,
+    /// Song to open, a MIDI file, a MusicXML score or an ABC tune, the
+    /// library is shown to pick one from when left out
+    midi_path: Option<PathBuf>,
+    #[arg(long = "midi-port", required_unless_present_any = ["virtual_in", "replay", "render_wav", "export_midi", "export_musicxml", "export_lilypond"])]
+    midi_port: Option<String>,
+    /// Instead of connecting to a port, create a virtual MIDI input with this
+    /// name for a DAW, a sequencer or `aplaymidi` to play into
+    #[arg(long = "virtual-in", conflicts_with = "midi_port")]
+    virtual_in: Option<String>,
+    /// Create a virtual MIDI output with this name, carrying the song and
+    /// everything played, for other software to record from
+    #[arg(long = "virtual-out")]
+    virtual_out: Option<String>,
+    /// Play the song through this MIDI output port
+    #[arg(long = "midi-out-port")]
+    midi_out_port: Option<String>,
+    /// Forward everything played on the input port to the output port
+    #[arg(long = "midi-thru", requires = "midi_out_port")]
+    midi_thru: bool,
+    /// Voice the song and the played notes with the built-in synth
+    #[arg(long = "synth")]
+    synth: bool,
+    /// Voice the song and the played notes with an SF2 SoundFont instead of
+    /// the built-in synth
+    #[arg(long = "soundfont")]
+    soundfont: Option<PathBuf>,
+    /// Render the song to a WAV file and exit, uses the SoundFont if given
+    #[arg(long = "render-wav", requires = "midi_path")]
+    render_wav: Option<PathBuf>,
+    /// Write the song back out as a Standard MIDI File and exit
+    #[arg(long = "export-midi", requires = "midi_path")]
+    export_midi: Option<PathBuf>,
+    /// Write the song out as a MusicXML score and exit
+    #[arg(long = "export-musicxml", requires = "midi_path")]
+    export_musicxml: Option<PathBuf>,
+    /// Write the song out as LilyPond source and exit
+    #[arg(long = "export-lilypond", requires = "midi_path")]
+    export_lilypond: Option<PathBuf>,
+    /// Replay a session log recorded earlier instead of listening to MIDI
+    /// input
+    #[arg(long = "replay", requires = "midi_path")]
+    replay: Option<PathBuf>,
+    /// Run the replay without a window, as fast as possible, and print how
+    /// it went
+    #[arg(long = "headless", requires = "replay")]
+    headless: bool,
+    /// Directory recorded takes are saved to
+    #[arg(long = "record-dir", default_value = ".")]
+    record_dir: PathBuf,
+    /// Standard MIDI File type of recorded takes
+    #[arg(long = "record-smf-type", default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=1))]
+    record_smf_type: u8,
+    /// Directory to scan for songs, besides the ones listed in the `library`
+    /// file of the config directory
+    #[arg(long = "library-dir")]
+    library_dir: Vec<PathBuf>,
+    /// Number of keys on the instrument played: 25, 49, 61, 76 or 88
+    #[arg(long = "keys", default_value_t = 88, value_parser = parse_keyboard_size)]
+    keys: u32,
+    /// Draw only the keys the song uses instead of the whole instrument
+    #[arg(long = "fit-keyboard")]
+    fit_keyboard: bool,
+    /// Move the notes the instrument doesn't have by octaves until they fit
+    #[arg(long = "fold-octaves")]
+    fold_octaves: bool,
+    /// Label the notes and keys with letter, solfege or german names
+    #[arg(long = "labels", value_parser = parse_naming)]
+    labels: Option<notation::NoteNaming>,
+    /// Add octave numbers to the labels
+    #[arg(long = "label-octaves")]
+    label_octaves: bool,
 }
 
-#[macroquad::main("zborro-piano-trainer")]
-async fn main() {
+fn parse_naming(arg: &str) -> Result<notation::NoteNaming, String> {
+    notation::NoteNaming::from_name(arg)
+        .ok_or_else(|| "names are letter, solfege or german".to_string())
+}
+
+fn parse_keyboard_size(arg: &str) -> Result<u32, String> {
+    let sizes: Vec<String> = keyboard::KeyRange::sizes()
+        .map(|size| size.to_string())
+        .collect();
+    match arg.parse() {
+        Ok(keys) if keyboard::KeyRange::of_size(keys).is_some() => Ok(keys),
+        _ => Err(format!("keyboards have {} keys", sizes.join(", "))),
+    }
+}
+
+fn main() {
     let args = Cli::parse();
-    println!("{:?}", args.midi_path.to_str());
+    if let Some(midi_path) = &args.midi_path {
+        println!("{:?}", midi_path.to_str());
 
-    if !args.midi_path.exists() {
-        panic!("passed MIDI file does not exist!");
+        if !midi_path.exists() {
+            panic!("passed MIDI file does not exist!");
+        }
     }
 
     env_logger::init();
-    match run(args.midi_path, args.midi_port).await {
-        Ok(_) => (),
-        Err(why) => println!("Error: {}", why),
+
+    if let Some(wav_path) = &args.render_wav {
+        let playable = keyboard::KeyRange::of_size(args.keys).unwrap();
+        let song = match read_song(
+            args.midi_path.as_deref().unwrap(),
+            &playable,
+            args.fold_octaves,
+        ) {
+            Ok(song) => song,
+            Err(why) => {
+                println!("Error: {}", why);
+                return;
+            }
+        };
+        let sample_rate = 44_100;
+        let result = match &args.soundfont {
+            Some(soundfont_path) => soundfont::SoundFontSynth::load(soundfont_path, sample_rate)
+                .and_then(|sf| audio::render_song_to_wav(&song, sf, sample_rate, wav_path)),
+            None => {
+                let synth = synth::Synth::new(sample_rate);
+                audio::render_song_to_wav(&song, synth, sample_rate, wav_path)
+            }
+        };
+        if let Err(why) = result {
+            println!("Error: {}", why);
+        }
+        return;
     }
+
+    if args.export_midi.is_some()
+        || args.export_musicxml.is_some()
+        || args.export_lilypond.is_some()
+    {
+        let playable = keyboard::KeyRange::of_size(args.keys).unwrap();
+        let song = match read_song(
+            args.midi_path.as_deref().unwrap(),
+            &playable,
+            args.fold_octaves,
+        ) {
+            Ok(song) => song,
+            Err(why) => {
+                println!("Error: {}", why);
+                return;
+            }
+        };
+        let results = [
+            args.export_midi.as_ref().map(|path| song.write_midi(path)),
+            args.export_musicxml
+                .as_ref()
+                .map(|path| export::write_musicxml(&song, path)),
+            args.export_lilypond
+                .as_ref()
+                .map(|path| export::write_lilypond(&song, path)),
+        ];
+        for why in results.into_iter().flatten().filter_map(Result::err) {
+            println!("Error: {}", why);
+        }
+        return;
+    }
+
+    if args.headless {
+        if let Err(why) = replay_headless(&args) {
+            println!("Error: {}", why);
+        }
+        return;
+    }
+
+    let (logged_session, song) = match read_startup_song(&args) {
+        Ok(startup) => startup,
+        Err(why) => {
+            println!("Error: {}", why);
+            return;
+        }
+    };
+    // the window can't be retitled once it is open, so it names the song
+    // given at the start and the HUD the one playing
+    let window_title = match song.as_ref().and_then(|song| song.title()) {
+        Some(title) => format!("{} - zborro-piano-trainer", title),
+        None => "zborro-piano-trainer".to_string(),
+    };
+
+    macroquad::Window::new(&window_title, async move {
+        match run(args, logged_session, song).await {
+            Ok(_) => (),
+            Err(why) => println!("Error: {}", why),
+        }
+    });
 }
 
-async fn run(midi_path: PathBuf, midi_port: String) -> Result<(), Box<dyn Error>> {
-    let mut midi_in = MidiInput::new("midir reading input")?;
-    midi_in.ignore(Ignore::None);
+/// The session log to replay, if any, and the song given on the command
+/// line, read before the window opens for it to be titled after the song.
+fn read_startup_song(
+    args: &Cli,
+) -> Result<(Option<session::LoggedSession>, Option<song::Song>), Box<dyn Error>> {
+    let logged_session = match &args.replay {
+        Some(replay_path) => Some(session::read_log(replay_path)?),
+        None => None,
+    };
+    let settings = logged_session.as_ref().and_then(|s| s.settings.as_ref());
+    let (playable, fold_octaves) = keyboard_options(args, settings)?;
+    let song = match &args.midi_path {
+        Some(path) => Some(read_song(path, &playable, fold_octaves)?),
+        None => None,
+    };
+    Ok((logged_session, song))
+}
 
-    let mut last_screen_width = screen_width();
+/// Feeds a session log to the engine on a clock that jumps from one event
+/// to the next in frame sized steps, so the same log always ends the same.
+fn replay_headless(args: &Cli) -> Result<(), Box<dyn Error>> {
+    use clock::Clock;
 
-    let song = song::Song::load(midi_path.as_path());
-    let piano_screen_handle = scene::add_node(screen::PianoScreen::new(song));
-
-    let in_port = midi_in.find_port_by_id(midi_port).unwrap();
-
-    let _conn_in = midi_in.connect(
-        &in_port,
-        "midir-read-input",
-        move |stamp, message, _| {
-            println!("{}: {:?} (len = {})", stamp, message, message.len());
-
-            loop {
-                let node = scene::try_get_node(piano_screen_handle);
-                if let Some(mut node) = node {
-                    let parsed = LiveEvent::from_bytes(message).unwrap();
-                    if let LiveEvent::ChannelVoice(cv) = parsed {
-                        let event = cv.event();
-                        if let VoiceEvent::NoteOn { key, .. } = event {
-                            println!("detected live noteOn: {}/{}", key.octave(), key.note());
-                            node.on_piano_key_down(*key);
-                        } else if let VoiceEvent::NoteOff { key, .. } = event {
-                            println!("detected live noteOff: {}/{}", key.octave(), key.note());
-                            node.on_piano_key_up(*key);
-                        }
-                    }
+    let frame = std::time::Duration::from_micros(16_667);
 
-                    break;
-                } else {
-                    thread::sleep(std::time::Duration::from_millis(1));
-                }
+    let logged_session = session::read_log(args.replay.as_ref().unwrap())?;
+    let (playable, fold_octaves) = keyboard_options(args, logged_session.settings.as_ref())?;
+    let song = read_song(args.midi_path.as_deref().unwrap(), &playable, fold_octaves)?;
+    let song_duration = song.duration();
+    let clock = clock::ManualClock::new();
+    let mut engine = engine::Engine::new(song, Box::new(clock.clone()));
+    if let Some(device) = args.midi_port.as_ref().or(args.virtual_in.as_ref()) {
+        engine.set_input_device(device.clone());
+    }
+    if let Some(settings) = &logged_session.settings {
+        engine.apply(session::SessionEvent::Latency(settings.latency));
+    }
+
+    for logged in logged_session.events {
+        let due = std::time::Duration::from_micros(logged.time);
+        while clock.now() + frame < due {
+            clock.advance(frame);
+            engine.update();
+        }
+        clock.set(due);
+        engine.update();
+        engine.apply(logged.event);
+    }
+
+    // let whatever is still playing run out
+    while engine.is_playing() && ((engine.time_offset() * 1_000_000.) as u32) < song_duration {
+        let time_offset = engine.time_offset();
+        clock.advance(frame);
+        engine.update();
+        if engine.time_offset() == time_offset {
+            break;
+        }
+    }
+
+    println!("mode: {}", engine.mode().name());
+    println!("song time: {:.3}s", engine.time_offset());
+    println!("played notes: {}", engine.performance().len());
+
+    let grade = engine.grade();
+    for judgment in grade.judgments.iter() {
+        println!(
+            "{:.3}s {}{}: {}",
+            judgment.time,
+            judgment.key.note(),
+            judgment.key.octave(),
+            match judgment.verdict {
+                grading::Verdict::Hit(offset) => format!("hit {:+.0}ms", offset * 1_000.),
+                grading::Verdict::Miss => "miss".to_string(),
+                grading::Verdict::Wrong => "wrong".to_string(),
+            }
+        );
+    }
+    println!(
+        "accuracy: {:.0}% ({} hits, {} misses, {} wrong)",
+        grade.accuracy() * 100.,
+        grade.hits(),
+        grade.misses(),
+        grade.wrong()
+    );
+    for (measure, errors) in grade.measure_errors.iter() {
+        println!("measure {}: {} errors", measure, errors);
+    }
+
+    Ok(())
+}
+
+/// The keyboard and whether to fold octaves into it: a replay goes by the
+/// settings it was recorded with, when its log has them, and everything else
+/// by the command line.
+fn keyboard_options(
+    args: &Cli,
+    settings: Option<&session::SessionSettings>,
+) -> Result<(keyboard::KeyRange, bool), Box<dyn Error>> {
+    let (keys, fold_octaves) = match settings {
+        Some(settings) => (settings.keys, settings.fold_octaves),
+        None => (args.keys, args.fold_octaves),
+    };
+    let playable =
+        keyboard::KeyRange::of_size(keys).ok_or_else(|| format!("no {}-key keyboards", keys))?;
+    Ok((playable, fold_octaves))
+}
+
+/// Reads a song, folding the notes out of the instrument's reach into it if
+/// asked to.
+fn read_song(
+    path: &std::path::Path,
+    playable: &keyboard::KeyRange,
+    fold_octaves: bool,
+) -> Result<song::Song, Box<dyn Error>> {
+    let mut song = song::Song::read(path)?;
+    match fingering::load(&mut song, path) {
+        Ok(count) => println!("loaded {} fingers from the fingering file", count),
+        Err(why) if why.kind() == std::io::ErrorKind::NotFound => (),
+        Err(why) => println!("could not read the fingering file: {}", why),
+    }
+    if fold_octaves {
+        let folded = song.fold_into(playable);
+        if folded > 0 {
+            println!("folded {} notes into the keyboard's range", folded);
+        }
+    } else {
+        let outside = song.notes_outside(playable);
+        if outside > 0 {
+            println!(
+                "{} notes are outside the keyboard's range, --fold-octaves moves them in",
+                outside
+            );
+        }
+    }
+    fingering::suggest(&mut song);
+    Ok(song)
+}
+
+/// Appends the attempts practiced so far to the song's history, and their
+/// mistakes to the statistics kept across songs.
+fn save_practice(engine: &mut engine::Engine, song_path: &std::path::Path) {
+    let records = engine.take_practice_records();
+    if let Err(why) = history::append(song_path, &records) {
+        println!("could not save practice history: {}", why);
+    }
+    let weaknesses = engine.take_weaknesses();
+    if !weaknesses.is_empty()
+        && let Err(why) = weakness::save(&weaknesses)
+    {
+        println!("could not save weakness statistics: {}", why);
+    }
+}
+
+/// Logs the session with `song_path` to a new file in the data directory.
+fn start_session_log(
+    engine: &mut engine::Engine,
+    song_path: &std::path::Path,
+    playable: &keyboard::KeyRange,
+    fold_octaves: bool,
+) {
+    let session_log_path = session::new_log_path();
+    let settings = session::SessionSettings {
+        latency: engine.latency(),
+        keys: playable.size(),
+        fold_octaves,
+    };
+    match session::SessionLog::create(&session_log_path, song_path, &settings) {
+        Ok(session_log) => {
+            println!("logging session to {}", session_log_path.display());
+            engine.set_session_log(session_log);
+        }
+        Err(why) => println!(
+            "could not create session log {}: {}",
+            session_log_path.display(),
+            why
+        ),
+    }
+}
+
+/// Shows the library until a song is picked, before anything else is set
+/// up. Escape quits.
+async fn pick_first_song(library_dirs: &[PathBuf]) -> Option<PathBuf> {
+    let mut picker = picker::SongPicker::new(library::scan(library_dirs));
+    loop {
+        if is_key_pressed(KeyCode::Escape) {
+            return None;
+        }
+        if let Some(path) = picker.update() {
+            return Some(path);
+        }
+        picker.draw();
+        next_frame().await;
+    }
+}
+
+async fn run(
+    args: Cli,
+    logged_session: Option<session::LoggedSession>,
+    song: Option<song::Song>,
+) -> Result<(), Box<dyn Error>> {
+    let settings = logged_session.as_ref().and_then(|s| s.settings.clone());
+    let (playable, fold_octaves) = keyboard_options(&args, settings.as_ref())?;
+
+    let library_dirs = library::dirs(&args.library_dir);
+    let (mut song_path, song) = match (args.midi_path.clone(), song) {
+        (Some(path), Some(song)) => (path, song),
+        _ => match pick_first_song(&library_dirs).await {
+            Some(path) => {
+                let song = read_song(&path, &playable, fold_octaves)?;
+                (path, song)
             }
+            None => return Ok(()),
         },
-        (),
-    )?;
+    };
+
+    let mut last_screen_width = screen_width();
+    let mut last_screen_height = screen_height();
+
+    let mut engine = engine::Engine::new(song, Box::new(clock::SystemClock::new()));
+
+    let mut song_outputs: Vec<player::SharedMidiSink> = vec![];
+    let mut thru_outputs: Vec<player::SharedMidiSink> = vec![];
+
+    if let Some(midi_out_port) = args.midi_out_port {
+        let conn_out: player::SharedMidiSink =
+            Arc::new(Mutex::new(ports::connect_output(midi_out_port)?));
+        song_outputs.push(conn_out.clone());
+        if args.midi_thru {
+            thru_outputs.push(conn_out);
+        }
+    }
+
+    if let Some(virtual_out) = &args.virtual_out {
+        let conn_out: player::SharedMidiSink =
+            Arc::new(Mutex::new(ports::create_virtual_output(virtual_out)?));
+        song_outputs.push(conn_out.clone());
+        thru_outputs.push(conn_out);
+    }
+
+    let audio_output = match (&args.soundfont, args.synth) {
+        (Some(soundfont_path), _) => Some(audio::start(|sample_rate| {
+            soundfont::SoundFontSynth::load(soundfont_path, sample_rate)
+        })?),
+        (None, true) => Some(audio::start(|sample_rate| {
+            Ok(synth::Synth::new(sample_rate))
+        })?),
+        (None, false) => None,
+    };
+    if let Some(audio_output) = &audio_output {
+        song_outputs.push(audio_output.sink.clone());
+        thru_outputs.push(audio_output.sink.clone());
+    }
+
+    if !song_outputs.is_empty() {
+        engine.set_player(player::SongPlayer::new(song_outputs));
+    }
+    engine.set_recording(
+        args.record_dir,
+        match args.record_smf_type {
+            0 => smf::Format::SingleTrack,
+            _ => smf::Format::MultiTrack,
+        },
+    );
+    if let Some(device) = args.midi_port.as_ref().or(args.virtual_in.as_ref()) {
+        engine.set_input_device(device.clone());
+    }
+
+    if let Some(settings) = &settings {
+        engine.apply(session::SessionEvent::Latency(settings.latency));
+    }
+
+    let mut replay =
+        logged_session.map(|logged_session| session::Replay::new(logged_session.events));
+    if replay.is_none() {
+        start_session_log(&mut engine, &song_path, &playable, fold_octaves);
+    }
+
+    let mut piano_screen = screen::PianoScreen::new(engine);
+    piano_screen.set_keyboard(playable, args.fit_keyboard);
+    piano_screen.set_labels(args.labels, args.label_octaves);
+    let piano_screen_handle = scene::add_node(piano_screen);
+
+    // replays don't go into the practice history
+    let practicing = replay.is_none();
+
+    let replay_thru_outputs = thru_outputs.clone();
+    let _conn_in = if replay.is_none() {
+        Some(ports::connect_input(
+            args.midi_port,
+            args.virtual_in,
+            move |stamp, message, _| {
+                player::send(&thru_outputs, message);
+
+                loop {
+                    let node = scene::try_get_node(piano_screen_handle);
+                    if let Some(mut node) = node {
+                        node.apply(session::SessionEvent::Midi(stamp, message.to_vec()));
+                        break;
+                    } else {
+                        thread::sleep(std::time::Duration::from_millis(1));
+                    }
+                }
+            },
+        )?)
+    } else {
+        None
+    };
 
     let mut camera =
         Camera2D::from_display_rect(Rect::new(0., 0., screen_width(), screen_height()));
@@ -83,13 +545,70 @@ async fn run(midi_path: PathBuf, midi_port: String) -> Result<(), Box<dyn Error>
     let mut mode_selection_mode = false;
 
     loop {
+        if let Some(replay) = replay.as_mut() {
+            for event in replay.due_events() {
+                if let session::SessionEvent::Midi(_, message) = &event {
+                    player::send(&replay_thru_outputs, message);
+                }
+                scene::get_node(piano_screen_handle).apply(event);
+            }
+        }
+
+        if scene::get_node(piano_screen_handle).is_calibrating() {
+            if is_key_pressed(KeyCode::Enter) {
+                scene::get_node(piano_screen_handle).finish_calibration(true);
+            } else if is_key_pressed(KeyCode::Escape) {
+                scene::get_node(piano_screen_handle).finish_calibration(false);
+            }
+            next_frame().await;
+            continue;
+        }
+
+        if scene::get_node(piano_screen_handle).is_picking() {
+            let mut node = scene::get_node(piano_screen_handle);
+            if is_key_pressed(KeyCode::Escape) {
+                node.hide_picker();
+            } else if let Some(path) = node.update_picker() {
+                match read_song(&path, &playable, fold_octaves) {
+                    Ok(song) => {
+                        save_practice(node.engine(), &song_path);
+                        node.hide_picker();
+                        node.load_song(song);
+                        song_path = path;
+                        start_session_log(node.engine(), &song_path, &playable, fold_octaves);
+                    }
+                    Err(why) => println!("could not load {}: {}", path.display(), why),
+                }
+            }
+            next_frame().await;
+            continue;
+        }
+
+        if scene::get_node(piano_screen_handle).is_showing_progress() {
+            if is_key_pressed(KeyCode::H) || is_key_pressed(KeyCode::Escape) {
+                scene::get_node(piano_screen_handle).hide_progress();
+            }
+            next_frame().await;
+            continue;
+        }
+
+        if scene::get_node(piano_screen_handle).is_showing_statistics() {
+            if is_key_pressed(KeyCode::S) || is_key_pressed(KeyCode::Escape) {
+                scene::get_node(piano_screen_handle).hide_statistics();
+            }
+            next_frame().await;
+            continue;
+        }
+
         if mode_selection_mode {
             if is_key_pressed(KeyCode::P) {
-                scene::get_node(piano_screen_handle).set_mode(screen::GameMode::Play);
+                scene::get_node(piano_screen_handle)
+                    .apply(session::SessionEvent::Mode(engine::GameMode::Play));
                 mode_selection_mode = false;
             }
             if is_key_pressed(KeyCode::B) {
-                scene::get_node(piano_screen_handle).set_mode(screen::GameMode::LearnBlocking);
+                scene::get_node(piano_screen_handle)
+                    .apply(session::SessionEvent::Mode(engine::GameMode::LearnBlocking));
                 mode_selection_mode = false;
             }
         }
@@ -106,17 +625,122 @@ async fn run(midi_path: PathBuf, midi_port: String) -> Result<(), Box<dyn Error>
         } else if is_key_pressed(KeyCode::Equal) && is_shift_key_down {
             scene::get_node(piano_screen_handle).zoom_in();
         } else if is_key_pressed(KeyCode::R) {
-            scene::get_node(piano_screen_handle).reset();
+            scene::get_node(piano_screen_handle).apply(session::SessionEvent::Reset);
         } else if is_key_pressed(KeyCode::Right) {
-            scene::get_node(piano_screen_handle).skip_blocks(1);
+            scene::get_node(piano_screen_handle).apply(session::SessionEvent::Seek(1));
         } else if is_key_pressed(KeyCode::Left) {
-            scene::get_node(piano_screen_handle).skip_blocks(-1);
+            scene::get_node(piano_screen_handle).apply(session::SessionEvent::Seek(-1));
+        } else if is_key_pressed(KeyCode::Up) || is_key_pressed(KeyCode::Down) {
+            let mut node = scene::get_node(piano_screen_handle);
+            let step = if is_key_pressed(KeyCode::Up) {
+                0.05
+            } else {
+                -0.05
+            };
+            let tempo = node.engine().tempo() + step;
+            node.apply(session::SessionEvent::Tempo(tempo));
         }
 
         if is_key_pressed(KeyCode::M) {
             mode_selection_mode = true;
         }
 
+        if is_key_pressed(KeyCode::F5) {
+            scene::get_node(piano_screen_handle)
+                .engine()
+                .toggle_recording();
+        }
+
+        if is_key_pressed(KeyCode::H) {
+            let mut node = scene::get_node(piano_screen_handle);
+            if practicing {
+                save_practice(node.engine(), &song_path);
+            }
+            node.show_progress(history::load(&song_path));
+        }
+
+        if is_key_pressed(KeyCode::S) {
+            let mut node = scene::get_node(piano_screen_handle);
+            if practicing {
+                save_practice(node.engine(), &song_path);
+            }
+            node.show_statistics(weakness::load());
+        }
+
+        if is_key_pressed(KeyCode::G) {
+            let mut node = scene::get_node(piano_screen_handle);
+            if node.is_showing_heatmap() {
+                node.hide_heatmap();
+            } else {
+                if practicing {
+                    save_practice(node.engine(), &song_path);
+                }
+                node.show_heatmap(history::load(&song_path));
+            }
+        }
+
+        if is_mouse_button_pressed(MouseButton::Left) {
+            let (x, y) = mouse_position();
+            scene::get_node(piano_screen_handle).on_click(x, y);
+        }
+
+        // a replay belongs to the song it was recorded with
+        if practicing && is_key_pressed(KeyCode::L) {
+            scene::get_node(piano_screen_handle).show_picker(library::scan(&library_dirs));
+        }
+
+        if is_key_pressed(KeyCode::O) {
+            scene::get_node(piano_screen_handle).toggle_orientation();
+        }
+
+        if is_key_pressed(KeyCode::N) {
+            let mut node = scene::get_node(piano_screen_handle);
+            if is_shift_key_down {
+                node.toggle_label_octaves();
+            } else {
+                node.cycle_labels();
+            }
+        }
+
+        if is_key_pressed(KeyCode::F) {
+            scene::get_node(piano_screen_handle).toggle_fingers();
+        }
+
+        if is_key_pressed(KeyCode::V) {
+            scene::get_node(piano_screen_handle).toggle_sheet();
+        }
+
+        if is_key_pressed(KeyCode::K) {
+            scene::get_node(piano_screen_handle).start_calibration();
+        }
+
+        if let Some(player) = scene::get_node(piano_screen_handle).engine().player_mut() {
+            let channel_keys = [KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4];
+            for (channel, key_code) in channel_keys.into_iter().enumerate() {
+                if is_key_pressed(key_code) {
+                    if is_shift_key_down {
+                        player.toggle_channel_solo(channel as u32);
+                    } else {
+                        player.toggle_channel_mute(channel as u32);
+                    }
+                }
+            }
+
+            let hand_keys = [
+                (KeyCode::LeftBracket, song::Hand::Left),
+                (KeyCode::RightBracket, song::Hand::Right),
+            ];
+            for (key_code, hand) in hand_keys {
+                if is_key_pressed(key_code) {
+                    if is_shift_key_down {
+                        player.toggle_hand_solo(hand);
+                    } else {
+                        player.toggle_hand_mute(hand);
+                    }
+                }
+            }
+        }
+
         if fake_piano_key_down > 0 {
             fake_piano_key_down += 1;
         }
@@ -130,7 +754,8 @@ async fn run(midi_path: PathBuf, midi_port: String) -> Result<(), Box<dyn Error>
             fake_piano_key_down = 1;
         }
 
-        if screen_width() != last_screen_width {
+        // the horizontal layout sizes the keys from the height
+        if screen_width() != last_screen_width || screen_height() != last_screen_height {
             scene::get_node(piano_screen_handle).on_screen_resize();
 
             camera =
@@ -138,11 +763,16 @@ async fn run(midi_path: PathBuf, midi_port: String) -> Result<(), Box<dyn Error>
             scene::set_camera(0, Some(camera));
 
             last_screen_width = screen_width();
+            last_screen_height = screen_height();
         }
 
         next_frame().await
     }
 
+    if practicing {
+        save_practice(scene::get_node(piano_screen_handle).engine(), &song_path);
+    }
+
     scene::clear();
 
     Ok(())
@@ -0,0 +1,554 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use midix::prelude::*;
+use roxmltree::{Document, Node, ParsingOptions};
+
+use crate::song::{
+    Hand, KeySignatureChange, NoteBlock, Song, SongParts, TempoChange, TimeSignatureChange, Track,
+    odd_measure_signature,
+};
+
+/// Ticks per quarter note of imported songs, divisible by the usual
+/// MusicXML divisions.
+const TICKS_PER_QUARTER_NOTE: u32 = 480;
+const DEFAULT_VELOCITY: u8 = 80;
+/// MIDI's percussion channel, which parts don't get.
+const DRUM_CHANNEL: u32 = 9;
+
+pub fn is_musicxml(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| ["musicxml", "mxl", "xml"].contains(&e.to_lowercase().as_str()))
+}
+
+/// The score's XML, out of the zip archive for compressed `.mxl` files.
+fn read_xml(path: &Path) -> Result<String, Box<dyn Error>> {
+    let display = path.display();
+    let compressed = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("mxl"));
+    if !compressed {
+        return Ok(fs::read_to_string(path)
+            .map_err(|why| format!("could not read {}: {}", display, why))?);
+    }
+
+    let file = File::open(path).map_err(|why| format!("could not open {}: {}", display, why))?;
+    let mut archive = zip::ZipArchive::new(file)
+        .map_err(|why| format!("could not unzip {}: {}", display, why))?;
+
+    // the container names the score, otherwise it is the first XML file
+    // outside META-INF
+    let mut container = String::new();
+    let root = match archive.by_name("META-INF/container.xml") {
+        Ok(mut entry) => {
+            entry.read_to_string(&mut container)?;
+            let doc = Document::parse(&container)?;
+            doc.descendants()
+                .find(|n| n.has_tag_name("rootfile"))
+                .and_then(|n| n.attribute("full-path"))
+                .map(str::to_string)
+        }
+        Err(_) => None,
+    };
+    let root = root
+        .or_else(|| {
+            archive
+                .file_names()
+                .find(|name| !name.starts_with("META-INF") && name.ends_with(".xml"))
+                .map(str::to_string)
+        })
+        .ok_or_else(|| format!("no score in {}", display))?;
+
+    let mut xml = String::new();
+    archive.by_name(&root)?.read_to_string(&mut xml)?;
+    Ok(xml)
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|n| n.text()).map(str::trim)
+}
+
+fn child_number<T: std::str::FromStr>(node: Node, name: &str) -> Option<T> {
+    child_text(node, name).and_then(|text| text.parse().ok())
+}
+
+/// MIDI key of a `<pitch>`.
+fn key_of(pitch: Node) -> Option<u8> {
+    let step = match child_text(pitch, "step")? {
+        "C" => 0,
+        "D" => 2,
+        "E" => 4,
+        "F" => 5,
+        "G" => 7,
+        "A" => 9,
+        "B" => 11,
+        _ => return None,
+    };
+    let alter = child_number::<f32>(pitch, "alter").unwrap_or(0.).round() as i32;
+    let octave: i32 = child_number(pitch, "octave")?;
+    u8::try_from((octave + 1) * 12 + step + alter)
+        .ok()
+        .filter(|key| *key < 128)
+}
+
+/// The first finger of a note's fingering, "2-1" being a change of fingers
+/// on a held note.
+fn finger_of(note: Node) -> Option<u8> {
+    note.descendants()
+        .filter(|n| n.has_tag_name("fingering"))
+        .filter_map(|n| n.text())
+        .filter_map(|text| text.trim().chars().next()?.to_digit(10))
+        .map(|finger| finger as u8)
+        .find(|finger| (1..=5).contains(finger))
+}
+
+/// Right hand for the top staff of a piano part, left for the bottom one.
+/// A part with a single staff goes by its clef.
+fn hand_of(staff: u32, staves: u32, clefs: &HashMap<u32, String>) -> Hand {
+    if staves > 1 {
+        return if staff == 1 { Hand::Right } else { Hand::Left };
+    }
+    match clefs.get(&staff).map(String::as_str) {
+        Some("F") => Hand::Left,
+        _ => Hand::Right,
+    }
+}
+
+/// Reads a MusicXML score, plain or compressed, into a song. Staves become
+/// hands and every staff gets a channel of its own; time, key and tempo come
+/// from the first part.
+pub fn read(path: &Path) -> Result<Song, Box<dyn Error>> {
+    let xml = read_xml(path)?;
+    parse(&xml, &path.display().to_string())
+}
+
+/// A song out of a score's XML, `display` naming it in errors.
+fn parse(xml: &str, display: &str) -> Result<Song, Box<dyn Error>> {
+    // scores written by notation software come with a DOCTYPE
+    let options = ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    let doc = Document::parse_with_options(xml, options)
+        .map_err(|why| format!("could not parse {}: {}", display, why))?;
+    let score = doc.root_element();
+    if !score.has_tag_name("score-partwise") {
+        return Err(format!("{} is not a partwise MusicXML score", display).into());
+    }
+
+    let mut parts = SongParts {
+        ticks_per_quarter_note: TICKS_PER_QUARTER_NOTE as u16,
+        ..Default::default()
+    };
+    parts.title = child(score, "work")
+        .and_then(|work| child_text(work, "work-title"))
+        .or_else(|| child_text(score, "movement-title"))
+        .filter(|title| !title.is_empty())
+        .map(str::to_string);
+    if let Some(identification) = child(score, "identification") {
+        parts.composer = identification
+            .children()
+            .find(|n| n.has_tag_name("creator") && n.attribute("type") == Some("composer"))
+            .and_then(|n| n.text())
+            .map(|text| text.trim().to_string());
+        parts.copyright = child_text(identification, "rights").map(str::to_string);
+    }
+
+    // name and instrument of every part
+    let part_list: HashMap<&str, Track> = child(score, "part-list")
+        .into_iter()
+        .flat_map(|list| list.children().filter(|n| n.has_tag_name("score-part")))
+        .filter_map(|part| {
+            let track = Track {
+                name: child_text(part, "part-name").map(str::to_string),
+                instrument: child(part, "score-instrument")
+                    .and_then(|instrument| child_text(instrument, "instrument-name"))
+                    .map(str::to_string),
+                notes: 0,
+            };
+            Some((part.attribute("id")?, track))
+        })
+        .collect();
+
+    // by tick, so parts repeating them don't add them again
+    let mut tempos: BTreeMap<u32, u32> = BTreeMap::new();
+    let mut signatures: BTreeMap<u32, (u8, u8)> = BTreeMap::new();
+    let mut keys: BTreeMap<u32, (i8, bool)> = BTreeMap::new();
+    let mut channels: HashMap<(usize, u32), u32> = HashMap::new();
+
+    for (part_index, part) in score
+        .children()
+        .filter(|n| n.has_tag_name("part"))
+        .enumerate()
+    {
+        let first_part = part_index == 0;
+        let mut track = part
+            .attribute("id")
+            .and_then(|id| part_list.get(id))
+            .cloned()
+            .unwrap_or_default();
+
+        let mut divisions = 1;
+        let mut staves = 1;
+        let mut clefs: HashMap<u32, String> = HashMap::new();
+        let mut signature = (4, 4);
+        let mut measure_start = 0;
+        let mut expected_number = 1;
+        // notes waiting for the rest of their tie, by staff and key
+        let mut tied: HashMap<(u32, u8), usize> = HashMap::new();
+
+        for measure in part.children().filter(|n| n.has_tag_name("measure")) {
+            if first_part {
+                let number = measure
+                    .attribute("number")
+                    .map(|n| {
+                        n.chars()
+                            .take_while(char::is_ascii_digit)
+                            .collect::<String>()
+                    })
+                    .and_then(|n| n.parse::<u32>().ok());
+                if let Some(number) = number {
+                    if number != expected_number {
+                        parts.measure_numbers.push((measure_start, number));
+                    }
+                    expected_number = number + 1;
+                } else {
+                    expected_number += 1;
+                }
+            }
+
+            let mut position = measure_start;
+            let mut measure_end = measure_start;
+            let mut last_start = measure_start;
+            let ticks = |duration: u32, divisions: u32| {
+                duration * TICKS_PER_QUARTER_NOTE / divisions.max(1)
+            };
+
+            for element in measure.children().filter(|n| n.is_element()) {
+                match element.tag_name().name() {
+                    "attributes" => {
+                        if let Some(d) = child_number(element, "divisions") {
+                            divisions = d;
+                        }
+                        if let Some(s) = child_number(element, "staves") {
+                            staves = s;
+                        }
+                        for clef in element.children().filter(|n| n.has_tag_name("clef")) {
+                            let staff = clef.attribute("number").and_then(|n| n.parse().ok());
+                            if let Some(sign) = child_text(clef, "sign") {
+                                clefs.insert(staff.unwrap_or(1), sign.to_string());
+                            }
+                        }
+                        if let Some(time) = child(element, "time")
+                            && let (Some(beats), Some(beat_type)) = (
+                                child_text(time, "beats")
+                                    .and_then(|b| b.split('+').map(|b| b.parse::<u8>().ok()).sum()),
+                                child_number(time, "beat-type"),
+                            )
+                        {
+                            signature = (beats, beat_type);
+                            if first_part {
+                                signatures.insert(position, signature);
+                            }
+                        }
+                        if first_part && let Some(key) = child(element, "key") {
+                            let fifths = child_number(key, "fifths").unwrap_or(0);
+                            let minor = child_text(key, "mode") == Some("minor");
+                            keys.insert(position, (fifths, minor));
+                        }
+                    }
+                    "direction" | "sound" => {
+                        let sound = if element.has_tag_name("sound") {
+                            Some(element)
+                        } else {
+                            child(element, "sound")
+                        };
+                        if first_part
+                            && let Some(tempo) = sound
+                                .and_then(|s| s.attribute("tempo"))
+                                .and_then(|t| t.parse::<f32>().ok())
+                                .filter(|t| *t > 0.)
+                        {
+                            tempos.insert(position, (60_000_000. / tempo) as u32);
+                        }
+                    }
+                    "backup" => {
+                        let duration = child_number(element, "duration").unwrap_or(0);
+                        position = position.saturating_sub(ticks(duration, divisions));
+                    }
+                    "forward" => {
+                        position +=
+                            ticks(child_number(element, "duration").unwrap_or(0), divisions);
+                        measure_end = measure_end.max(position);
+                    }
+                    "note" => {
+                        // grace notes take no time of their own
+                        if child(element, "grace").is_some() {
+                            continue;
+                        }
+                        let duration =
+                            ticks(child_number(element, "duration").unwrap_or(0), divisions);
+                        let start = if child(element, "chord").is_some() {
+                            last_start
+                        } else {
+                            last_start = position;
+                            position += duration;
+                            measure_end = measure_end.max(position);
+                            last_start
+                        };
+
+                        let Some(key) = child(element, "pitch").and_then(key_of) else {
+                            continue;
+                        };
+                        let staff = child_number(element, "staff").unwrap_or(1);
+                        let ties: Vec<&str> = element
+                            .children()
+                            .filter(|n| n.has_tag_name("tie"))
+                            .filter_map(|n| n.attribute("type"))
+                            .collect();
+                        let stop = start + duration;
+
+                        if ties.contains(&"stop")
+                            && let Some(index) = tied.remove(&(staff, key))
+                        {
+                            parts.note_blocks[index].stop_delta = Some(stop);
+                            if ties.contains(&"start") {
+                                tied.insert((staff, key), index);
+                            }
+                            continue;
+                        }
+
+                        let next_channel = channels.len() as u32;
+                        let channel = *channels.entry((part_index, staff)).or_insert(
+                            if next_channel >= DRUM_CHANNEL {
+                                (next_channel + 1).min(15)
+                            } else {
+                                next_channel
+                            },
+                        );
+                        let velocity = element
+                            .attribute("dynamics")
+                            .and_then(|d| d.parse::<f32>().ok())
+                            .map_or(DEFAULT_VELOCITY, |d| (d * 0.9).clamp(1., 127.) as u8);
+                        let key = Key::from_databyte(key)?;
+                        if ties.contains(&"start") {
+                            tied.insert((staff, key.byte()), parts.note_blocks.len());
+                        }
+                        track.notes += 1;
+                        parts.note_blocks.push(NoteBlock {
+                            octave: key.octave(),
+                            note: key.note(),
+                            key,
+                            velocity,
+                            hand: hand_of(staff, staves, &clefs),
+                            start_delta: start,
+                            stop_delta: Some(stop),
+                            start_time: 0,
+                            stop_time: None,
+                            channel_number: channel,
+                            finger: finger_of(element),
+                        });
+                    }
+                    _ => (),
+                }
+            }
+
+            // a pickup or otherwise short measure gets a time signature of
+            // its own, for the measures after it to line up
+            let length = measure_end - measure_start;
+            if first_part
+                && let Some(odd) = odd_measure_signature(length, TICKS_PER_QUARTER_NOTE, signature)
+            {
+                signatures.insert(measure_start, odd);
+                signatures
+                    .entry(measure_start + length)
+                    .or_insert(signature);
+            }
+            measure_start += if length > 0 {
+                length
+            } else {
+                4 * TICKS_PER_QUARTER_NOTE * signature.0 as u32 / signature.1.max(1) as u32
+            };
+        }
+        if track.notes > 0 {
+            parts.tracks.push(track);
+        }
+    }
+
+    parts.tempo_changes = tempos
+        .into_iter()
+        .map(|(delta, micros_per_quarter_note)| TempoChange {
+            delta,
+            time: 0,
+            micros_per_quarter_note,
+        })
+        .collect();
+    parts.time_signatures = signatures
+        .into_iter()
+        .map(|(delta, (numerator, denominator))| TimeSignatureChange {
+            delta,
+            time: 0,
+            numerator,
+            denominator,
+            clocks_per_click: 24,
+            notated_32nds_per_quarter: 8,
+        })
+        .collect();
+    parts.key_signatures = keys
+        .into_iter()
+        .map(|(delta, (sharps, minor))| KeySignatureChange {
+            delta,
+            time: 0,
+            sharps,
+            minor,
+        })
+        .collect();
+
+    Ok(Song::from_parts(parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A one part piano score of `measures`, read back as a song.
+    fn score(measures: &str) -> Song {
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 4.0 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd">
+<score-partwise version="4.0">
+  <part-list><score-part id="P1"><part-name>Piano</part-name></score-part></part-list>
+  <part id="P1">{}</part>
+</score-partwise>"#,
+            measures
+        );
+        parse(&xml, "test").unwrap()
+    }
+
+    /// Eighths as divisions, in 4/4 on a grand staff.
+    const ATTRIBUTES: &str = "<attributes><divisions>2</divisions><time><beats>4</beats><beat-type>4</beat-type></time><staves>2</staves></attributes>";
+
+    /// Key, start and stop in ticks, hand and finger of every note.
+    type Notes = Vec<(u8, u32, Option<u32>, Hand, Option<u8>)>;
+
+    fn notes(song: &Song) -> Notes {
+        let mut notes: Notes = song
+            .note_blocks()
+            .map(|b| (b.key.byte(), b.start_delta, b.stop_delta, b.hand, b.finger))
+            .collect();
+        notes.sort_by_key(|(key, start, ..)| (*start, *key));
+        notes
+    }
+
+    #[test]
+    fn staves_split_the_hands_with_backup_forward_and_chords() {
+        let song = score(&format!(
+            r#"<measure number="1">{}
+  <note><pitch><step>C</step><octave>4</octave></pitch><duration>4</duration><staff>1</staff><notations><technical><fingering>1</fingering></technical></notations></note>
+  <note><chord/><pitch><step>E</step><octave>4</octave></pitch><duration>4</duration><staff>1</staff></note>
+  <forward><duration>2</duration></forward>
+  <note><pitch><step>F</step><alter>1</alter><octave>4</octave></pitch><duration>2</duration><staff>1</staff></note>
+  <backup><duration>8</duration></backup>
+  <note><pitch><step>C</step><octave>3</octave></pitch><duration>8</duration><staff>2</staff></note>
+</measure>"#,
+            ATTRIBUTES
+        ));
+
+        assert_eq!(
+            notes(&song),
+            vec![
+                (48, 0, Some(1920), Hand::Left, None),
+                (60, 0, Some(960), Hand::Right, Some(1)),
+                (64, 0, Some(960), Hand::Right, None),
+                (66, 1440, Some(1920), Hand::Right, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_join_notes_over_the_bar_line() {
+        let song = score(&format!(
+            r#"<measure number="1">{}
+  <note><rest/><duration>4</duration><staff>1</staff></note>
+  <note><pitch><step>G</step><octave>4</octave></pitch><duration>4</duration><tie type="start"/><staff>1</staff></note>
+</measure>
+<measure number="2">
+  <note><pitch><step>G</step><octave>4</octave></pitch><duration>2</duration><tie type="stop"/><staff>1</staff></note>
+  <note><pitch><step>A</step><octave>4</octave></pitch><duration>6</duration><staff>1</staff></note>
+</measure>"#,
+            ATTRIBUTES
+        ));
+
+        assert_eq!(
+            notes(&song),
+            vec![
+                (67, 960, Some(2400), Hand::Right, None),
+                (69, 2400, Some(3840), Hand::Right, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn sound_tempo_changes_the_tempo_where_it_stands() {
+        let song = score(&format!(
+            r#"<measure number="1">{}
+  <direction><direction-type><metronome><beat-unit>quarter</beat-unit><per-minute>60</per-minute></metronome></direction-type><sound tempo="60"/></direction>
+  <note><pitch><step>C</step><octave>4</octave></pitch><duration>4</duration><staff>1</staff></note>
+  <sound tempo="120"/>
+  <note><pitch><step>D</step><octave>4</octave></pitch><duration>4</duration><staff>1</staff></note>
+</measure>"#,
+            ATTRIBUTES
+        ));
+
+        let tempos: Vec<_> = song
+            .tempo_changes()
+            .iter()
+            .map(|tc| (tc.delta, tc.micros_per_quarter_note))
+            .collect();
+        assert_eq!(tempos, vec![(0, 1_000_000), (960, 500_000)]);
+        let times: Vec<_> = song.note_blocks().map(|b| b.start_time).collect();
+        assert_eq!(times, vec![0, 2_000_000]);
+    }
+
+    #[test]
+    fn a_pickup_gets_a_measure_of_its_own() {
+        let song = score(&format!(
+            r#"<measure number="0">{}
+  <note><pitch><step>G</step><octave>4</octave></pitch><duration>2</duration><staff>1</staff></note>
+</measure>
+<measure number="1">
+  <note><pitch><step>C</step><octave>5</octave></pitch><duration>8</duration><staff>1</staff></note>
+</measure>"#,
+            ATTRIBUTES
+        ));
+
+        let measures: Vec<_> = song
+            .measures()
+            .iter()
+            .map(|m| (m.number, m.delta, m.numerator, m.denominator))
+            .collect();
+        assert_eq!(measures[..2], [(0, 0, 1, 4), (1, 480, 4, 4)]);
+        assert_eq!(
+            notes(&song),
+            vec![
+                (67, 0, Some(480), Hand::Right, None),
+                (72, 480, Some(2400), Hand::Right, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn bare_xml_files_are_musicxml() {
+        assert!(is_musicxml(Path::new("song.xml")));
+        assert!(is_musicxml(Path::new("song.MXL")));
+        assert!(!is_musicxml(Path::new("song.mid")));
+    }
+}
@@ -0,0 +1,563 @@
+use crate::song::{Hand, Song};
+
+/// Letters of the white keys, by pitch class.
+const LETTER_OF_WHITE: [Option<u8>; 12] = [
+    Some(0),
+    None,
+    Some(1),
+    None,
+    Some(2),
+    Some(3),
+    None,
+    Some(4),
+    None,
+    Some(5),
+    None,
+    Some(6),
+];
+/// Letters sharpened by a key signature, in the order the sharps are added.
+const SHARP_ORDER: [u8; 7] = [3, 0, 4, 1, 5, 2, 6];
+/// Letters flattened by a key signature, in the order the flats are added.
+const FLAT_ORDER: [u8; 7] = [6, 2, 5, 1, 4, 0, 3];
+/// Notes are quantized to sixteenths.
+const GRID_PER_QUARTER: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Staff {
+    Treble,
+    Bass,
+}
+
+impl Staff {
+    fn of(hand: Hand) -> Self {
+        match hand {
+            Hand::Right => Staff::Treble,
+            Hand::Left => Staff::Bass,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Accidental {
+    Sharp,
+    Flat,
+    Natural,
+}
+
+impl Accidental {
+    fn of(alteration: i8) -> Self {
+        match alteration {
+            1 => Accidental::Sharp,
+            -1 => Accidental::Flat,
+            _ => Accidental::Natural,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteValue {
+    Whole,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+}
+
+impl NoteValue {
+    fn sixteenths(&self) -> u32 {
+        match self {
+            NoteValue::Whole => 16,
+            NoteValue::Half => 8,
+            NoteValue::Quarter => 4,
+            NoteValue::Eighth => 2,
+            NoteValue::Sixteenth => 1,
+        }
+    }
+
+    pub fn sixteenths_dotted(&self, dotted: bool) -> u32 {
+        if dotted {
+            self.sixteenths() * 3 / 2
+        } else {
+            self.sixteenths()
+        }
+    }
+
+    /// The longest value, dotted or not, that fits in `sixteenths`.
+    fn fitting(sixteenths: u32) -> (Self, bool) {
+        for value in [
+            NoteValue::Whole,
+            NoteValue::Half,
+            NoteValue::Quarter,
+            NoteValue::Eighth,
+        ] {
+            let length = value.sixteenths();
+            if sixteenths >= length * 3 / 2 && value != NoteValue::Whole {
+                return (value, true);
+            }
+            if sixteenths >= length {
+                return (value, false);
+            }
+        }
+        (NoteValue::Sixteenth, false)
+    }
+
+    /// Values, longest first, adding up to `sixteenths`, to be tied
+    /// together.
+    pub fn split(mut sixteenths: u32) -> Vec<(Self, bool)> {
+        let mut values = vec![];
+        while sixteenths > 0 {
+            let (value, dotted) = Self::fitting(sixteenths);
+            sixteenths -= value.sixteenths_dotted(dotted);
+            values.push((value, dotted));
+        }
+        values
+    }
+
+    pub fn is_filled(&self) -> bool {
+        !matches!(self, NoteValue::Whole | NoteValue::Half)
+    }
+
+    pub fn has_stem(&self) -> bool {
+        *self != NoteValue::Whole
+    }
+
+    /// Flags, or beams when beamed.
+    pub fn flags(&self) -> u32 {
+        match self {
+            NoteValue::Eighth => 1,
+            NoteValue::Sixteenth => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// A letter from 0 (C) to 6 (B) and an alteration from -1 (flat) to 1
+/// (sharp), spelling `key` the way a key with `sharps` sharps (flats when
+/// negative) would.
+pub fn spell(key: u8, sharps: i8) -> (u8, i8) {
+    let pitch_class = key % 12;
+    match LETTER_OF_WHITE[pitch_class as usize] {
+        Some(letter) => (letter, 0),
+        None if sharps >= 0 => (LETTER_OF_WHITE[pitch_class as usize - 1].unwrap(), 1),
+        None => (LETTER_OF_WHITE[pitch_class as usize + 1].unwrap(), -1),
+    }
+}
+
+/// Ways of naming notes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteNaming {
+    /// C D E F G A B
+    Letter,
+    /// Do Re Mi Fa Sol La Si
+    Solfege,
+    /// C D E F G A H, with B for B flat
+    German,
+}
+
+impl NoteNaming {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NoteNaming::Letter => "letter",
+            NoteNaming::Solfege => "solfege",
+            NoteNaming::German => "german",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [NoteNaming::Letter, NoteNaming::Solfege, NoteNaming::German]
+            .into_iter()
+            .find(|naming| naming.name() == name)
+    }
+
+    /// `key` spelled the way a key with `sharps` sharps (flats when negative)
+    /// would, with its octave number if `with_octave`.
+    pub fn label(&self, key: u8, sharps: i8, with_octave: bool) -> String {
+        const LETTERS: [&str; 7] = ["C", "D", "E", "F", "G", "A", "B"];
+        const SOLFEGE: [&str; 7] = ["Do", "Re", "Mi", "Fa", "Sol", "La", "Si"];
+        const GERMAN: [&str; 7] = ["C", "D", "E", "F", "G", "A", "H"];
+
+        let (letter, alteration) = spell(key, sharps);
+        let mut label = match self {
+            NoteNaming::Letter | NoteNaming::Solfege => {
+                let names = if *self == NoteNaming::Letter {
+                    LETTERS
+                } else {
+                    SOLFEGE
+                };
+                let accidental = match alteration {
+                    1 => "#",
+                    -1 => "b",
+                    _ => "",
+                };
+                format!("{}{}", names[letter as usize], accidental)
+            }
+            NoteNaming::German => match (GERMAN[letter as usize], alteration) {
+                ("H", -1) => "B".to_string(),
+                ("E", -1) => "Es".to_string(),
+                ("A", -1) => "As".to_string(),
+                (name, -1) => format!("{}es", name),
+                (name, 1) => format!("{}is", name),
+                (name, _) => name.to_string(),
+            },
+        };
+        if with_octave {
+            label.push_str(&(key as i32 / 12 - 1).to_string());
+        }
+        label
+    }
+}
+
+/// How the key signature alters `letter`.
+fn key_alteration(letter: u8, sharps: i8) -> i8 {
+    if sharps > 0 && SHARP_ORDER[..sharps.min(7) as usize].contains(&letter) {
+        1
+    } else if sharps < 0 && FLAT_ORDER[..(-sharps).min(7) as usize].contains(&letter) {
+        -1
+    } else {
+        0
+    }
+}
+
+/// The letters a key signature alters, in the order they are written.
+pub fn key_signature_letters(sharps: i8) -> &'static [u8] {
+    if sharps >= 0 {
+        &SHARP_ORDER[..sharps.min(7) as usize]
+    } else {
+        &FLAT_ORDER[..(-sharps).min(7) as usize]
+    }
+}
+
+/// Staff position of `letter` in `octave`, in diatonic steps from middle C.
+pub fn step_of(letter: u8, octave: i32) -> i32 {
+    (octave - 4) * 7 + letter as i32
+}
+
+pub struct ScoreNote {
+    pub key: u8,
+    /// Song time the note is played at, in microseconds, or the measure
+    /// starts at for the rest of a note held over the bar line.
+    pub start_time: u32,
+    pub staff: Staff,
+    /// Diatonic steps from middle C.
+    pub step: i32,
+    pub accidental: Option<Accidental>,
+    /// Sixteenths from the start of the measure.
+    pub position: u32,
+    /// Sixteenths the note lasts, up to the end of the measure.
+    pub length: u32,
+    pub finger: Option<u8>,
+    /// The rest of a note from the measure before, tied to it.
+    pub tie_stop: bool,
+    /// Held over the bar line, tied to the rest of it in the next measure.
+    pub tie_start: bool,
+}
+
+impl ScoreNote {
+    /// Letter from 0 (C) to 6 (B), alteration in semitones and octave.
+    pub fn pitch(&self) -> (u8, i8, i32) {
+        let letter = self.step.rem_euclid(7) as u8;
+        let octave = self.step.div_euclid(7) + 4;
+        const NATURALS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+        let natural = (octave + 1) * 12 + NATURALS[letter as usize];
+        (letter, (self.key as i32 - natural) as i8, octave)
+    }
+
+    /// The values the note is written as, tied together when there are
+    /// several.
+    pub fn pieces(&self) -> Vec<(NoteValue, bool)> {
+        NoteValue::split(self.length)
+    }
+}
+
+pub struct ScoreMeasure {
+    pub number: u32,
+    /// Song time the measure starts at, in microseconds.
+    pub time: u32,
+    /// Song time the measure ends at, in microseconds.
+    pub end_time: u32,
+    pub numerator: u8,
+    pub denominator: u8,
+    /// Sharps (flats when negative) of the key signature in effect.
+    pub sharps: i8,
+    pub notes: Vec<ScoreNote>,
+}
+
+impl ScoreMeasure {
+    pub fn sixteenths(&self) -> u32 {
+        (self.numerator as u32 * 16 / self.denominator.max(1) as u32).max(1)
+    }
+
+    /// Song time `position` sixteenths into the measure, taking the tempo
+    /// as steady within it.
+    pub fn time_at(&self, position: u32) -> u32 {
+        let length = self.end_time.saturating_sub(self.time) as u64;
+        self.time + (length * position as u64 / self.sixteenths() as u64) as u32
+    }
+
+    /// Beat `position` sixteenths into the measure falls on.
+    pub fn beat_at(&self, position: u32) -> u32 {
+        position * self.denominator.max(1) as u32 / 16
+    }
+}
+
+/// A song quantized and spelled for a grand staff.
+pub struct Score {
+    pub measures: Vec<ScoreMeasure>,
+}
+
+impl Score {
+    pub fn of(song: &Song) -> Self {
+        let grid = (song.ticks_per_quarter_note() as u32 / GRID_PER_QUARTER).max(1);
+        let quantize = |delta: u32| (delta as f32 / grid as f32).round() as u32 * grid;
+
+        let song_measures = song.measures();
+        let mut measures: Vec<ScoreMeasure> = song_measures
+            .iter()
+            .enumerate()
+            .map(|(i, m)| ScoreMeasure {
+                number: m.number,
+                time: m.time,
+                end_time: match song_measures.get(i + 1) {
+                    Some(next) => next.time,
+                    None => song.time_at(
+                        m.delta
+                            + song.ticks_per_quarter_note() as u32 * 4 * m.numerator as u32
+                                / m.denominator.max(1) as u32,
+                    ),
+                },
+                numerator: m.numerator,
+                denominator: m.denominator,
+                sharps: song.sharps_at(m.time),
+                notes: vec![],
+            })
+            .collect();
+
+        let mut blocks: Vec<_> = song.note_blocks().collect();
+        blocks.sort_by_key(|b| (b.start_delta, b.key.byte()));
+        for block in blocks {
+            let start = quantize(block.start_delta);
+            let stop = quantize(block.stop_delta.unwrap_or(block.start_delta)).max(start + grid);
+            // notes held over bar lines go on in the measures after, tied
+            let mut index = song_measures
+                .partition_point(|m| m.delta <= start)
+                .saturating_sub(1);
+            let sharps = measures.get(index).map_or(0, |m| m.sharps);
+            let (letter, alteration) = spell(block.key.byte(), sharps);
+            let octave = block.key.byte() as i32 / 12 - 1;
+            let mut piece_start = start;
+            while let Some(measure) = song_measures.get(index)
+                && piece_start < stop
+            {
+                let measure_end = song_measures
+                    .get(index + 1)
+                    .map_or(u32::MAX, |next| next.delta);
+                let piece_stop = stop.min(measure_end);
+
+                let held_over = piece_start > start;
+
+                measures[index].notes.push(ScoreNote {
+                    key: block.key.byte(),
+                    start_time: if held_over {
+                        measure.time
+                    } else {
+                        block.start_time
+                    },
+                    staff: Staff::of(block.hand),
+                    step: step_of(letter, octave),
+                    accidental: Some(Accidental::of(alteration)),
+                    position: (piece_start - measure.delta) / grid,
+                    length: (piece_stop - piece_start) / grid,
+                    finger: if held_over { None } else { block.finger },
+                    tie_stop: held_over,
+                    tie_start: piece_stop < stop,
+                });
+                piece_start = piece_stop;
+                index += 1;
+            }
+        }
+
+        // held over notes join the measure's notes by where they start
+        for measure in measures.iter_mut() {
+            measure.notes.sort_by_key(|note| (note.position, note.key));
+        }
+
+        for measure in measures.iter_mut() {
+            mark_accidentals(measure);
+        }
+
+        Self { measures }
+    }
+
+    /// Sharps (flats when negative) of the key signature at song time
+    /// `time`, in microseconds.
+    pub fn sharps_at(&self, time: u32) -> i8 {
+        let index = self.measures.partition_point(|m| m.time <= time);
+        self.measures
+            .get(index.saturating_sub(1))
+            .map_or(0, |m| m.sharps)
+    }
+}
+
+/// Keeps only the accidentals the key signature and earlier notes in the
+/// measure don't already imply. Notes come in with their alteration as
+/// accidental. The rest of a tied note goes without, as the tie carries it.
+fn mark_accidentals(measure: &mut ScoreMeasure) {
+    let sharps = measure.sharps;
+    let mut altered: Vec<(Staff, i32, i8)> = vec![];
+    for note in measure.notes.iter_mut() {
+        if note.tie_stop {
+            note.accidental = None;
+            continue;
+        }
+        let alteration = match note.accidental {
+            Some(Accidental::Sharp) => 1,
+            Some(Accidental::Flat) => -1,
+            _ => 0,
+        };
+        let letter = note.step.rem_euclid(7) as u8;
+        let current = altered
+            .iter()
+            .rev()
+            .find(|(staff, step, _)| *staff == note.staff && *step == note.step)
+            .map_or(key_alteration(letter, sharps), |(_, _, a)| *a);
+
+        if alteration == current {
+            note.accidental = None;
+        } else {
+            note.accidental = Some(Accidental::of(alteration));
+            altered.push((note.staff, note.step, alteration));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::KeySignatureChange;
+    use crate::song::tests::{note, parts};
+
+    /// A 4/4 song at 120 bpm with key changes as (tick, sharps).
+    fn song_in_keys(notes: Vec<crate::song::NoteBlock>, keys: &[(u32, i8)]) -> Song {
+        Song::from_parts(crate::song::SongParts {
+            key_signatures: keys
+                .iter()
+                .map(|(delta, sharps)| KeySignatureChange {
+                    delta: *delta,
+                    time: 0,
+                    sharps: *sharps,
+                    minor: false,
+                })
+                .collect(),
+            ..parts(notes, &[(0, 500_000)])
+        })
+    }
+
+    #[test]
+    fn black_keys_are_spelled_by_the_key() {
+        // C# in G major, Db in F major
+        assert_eq!(spell(61, 1), (0, 1));
+        assert_eq!(spell(61, -1), (1, -1));
+        // C major spells them as sharps
+        assert_eq!(spell(70, 0), (5, 1));
+        assert_eq!(spell(64, -3), (2, 0));
+    }
+
+    #[test]
+    fn lengths_split_into_values_longest_first() {
+        assert_eq!(NoteValue::fitting(4), (NoteValue::Quarter, false));
+        assert_eq!(NoteValue::fitting(6), (NoteValue::Quarter, true));
+        assert_eq!(NoteValue::fitting(7), (NoteValue::Quarter, true));
+        assert_eq!(NoteValue::fitting(24), (NoteValue::Whole, false));
+        assert_eq!(
+            NoteValue::split(5),
+            vec![(NoteValue::Quarter, false), (NoteValue::Sixteenth, false)]
+        );
+        assert_eq!(
+            NoteValue::split(9),
+            vec![(NoteValue::Half, false), (NoteValue::Sixteenth, false)]
+        );
+        for sixteenths in 1..40 {
+            let total: u32 = NoteValue::split(sixteenths)
+                .iter()
+                .map(|(value, dotted)| value.sixteenths_dotted(*dotted))
+                .sum();
+            assert_eq!(total, sixteenths);
+        }
+    }
+
+    #[test]
+    fn accidentals_carry_through_the_measure() {
+        // F#, F#, F natural in G major, then F# again in the next measure
+        let song = song_in_keys(
+            vec![
+                note(66, 0, 480),
+                note(66, 480, 960),
+                note(65, 960, 1440),
+                note(66, 1440, 1920),
+                note(66, 1920, 2400),
+                note(61, 2400, 2880),
+                note(61, 2880, 3360),
+            ],
+            &[(0, 1)],
+        );
+        let score = Score::of(&song);
+        let accidentals = |measure: usize| -> Vec<Option<Accidental>> {
+            score.measures[measure]
+                .notes
+                .iter()
+                .map(|n| n.accidental)
+                .collect()
+        };
+        assert_eq!(
+            accidentals(0),
+            vec![
+                None,
+                None,
+                Some(Accidental::Natural),
+                Some(Accidental::Sharp)
+            ]
+        );
+        assert_eq!(accidentals(1), vec![None, Some(Accidental::Sharp), None]);
+    }
+
+    #[test]
+    fn each_measure_is_spelled_in_its_own_key() {
+        // A# in E major, then Bb once the key turns to F major
+        let song = song_in_keys(
+            vec![note(70, 0, 1920), note(70, 1920, 3840)],
+            &[(0, 4), (1920, -1)],
+        );
+        let score = Score::of(&song);
+        assert_eq!(
+            score.measures[..2]
+                .iter()
+                .map(|m| m.sharps)
+                .collect::<Vec<_>>(),
+            vec![4, -1]
+        );
+        let pitches: Vec<_> = score.measures[..2]
+            .iter()
+            .map(|m| (m.notes[0].pitch(), m.notes[0].accidental))
+            .collect();
+        assert_eq!(
+            pitches,
+            vec![((5, 1, 4), Some(Accidental::Sharp)), ((6, -1, 4), None)]
+        );
+        assert_eq!(score.sharps_at(0), 4);
+        assert_eq!(score.sharps_at(2_500_000), -1);
+    }
+
+    #[test]
+    fn notes_over_the_bar_line_are_tied() {
+        // a dotted half starting on beat 3 of 4/4
+        let song = song_in_keys(vec![note(60, 960, 2400)], &[]);
+        let score = Score::of(&song);
+        let first = &score.measures[0].notes[0];
+        let rest = &score.measures[1].notes[0];
+        assert_eq!((first.position, first.length), (8, 8));
+        assert!(first.tie_start && !first.tie_stop);
+        assert_eq!((rest.position, rest.length), (0, 4));
+        assert!(rest.tie_stop && !rest.tie_start);
+        assert_eq!(rest.accidental, None);
+    }
+}
@@ -0,0 +1,192 @@
+use std::path::PathBuf;
+
+use macroquad::prelude::*;
+
+use crate::library::SongInfo;
+
+const MARGIN: f32 = 60.;
+const LINE_HEIGHT: f32 = 32.;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortBy {
+    Title,
+    Composer,
+    Duration,
+    Notes,
+    Difficulty,
+}
+
+impl SortBy {
+    fn name(&self) -> &'static str {
+        match self {
+            SortBy::Title => "title",
+            SortBy::Composer => "composer",
+            SortBy::Duration => "duration",
+            SortBy::Notes => "notes",
+            SortBy::Difficulty => "difficulty",
+        }
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            SortBy::Title => SortBy::Composer,
+            SortBy::Composer => SortBy::Duration,
+            SortBy::Duration => SortBy::Notes,
+            SortBy::Notes => SortBy::Difficulty,
+            SortBy::Difficulty => SortBy::Title,
+        }
+    }
+}
+
+/// The song library as a list to search by typing, sort with Tab and pick
+/// from with the arrows and Enter.
+pub struct SongPicker {
+    songs: Vec<SongInfo>,
+    query: String,
+    sort_by: SortBy,
+    /// Indexes into `songs` of the songs matching the query, in order.
+    shown: Vec<usize>,
+    selected: usize,
+}
+
+fn format_duration(seconds: f32) -> String {
+    let seconds = seconds.round() as u32;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+impl SongPicker {
+    pub fn new(songs: Vec<SongInfo>) -> Self {
+        let mut picker = Self {
+            songs,
+            query: String::new(),
+            sort_by: SortBy::Title,
+            shown: vec![],
+            selected: 0,
+        };
+        picker.refresh();
+        picker
+    }
+
+    fn refresh(&mut self) {
+        let mut shown: Vec<usize> = (0..self.songs.len())
+            .filter(|i| self.songs[*i].matches(&self.query))
+            .collect();
+        let songs = &self.songs;
+        match self.sort_by {
+            SortBy::Title => shown.sort_by_key(|i| songs[*i].title.to_lowercase()),
+            SortBy::Composer => shown.sort_by_key(|i| songs[*i].composer.to_lowercase()),
+            SortBy::Duration => {
+                shown.sort_by(|a, b| songs[*a].duration.total_cmp(&songs[*b].duration))
+            }
+            SortBy::Notes => shown.sort_by_key(|i| songs[*i].notes),
+            SortBy::Difficulty => shown.sort_by(|a, b| {
+                songs[*a]
+                    .difficulty
+                    .score
+                    .total_cmp(&songs[*b].difficulty.score)
+            }),
+        }
+        self.shown = shown;
+        self.selected = self.selected.min(self.shown.len().saturating_sub(1));
+    }
+
+    /// Takes the frame's key presses, returning the song picked with Enter.
+    pub fn update(&mut self) -> Option<PathBuf> {
+        let mut changed = false;
+        while let Some(c) = get_char_pressed() {
+            if !c.is_control() {
+                self.query.push(c);
+                changed = true;
+            }
+        }
+        if is_key_pressed(KeyCode::Backspace) && self.query.pop().is_some() {
+            changed = true;
+        }
+        if is_key_pressed(KeyCode::Tab) {
+            self.sort_by = self.sort_by.next();
+            changed = true;
+        }
+        if changed {
+            self.refresh();
+        }
+
+        if is_key_pressed(KeyCode::Down) && self.selected + 1 < self.shown.len() {
+            self.selected += 1;
+        }
+        if is_key_pressed(KeyCode::Up) {
+            self.selected = self.selected.saturating_sub(1);
+        }
+
+        if is_key_pressed(KeyCode::Enter) {
+            let index = self.shown.get(self.selected)?;
+            return Some(self.songs[*index].path.clone());
+        }
+        None
+    }
+
+    pub fn draw(&self) {
+        clear_background(BLACK);
+
+        draw_text(
+            format!(
+                "{} of {} songs, sorted by {} (Tab), Enter to play, Esc to go back",
+                self.shown.len(),
+                self.songs.len(),
+                self.sort_by.name()
+            ),
+            MARGIN,
+            MARGIN,
+            28.,
+            WHITE,
+        );
+        draw_text(
+            format!("search: {}_", self.query),
+            MARGIN,
+            MARGIN + LINE_HEIGHT,
+            28.,
+            YELLOW,
+        );
+
+        if let Some(index) = self.shown.get(self.selected) {
+            draw_text(
+                self.songs[*index].difficulty.describe(),
+                MARGIN,
+                screen_height() - MARGIN,
+                24.,
+                YELLOW,
+            );
+        }
+
+        let rows = ((screen_height() - MARGIN * 4.) / LINE_HEIGHT).max(1.) as usize;
+        let first = self.selected.saturating_sub(rows / 2);
+        for (row, index) in self.shown.iter().skip(first).take(rows).enumerate() {
+            let song = &self.songs[*index];
+            let y = MARGIN + LINE_HEIGHT * (row + 3) as f32;
+            let color = if first + row == self.selected {
+                draw_rectangle(
+                    MARGIN - 10.,
+                    y - LINE_HEIGHT + 8.,
+                    screen_width() - MARGIN * 2. + 20.,
+                    LINE_HEIGHT,
+                    DARKBLUE,
+                );
+                WHITE
+            } else {
+                LIGHTGRAY
+            };
+            let mut line = song.title.clone();
+            if !song.composer.is_empty() {
+                line.push_str(&format!(" - {}", song.composer));
+            }
+            line.push_str(&format!(
+                "  [difficulty {:.1}, {}, {} notes{}{}]",
+                song.difficulty.score,
+                format_duration(song.duration),
+                song.notes,
+                if song.key.is_empty() { "" } else { ", " },
+                song.key
+            ));
+            draw_text(line, MARGIN, y, 26., color);
+        }
+    }
+}
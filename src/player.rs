@@ -0,0 +1,310 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use midir::MidiOutputConnection;
+
+use crate::song::{Hand, NoteBlock, Song};
+
+pub const NOTE_OFF: u8 = 0x80;
+pub const NOTE_ON: u8 = 0x90;
+pub const CONTROL_CHANGE: u8 = 0xB0;
+pub const PROGRAM_CHANGE: u8 = 0xC0;
+
+/// Anything that can be fed raw MIDI messages: an external port or one of
+/// the built-in synths.
+pub trait MidiSink: Send {
+    fn send(&mut self, message: &[u8]);
+}
+
+impl MidiSink for MidiOutputConnection {
+    fn send(&mut self, message: &[u8]) {
+        if let Err(why) = MidiOutputConnection::send(self, message) {
+            println!("failed to send MIDI message {:?}: {}", message, why);
+        }
+    }
+}
+
+pub type SharedMidiSink = Arc<Mutex<dyn MidiSink>>;
+
+pub fn send(outputs: &[SharedMidiSink], message: &[u8]) {
+    for output in outputs {
+        output.lock().unwrap().send(message);
+    }
+}
+
+/// Sends the song's notes to MIDI sinks, following the song time it is
+/// given on every `update`.
+pub struct SongPlayer {
+    outputs: Vec<SharedMidiSink>,
+    position: u32,
+    // indices of the first program change, control change and note group
+    // not played yet, so an update only looks at what's new
+    next_program_change: usize,
+    next_control_change: usize,
+    next_group: usize,
+    // (stop time, channel, key) of notes started but not stopped yet
+    pending_stops: Vec<(u32, u32, u8)>,
+    sounding: HashSet<(u32, u8)>,
+    muted_channels: HashSet<u32>,
+    solo_channel: Option<u32>,
+    muted_hands: HashSet<Hand>,
+    solo_hand: Option<Hand>,
+}
+
+impl SongPlayer {
+    pub fn new(outputs: Vec<SharedMidiSink>) -> Self {
+        Self {
+            outputs,
+            position: 0,
+            next_program_change: 0,
+            next_control_change: 0,
+            next_group: 0,
+            pending_stops: vec![],
+            sounding: HashSet::new(),
+            muted_channels: HashSet::new(),
+            solo_channel: None,
+            muted_hands: HashSet::new(),
+            solo_hand: None,
+        }
+    }
+
+    pub fn outputs(&self) -> &[SharedMidiSink] {
+        &self.outputs
+    }
+
+    fn is_audible(&self, block: &NoteBlock) -> bool {
+        !self.muted_channels.contains(&block.channel_number)
+            && !self.muted_hands.contains(&block.hand)
+            && self
+                .solo_channel
+                .is_none_or(|ch| ch == block.channel_number)
+            && self.solo_hand.is_none_or(|hand| hand == block.hand)
+    }
+
+    /// Plays everything that happens in `[position, now)`.
+    pub fn update(&mut self, song: &Song, now: u32) {
+        if now < self.position {
+            self.seek(song, now);
+            return;
+        }
+
+        let program_changes = song.program_changes();
+        while let Some(pc) = program_changes.get(self.next_program_change)
+            && pc.time < now
+        {
+            send(
+                &self.outputs,
+                &[PROGRAM_CHANGE | pc.channel_number as u8, pc.program],
+            );
+            self.next_program_change += 1;
+        }
+
+        let control_changes = song.control_changes();
+        while let Some(cc) = control_changes.get(self.next_control_change)
+            && cc.time < now
+        {
+            send(
+                &self.outputs,
+                &[
+                    CONTROL_CHANGE | cc.channel_number as u8,
+                    cc.controller,
+                    cc.value,
+                ],
+            );
+            self.next_control_change += 1;
+        }
+
+        let mut events = vec![];
+        let groups = song.note_groups();
+        while let Some(group) = groups.get(self.next_group)
+            && group.first().is_none_or(|block| block.start_time < now)
+        {
+            for block in group {
+                if !self.is_audible(block) {
+                    continue;
+                }
+                events.push((
+                    block.start_time,
+                    true,
+                    block.channel_number,
+                    block.key.byte(),
+                    block.velocity,
+                ));
+                if let Some(stop_time) = block.stop_time {
+                    self.pending_stops
+                        .push((stop_time, block.channel_number, block.key.byte()));
+                }
+            }
+            self.next_group += 1;
+        }
+        self.pending_stops.retain(|&(stop_time, channel, key)| {
+            if stop_time < now {
+                events.push((stop_time, false, channel, key, 0));
+                false
+            } else {
+                true
+            }
+        });
+        // note offs go first, so a repeated key is released before it's struck again
+        events.sort_by_key(|(time, on, _, _, _)| (*time, *on));
+
+        for (_, on, channel, key, velocity) in events {
+            if on {
+                send(&self.outputs, &[NOTE_ON | channel as u8, key, velocity]);
+                self.sounding.insert((channel, key));
+            } else if self.sounding.remove(&(channel, key)) {
+                send(&self.outputs, &[NOTE_OFF | channel as u8, key, 0]);
+            }
+        }
+
+        self.position = now;
+    }
+
+    pub fn pause(&mut self) {
+        for (channel, key) in self.sounding.drain() {
+            send(&self.outputs, &[NOTE_OFF | channel as u8, key, 0]);
+        }
+    }
+
+    /// Jumps to `time` without playing anything in between. Programs and
+    /// controllers set before `time` are re-sent, so the output sounds like
+    /// it would have had the song been played from the start.
+    pub fn seek(&mut self, song: &Song, time: u32) {
+        self.pause();
+
+        let mut programs = HashMap::new();
+        for pc in song.program_changes() {
+            if pc.time < time {
+                programs.insert(pc.channel_number, pc.program);
+            }
+        }
+        for (channel, program) in programs {
+            send(&self.outputs, &[PROGRAM_CHANGE | channel as u8, program]);
+        }
+
+        let mut controllers = HashMap::new();
+        for cc in song.control_changes() {
+            if cc.time < time {
+                controllers.insert((cc.channel_number, cc.controller), cc.value);
+            }
+        }
+        for ((channel, controller), value) in controllers {
+            send(
+                &self.outputs,
+                &[CONTROL_CHANGE | channel as u8, controller, value],
+            );
+        }
+
+        self.next_program_change = song.program_changes().partition_point(|pc| pc.time < time);
+        self.next_control_change = song.control_changes().partition_point(|cc| cc.time < time);
+        self.next_group = song
+            .note_groups()
+            .partition_point(|group| group.first().is_none_or(|block| block.start_time < time));
+        self.pending_stops.clear();
+        self.position = time;
+    }
+
+    pub fn toggle_channel_mute(&mut self, channel: u32) {
+        if !self.muted_channels.remove(&channel) {
+            self.muted_channels.insert(channel);
+        }
+        self.pause();
+    }
+
+    pub fn toggle_channel_solo(&mut self, channel: u32) {
+        self.solo_channel = match self.solo_channel {
+            Some(ch) if ch == channel => None,
+            _ => Some(channel),
+        };
+        self.pause();
+    }
+
+    pub fn toggle_hand_mute(&mut self, hand: Hand) {
+        if !self.muted_hands.remove(&hand) {
+            self.muted_hands.insert(hand);
+        }
+        self.pause();
+    }
+
+    pub fn toggle_hand_solo(&mut self, hand: Hand) {
+        self.solo_hand = match self.solo_hand {
+            Some(h) if h == hand => None,
+            _ => Some(hand),
+        };
+        self.pause();
+    }
+
+    pub fn describe(&self) -> String {
+        let mut parts = vec![];
+        let mut muted_channels: Vec<_> = self.muted_channels.iter().collect();
+        muted_channels.sort();
+        for ch in muted_channels {
+            parts.push(format!("-ch{}", ch + 1));
+        }
+        for hand in [Hand::Left, Hand::Right] {
+            if self.muted_hands.contains(&hand) {
+                parts.push(format!("-{:?}", hand));
+            }
+        }
+        if let Some(ch) = self.solo_channel {
+            parts.push(format!("solo ch{}", ch + 1));
+        }
+        if let Some(hand) = self.solo_hand {
+            parts.push(format!("solo {:?}", hand));
+        }
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::tests::{note, song};
+
+    struct Recording(Vec<Vec<u8>>);
+
+    impl MidiSink for Recording {
+        fn send(&mut self, message: &[u8]) {
+            self.0.push(message.to_vec());
+        }
+    }
+
+    #[test]
+    fn notes_play_once_in_order_across_updates_and_seeks() {
+        // at 120 bpm a quarter note is half a second
+        let song = song(
+            vec![note(60, 0, 480), note(64, 480, 960), note(67, 480, 1440)],
+            &[(0, 500_000)],
+        );
+        let sink = Arc::new(Mutex::new(Recording(vec![])));
+        let mut player = SongPlayer::new(vec![sink.clone()]);
+
+        for now in (0..=800_000).step_by(10_000) {
+            player.update(&song, now);
+        }
+        assert_eq!(
+            sink.lock().unwrap().0,
+            vec![
+                vec![NOTE_ON, 60, 80],
+                vec![NOTE_OFF, 60, 0],
+                vec![NOTE_ON, 64, 80],
+                vec![NOTE_ON, 67, 80],
+            ]
+        );
+
+        // seeking releases what's sounding, then plays everything again
+        player.seek(&song, 0);
+        sink.lock().unwrap().0.clear();
+        player.update(&song, 1_200_000);
+        assert_eq!(
+            sink.lock().unwrap().0,
+            vec![
+                vec![NOTE_ON, 60, 80],
+                vec![NOTE_OFF, 60, 0],
+                vec![NOTE_ON, 64, 80],
+                vec![NOTE_ON, 67, 80],
+                vec![NOTE_OFF, 64, 0],
+            ]
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use std::error::Error;
+
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+
+/// Connects to the input port with the given id, or creates a virtual input
+/// port named `virtual_name` for other software to connect to.
+pub fn connect_input<F>(
+    port_id: Option<String>,
+    virtual_name: Option<String>,
+    callback: F,
+) -> Result<MidiInputConnection<()>, Box<dyn Error>>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    let mut midi_in = MidiInput::new("midir reading input")?;
+    midi_in.ignore(Ignore::None);
+
+    match (port_id, virtual_name) {
+        (None, Some(virtual_name)) => create_virtual_input(midi_in, &virtual_name, callback),
+        (Some(port_id), _) => {
+            let in_port = midi_in
+                .find_port_by_id(port_id.clone())
+                .ok_or(format!("MIDI input port {} not found", port_id))?;
+            Ok(midi_in.connect(&in_port, "midir-read-input", callback, ())?)
+        }
+        (None, None) => Err("no MIDI input port given".into()),
+    }
+}
+
+pub fn connect_output(port_id: String) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    let midi_out = MidiOutput::new("midir output")?;
+    let out_port = midi_out
+        .find_port_by_id(port_id.clone())
+        .ok_or(format!("MIDI output port {} not found", port_id))?;
+    Ok(midi_out.connect(&out_port, "midir-write-output")?)
+}
+
+#[cfg(unix)]
+fn create_virtual_input<F>(
+    midi_in: MidiInput,
+    name: &str,
+    callback: F,
+) -> Result<MidiInputConnection<()>, Box<dyn Error>>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    use midir::os::unix::VirtualInput;
+    Ok(midi_in.create_virtual(name, callback, ())?)
+}
+
+#[cfg(not(unix))]
+fn create_virtual_input<F>(
+    _midi_in: MidiInput,
+    _name: &str,
+    _callback: F,
+) -> Result<MidiInputConnection<()>, Box<dyn Error>>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    Err("virtual MIDI ports are not supported on this platform".into())
+}
+
+#[cfg(unix)]
+pub fn create_virtual_output(name: &str) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    use midir::os::unix::VirtualOutput;
+    let midi_out = MidiOutput::new("midir output")?;
+    Ok(midi_out.create_virtual(name)?)
+}
+
+#[cfg(not(unix))]
+pub fn create_virtual_output(_name: &str) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    Err("virtual MIDI ports are not supported on this platform".into())
+}
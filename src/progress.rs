@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+
+use macroquad::prelude::*;
+
+use crate::engine::MAX_TEMPO;
+use crate::history::PracticeRecord;
+
+const MARGIN: f32 = 60.;
+
+/// Accuracy and tempo of every past attempt at the song, oldest on the left.
+pub struct ProgressView {
+    records: Vec<PracticeRecord>,
+}
+
+impl ProgressView {
+    pub fn new(records: Vec<PracticeRecord>) -> Self {
+        Self { records }
+    }
+
+    fn draw_graph(&self, value: impl Fn(&PracticeRecord) -> f32, color: Color) {
+        let width = screen_width() - MARGIN * 2.;
+        let height = screen_height() - MARGIN * 4.;
+        let step = width / (self.records.len().max(2) - 1) as f32;
+
+        let points: Vec<Vec2> = self
+            .records
+            .iter()
+            .enumerate()
+            .map(|(i, record)| {
+                vec2(
+                    MARGIN + i as f32 * step,
+                    MARGIN * 2. + height * (1. - value(record).clamp(0., 1.)),
+                )
+            })
+            .collect();
+        for pair in points.windows(2) {
+            draw_line(pair[0].x, pair[0].y, pair[1].x, pair[1].y, 2., color);
+        }
+        for point in points {
+            draw_circle(point.x, point.y, 4., color);
+        }
+    }
+
+    pub fn draw(&self) {
+        clear_background(BLACK);
+
+        if self.records.is_empty() {
+            draw_text(
+                "no practice recorded for this song yet, H to go back",
+                MARGIN,
+                MARGIN,
+                32.,
+                WHITE,
+            );
+            return;
+        }
+
+        let practiced: f32 = self.records.iter().map(|r| r.practiced).sum();
+        draw_text(
+            format!(
+                "{} sessions, {:.0} minutes practiced, H to go back",
+                self.records.len(),
+                practiced / 60.
+            ),
+            MARGIN,
+            MARGIN,
+            32.,
+            WHITE,
+        );
+        draw_text("accuracy", MARGIN, MARGIN * 1.5, 24., GREEN);
+        draw_text("tempo", MARGIN + 120., MARGIN * 1.5, 24., YELLOW);
+
+        let bottom = screen_height() - MARGIN * 2.;
+        draw_line(MARGIN, bottom, screen_width() - MARGIN, bottom, 1., GRAY);
+        draw_line(MARGIN, MARGIN * 2., MARGIN, bottom, 1., GRAY);
+
+        self.draw_graph(|r| r.accuracy, GREEN);
+        self.draw_graph(|r| r.tempo / MAX_TEMPO, YELLOW);
+
+        let mut errors: BTreeMap<usize, u32> = BTreeMap::new();
+        for record in self.records.iter() {
+            for (measure, count) in record.measure_errors.iter() {
+                *errors.entry(*measure).or_insert(0) += count;
+            }
+        }
+        let mut worst: Vec<(usize, u32)> = errors.into_iter().collect();
+        worst.sort_by_key(|(measure, count)| (std::cmp::Reverse(*count), *measure));
+        let worst: Vec<String> = worst
+            .iter()
+            .take(5)
+            .map(|(measure, count)| format!("m{} ({})", measure, count))
+            .collect();
+        if !worst.is_empty() {
+            draw_text(
+                format!("most errors: {}", worst.join(", ")),
+                MARGIN,
+                screen_height() - MARGIN,
+                32.,
+                WHITE,
+            );
+        }
+    }
+}
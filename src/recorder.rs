@@ -0,0 +1,94 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::smf;
+
+const TICKS_PER_QUARTER_NOTE: u16 = 960;
+const MICROS_PER_QUARTER_NOTE: u32 = 500_000;
+
+struct RecordedEvent {
+    time: u64,
+    message: Vec<u8>,
+}
+
+/// Collects what the player sends, for saving as a Standard MIDI File.
+///
+/// Over a playing song, events are placed at the song time they were played
+/// at, so a take slowed down to practice still lines up with the song. In
+/// free play there is no song time to go by, and events follow their MIDI
+/// input timestamps from where the take left off.
+pub struct Recorder {
+    free_play_anchor: Option<(u64, u64)>,
+    events: Vec<RecordedEvent>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self {
+            free_play_anchor: None,
+            events: vec![],
+        }
+    }
+
+    /// `stamp` is the input timestamp in microseconds and `song_time` where
+    /// in the song the message was played, also in microseconds, or `None`
+    /// when the song isn't playing.
+    pub fn record(&mut self, stamp: u64, song_time: Option<u64>, message: &[u8]) {
+        // note on/off, polyphonic aftertouch and controllers (pedals among
+        // them), nothing else makes it into a take
+        if message.is_empty() || !matches!(message[0] & 0xF0, 0x80 | 0x90 | 0xA0 | 0xB0) {
+            return;
+        }
+
+        let time = match song_time {
+            Some(song_time) => {
+                self.free_play_anchor = None;
+                song_time
+            }
+            None => {
+                let last_time = self.events.last().map_or(0, |event| event.time);
+                let (anchor_stamp, anchor_time) =
+                    *self.free_play_anchor.get_or_insert((stamp, last_time));
+                anchor_time + stamp.saturating_sub(anchor_stamp)
+            }
+        };
+        self.events.push(RecordedEvent {
+            time,
+            message: message.to_vec(),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn save(&self, path: &Path, format: smf::Format) -> io::Result<()> {
+        let mut conductor = smf::Track::new();
+        conductor.push_meta(0, smf::META_TRACK_NAME, b"take");
+        conductor.push_tempo(0, MICROS_PER_QUARTER_NOTE);
+
+        let mut take = smf::Track::new();
+        for event in self.events.iter() {
+            let tick = event.time * TICKS_PER_QUARTER_NOTE as u64 / MICROS_PER_QUARTER_NOTE as u64;
+            take.push(tick as u32, &event.message);
+        }
+
+        smf::write(path, format, TICKS_PER_QUARTER_NOTE, &[conductor, take])
+    }
+}
+
+/// A not yet used `take-<seconds since epoch>.mid` path in `dir`.
+pub fn take_path(dir: &Path) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut path = dir.join(format!("take-{}.mid", now));
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("take-{}-{}.mid", now, n));
+        n += 1;
+    }
+    path
+}
@@ -1,48 +1,73 @@
-use std::collections::{HashMap, HashSet};
-
 use macroquad::experimental::scene::{Node, RefMut};
 use macroquad::prelude::*;
-use midix::prelude::Key;
-
-use crate::song;
-use crate::utils;
-
-#[derive(Eq, PartialEq)]
-pub enum GameMode {
-    Play,
-    LearnBlocking,
-    Unset,
+use midix::prelude::{FromLiveEventBytes, Key, LiveEvent, VoiceEvent};
+
+use crate::calibration;
+use crate::engine::Engine;
+use crate::heatmap::Heatmap;
+use crate::history::PracticeRecord;
+use crate::keyboard::{self, KeyRange};
+use crate::library::SongInfo;
+use crate::notation::{self, NoteNaming};
+use crate::picker::SongPicker;
+use crate::progress;
+use crate::session::SessionEvent;
+use crate::sheet::SheetView;
+use crate::song::Song;
+use crate::statistics::StatisticsView;
+use crate::weakness::Weaknesses;
+
+/// Which way the notes travel towards the keyboard.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Orientation {
+    /// Notes fall onto a keyboard along the bottom.
+    Vertical,
+    /// Notes scroll right to left onto a keyboard along the left side, like
+    /// in a DAW's piano roll.
+    Horizontal,
 }
 
+/// Draws the engine's state: the keyboard and the notes falling onto it.
 pub struct PianoScreen {
-    song: song::Song,
-    mode: GameMode,
-    play: bool,
-    time_offset: f32,
-    time_offset_y: f32,
-    num_white_keys: u32,
+    engine: Engine,
+    /// Keys drawn.
+    keys: KeyRange,
+    /// Keys the player's instrument has.
+    playable: KeyRange,
+    /// Whether to draw just the keys the song needs.
+    fit_to_song: bool,
     white_piano_key_width: f32,
     white_piano_key_height: f32,
     black_piano_key_width: f32,
     black_piano_key_height: f32,
     midi_render_target: RenderTarget,
     midi_target_cam: Camera2D,
-    render_debug_extra: bool,
-    text_texture_cache: HashMap<String, Texture2D>,
+    /// How notes are labelled, if at all.
+    labels: Option<NoteNaming>,
+    label_octaves: bool,
+    /// Whether the notes' fingers are shown on their blocks.
+    fingers: bool,
     pixels_per_second: f32,
     default_pixels_per_second: f32,
-    awaiting_piano_input: bool,
-    awaiting_keys: Option<HashSet<Key>>,
-    active_piano_keys: HashSet<Key>,
-    active_piano_keys_history: utils::ActiveKeysHistory,
-    next_group: Vec<song::NoteBlock>,
+    calibration: Option<calibration::Calibration>,
+    progress: Option<progress::ProgressView>,
+    heatmap: Option<Heatmap>,
+    statistics: Option<StatisticsView>,
+    picker: Option<SongPicker>,
+    sheet: Option<SheetView>,
+    orientation: Orientation,
 }
 
 impl PianoScreen {
-    pub fn recalculate(&mut self, width: f32, _height: f32) {
-        self.white_piano_key_height = 200.;
-        self.white_piano_key_width = (width / ((self.num_white_keys + 1) as f32)) - 2.;
-        self.black_piano_key_height = 130.;
+    pub fn recalculate(&mut self, width: f32, height: f32) {
+        // the keyboard runs along the width, or up the height on its side
+        let (length, depth) = match self.layout() {
+            Orientation::Vertical => (width, 200.),
+            Orientation::Horizontal => (height, 160.),
+        };
+        self.white_piano_key_height = depth;
+        self.white_piano_key_width = (length / ((self.keys.white_keys() + 1) as f32)) - 2.;
+        self.black_piano_key_height = depth * 0.65;
         self.black_piano_key_width = self.white_piano_key_width * 0.5;
 
         self.midi_render_target = render_target(
@@ -58,99 +83,384 @@ impl PianoScreen {
         self.midi_target_cam.render_target = Some(self.midi_render_target.clone());
     }
 
-    pub fn new(song: song::Song) -> PianoScreen {
+    pub fn new(engine: Engine) -> PianoScreen {
         let mut ps = PianoScreen {
-            song,
-            mode: GameMode::Unset,
-            play: false,
-            time_offset: 0.,
-            time_offset_y: 0.,
-            num_white_keys: 52,
+            engine,
+            keys: KeyRange::of_size(88).unwrap(),
+            playable: KeyRange::of_size(88).unwrap(),
+            fit_to_song: false,
             white_piano_key_width: 0.,
             white_piano_key_height: 0.,
             black_piano_key_width: 0.,
             black_piano_key_height: 0.,
             midi_render_target: render_target(screen_width() as u32, screen_height() as u32),
             midi_target_cam: Camera2D::from_display_rect(Rect::new(0., 0., 100., 100.)),
-            render_debug_extra: false,
-            text_texture_cache: HashMap::new(),
+            labels: None,
+            label_octaves: false,
+            fingers: true,
             pixels_per_second: 400.,
             default_pixels_per_second: 400.,
-            awaiting_piano_input: false,
-            awaiting_keys: None,
-            active_piano_keys: HashSet::new(),
-            active_piano_keys_history: utils::ActiveKeysHistory::new(),
-            next_group: vec![],
+            calibration: None,
+            progress: None,
+            heatmap: None,
+            statistics: None,
+            picker: None,
+            sheet: None,
+            orientation: Orientation::Vertical,
         };
-        ps.recalculate(screen_width(), screen_height());
-        ps.set_mode(GameMode::Play);
+        ps.fit_keyboard();
         ps
     }
 
-    pub fn reset(&mut self) {
-        self.mode = GameMode::Unset;
-        self.play = false;
-        self.time_offset = 0.;
-        self.time_offset_y = 0.;
-        self.pixels_per_second = self.default_pixels_per_second;
-        self.awaiting_piano_input = false;
-        self.awaiting_keys = None;
-        self.active_piano_keys.clear();
-        self.active_piano_keys_history.clear();
-        self.next_group = vec![];
-        self.set_mode(GameMode::Play);
+    pub fn engine(&mut self) -> &mut Engine {
+        &mut self.engine
+    }
+
+    /// Passes an event on to the engine, unless a calibration is running,
+    /// which takes the key presses for itself.
+    pub fn apply(&mut self, event: SessionEvent) {
+        if let Some(calibration) = self.calibration.as_mut() {
+            if let SessionEvent::Midi(stamp, message) = &event
+                && let Ok(LiveEvent::ChannelVoice(cv)) = LiveEvent::from_bytes(message)
+                && let VoiceEvent::NoteOn { .. } = cv.event()
+            {
+                calibration.tap(self.engine.stamp_to_clock(*stamp));
+            }
+            return;
+        }
+        if let SessionEvent::Reset = event {
+            self.zoom_default();
+        }
+        self.engine.apply(event);
+    }
+
+    pub fn on_piano_key_down(&mut self, key: Key) {
+        if let Some(calibration) = self.calibration.as_mut() {
+            calibration.tap(self.engine.now());
+            return;
+        }
+        self.engine.on_piano_key_down(key);
+    }
+
+    pub fn on_piano_key_up(&mut self, key: Key) {
+        self.engine.on_piano_key_up(key);
+    }
+
+    /// Starts over with another song, dropping what belonged to the old one.
+    pub fn load_song(&mut self, song: Song) {
+        self.heatmap = None;
+        self.zoom_default();
+        self.engine.load_song(song);
+        self.fit_keyboard();
+        if self.sheet.is_some() {
+            self.sheet = Some(SheetView::new(&self.engine));
+        }
+    }
+
+    /// Switches between the falling notes and the sheet music.
+    pub fn toggle_sheet(&mut self) {
+        self.sheet = match self.sheet {
+            Some(_) => None,
+            None => Some(SheetView::new(&self.engine)),
+        };
+        self.on_screen_resize();
+    }
+
+    pub fn is_picking(&self) -> bool {
+        self.picker.is_some()
+    }
+
+    pub fn show_picker(&mut self, songs: Vec<SongInfo>) {
+        self.engine.pause();
+        self.picker = Some(SongPicker::new(songs));
+    }
+
+    pub fn hide_picker(&mut self) {
+        self.picker = None;
+    }
+
+    /// Passes the frame's key presses on to the picker, returning the song
+    /// picked.
+    pub fn update_picker(&mut self) -> Option<std::path::PathBuf> {
+        self.picker.as_mut()?.update()
+    }
+
+    pub fn is_showing_progress(&self) -> bool {
+        self.progress.is_some()
+    }
+
+    pub fn show_progress(&mut self, records: Vec<PracticeRecord>) {
+        self.engine.pause();
+        self.progress = Some(progress::ProgressView::new(records));
+    }
+
+    pub fn hide_progress(&mut self) {
+        self.progress = None;
+    }
+
+    pub fn is_showing_statistics(&self) -> bool {
+        self.statistics.is_some()
+    }
+
+    pub fn show_statistics(&mut self, weaknesses: Weaknesses) {
+        self.engine.pause();
+        self.statistics = Some(StatisticsView::new(weaknesses));
+        self.on_screen_resize();
+    }
+
+    pub fn hide_statistics(&mut self) {
+        self.statistics = None;
+        self.on_screen_resize();
+    }
+
+    /// The keyboard colored from white (or black) to red by how often each
+    /// key went wrong, dark gray for keys never played.
+    fn draw_weak_keys(&self, statistics: &StatisticsView) {
+        clear_background(BLACK);
+
+        self.draw_keys(|key, black| match statistics.key_heat(key) {
+            Some(heat) => {
+                let base = if black { BLACK } else { WHITE };
+                Color::new(
+                    base.r + (RED.r - base.r) * heat,
+                    base.g + (RED.g - base.g) * heat,
+                    base.b + (RED.b - base.b) * heat,
+                    1.,
+                )
+            }
+            None if black => Color::new(0.1, 0.1, 0.1, 1.),
+            None => DARKGRAY,
+        });
+    }
+
+    pub fn is_showing_heatmap(&self) -> bool {
+        self.heatmap.is_some()
+    }
+
+    pub fn show_heatmap(&mut self, records: Vec<PracticeRecord>) {
+        self.heatmap = Some(Heatmap::new(self.engine.song(), &records));
+    }
+
+    pub fn hide_heatmap(&mut self) {
+        self.heatmap = None;
+    }
+
+    /// Song time under a point on the screen, if it's on the timeline.
+    fn time_at(&self, x: f32, y: f32) -> Option<f32> {
+        match self.layout() {
+            Orientation::Vertical => {
+                // the timeline texture comes out upside down, song time grows
+                // upwards
+                let timeline_height = screen_height() - self.white_piano_key_height;
+                if y > timeline_height {
+                    return None;
+                }
+                Some((timeline_height - y) / self.pixels_per_second + self.engine.time_offset())
+            }
+            Orientation::Horizontal => {
+                if x < self.white_piano_key_height {
+                    return None;
+                }
+                Some(
+                    (x - self.white_piano_key_height) / self.pixels_per_second
+                        + self.engine.time_offset(),
+                )
+            }
+        }
+    }
+
+    /// With the heatmap shown, clicking a hot measure loops it, clicking
+    /// anywhere else stops looping.
+    pub fn on_click(&mut self, x: f32, y: f32) {
+        let (Some(heatmap), Some(time)) = (&self.heatmap, self.time_at(x, y)) else {
+            return;
+        };
+        let range = heatmap.hot_measure_at(time);
+        if range.is_some() || self.engine.loop_range().is_some() {
+            self.engine.apply(SessionEvent::Loop(range));
+        }
+    }
+
+    pub fn is_calibrating(&self) -> bool {
+        self.calibration.is_some()
+    }
+
+    pub fn start_calibration(&mut self) {
+        self.engine.pause();
+        let outputs = match self.engine.player() {
+            Some(player) => player.outputs().to_vec(),
+            None => vec![],
+        };
+        self.calibration = Some(calibration::Calibration::new(outputs, self.engine.now()));
+    }
+
+    pub fn finish_calibration(&mut self, accept: bool) {
+        let Some(calibration) = self.calibration.take() else {
+            return;
+        };
+        if !accept {
+            return;
+        }
+        if let Some(result) = calibration.result() {
+            self.engine.set_latency(result.offset);
+        }
     }
 
     pub fn on_screen_resize(&mut self) {
         self.recalculate(screen_width(), screen_height());
     }
 
+    /// The sheet music and the statistics only fit the keyboard along the
+    /// bottom.
+    fn layout(&self) -> Orientation {
+        if self.sheet.is_some() || self.statistics.is_some() {
+            Orientation::Vertical
+        } else {
+            self.orientation
+        }
+    }
+
+    pub fn toggle_orientation(&mut self) {
+        self.orientation = match self.orientation {
+            Orientation::Vertical => Orientation::Horizontal,
+            Orientation::Horizontal => Orientation::Vertical,
+        };
+        self.on_screen_resize();
+    }
+
+    /// Sets the keys of the player's instrument, and whether to draw only the
+    /// keys the song needs instead.
+    pub fn set_keyboard(&mut self, playable: KeyRange, fit_to_song: bool) {
+        self.playable = playable;
+        self.fit_to_song = fit_to_song;
+        self.fit_keyboard();
+    }
+
+    /// Picks the keys to draw: the song's, or the instrument's and whatever
+    /// the song has beyond them.
+    fn fit_keyboard(&mut self) {
+        let song_keys = KeyRange::of_song(self.engine.song());
+        self.keys = match song_keys {
+            Some(song_keys) if self.fit_to_song => song_keys.widened(25),
+            Some(song_keys) => self.playable.union(&song_keys).widened(0),
+            None => self.playable,
+        };
+        self.on_screen_resize();
+    }
+
+    /// Where `key` starts along the keyboard and how wide it is.
+    fn key_span(&self, key: Key) -> (f32, f32) {
+        let along = 1.
+            + (key_units(key.byte()) - key_units(self.keys.low))
+                * (self.white_piano_key_width + 3.);
+        let width = if key.is_sharp() {
+            self.black_piano_key_width
+        } else {
+            self.white_piano_key_width
+        };
+        (along, width)
+    }
+
+    /// The stretch of the timeline `width` wide at `along` the keyboard, from
+    /// song time `from` to `to` in seconds. Vertically that is in the
+    /// timeline texture, horizontally on the screen next to the keyboard.
+    fn timeline_rect(&self, along: f32, width: f32, from: f32, to: f32) -> Rect {
+        let time_offset = self.engine.time_offset();
+        let from = (from - time_offset) * self.pixels_per_second;
+        let length = (to - time_offset) * self.pixels_per_second - from;
+        match self.layout() {
+            Orientation::Vertical => Rect::new(along, from, width, length),
+            Orientation::Horizontal => {
+                Rect::new(self.white_piano_key_height + from, along, length, width)
+            }
+        }
+    }
+
+    /// How far the keyboard reaches across the timeline.
+    fn keyboard_length(&self) -> f32 {
+        match self.layout() {
+            Orientation::Vertical => screen_width(),
+            Orientation::Horizontal => screen_height(),
+        }
+    }
+
+    /// A line across the timeline at song time `time`.
+    fn draw_time_line(&self, time: f32, thickness: f32, color: Color) {
+        let rect = self.timeline_rect(0., self.keyboard_length(), time, time);
+        draw_line(
+            rect.x,
+            rect.y,
+            rect.right(),
+            rect.bottom(),
+            thickness,
+            color,
+        );
+    }
+
+    /// A line along the timeline at `along` the keyboard.
+    fn draw_key_line(&self, along: f32, color: Color) {
+        match self.layout() {
+            Orientation::Vertical => draw_line(along, 0., along, screen_height(), 1., color),
+            Orientation::Horizontal => draw_line(
+                self.white_piano_key_height,
+                along,
+                screen_width(),
+                along,
+                1.,
+                color,
+            ),
+        }
+    }
+
     fn draw_piano_keyboard(&self) {
         clear_background(GRAY);
 
-        let key_byte_offset = 21;
-
-        let num_piano_keys = 89;
-        let c1_offset = (self.white_piano_key_width + 2.) * 2. + 1.;
-        let octave_w = (self.white_piano_key_width + 3.) * 7.;
+        self.draw_keys(|key, black| {
+            if self.engine.active_piano_keys().contains(&key) {
+                RED
+            } else if !self.playable.contains(key.byte()) {
+                if black {
+                    Color::new(0.25, 0.25, 0.25, 1.)
+                } else {
+                    Color::new(0.55, 0.55, 0.55, 1.)
+                }
+            } else if black {
+                BLACK
+            } else {
+                WHITE
+            }
+        });
+    }
 
+    /// Draws the keyboard with every key in the color `key_color` picks for
+    /// it, given the key and whether it is black.
+    fn draw_keys(&self, key_color: impl Fn(Key, bool) -> Color) {
         for black_u32 in 0..2u32 {
             let black = black_u32 == 1;
-            for i in 0..num_piano_keys {
-                let key = Key::from_databyte((key_byte_offset + i) as u8).unwrap();
-                if self.is_key_white(key) == black {
+            for byte in self.keys.low..=self.keys.high {
+                let key = Key::from_databyte(byte).unwrap();
+                if keyboard::is_white(byte) == black {
                     continue;
                 }
 
-                let octave_offset = (key.octave().value() - 1) as f32 * octave_w;
-                let note_offset = self.calc_note_offset(key);
-                let color = if self.active_piano_keys.contains(&key) {
-                    RED
-                } else if black {
-                    BLACK
+                let (along, width) = self.key_span(key);
+                // black keys sit at the far end, next to the timeline
+                let (depth_from, depth) = if black {
+                    (
+                        self.white_piano_key_height - self.black_piano_key_height,
+                        self.black_piano_key_height,
+                    )
                 } else {
-                    WHITE
+                    (0., self.white_piano_key_height)
                 };
+                let color = key_color(key, black);
 
-                draw_rectangle(
-                    c1_offset + octave_offset + note_offset,
-                    if black {
-                        self.white_piano_key_height - self.black_piano_key_height
-                    } else {
-                        0.
-                    },
-                    if black {
-                        self.black_piano_key_width
-                    } else {
-                        self.white_piano_key_width
-                    },
-                    if black {
-                        self.black_piano_key_height
-                    } else {
-                        self.white_piano_key_height
-                    },
-                    color,
-                );
+                match self.layout() {
+                    Orientation::Vertical => draw_rectangle(along, depth_from, width, depth, color),
+                    Orientation::Horizontal => {
+                        draw_rectangle(depth_from, along, depth, width, color)
+                    }
+                }
             }
         }
     }
@@ -174,233 +484,406 @@ impl PianoScreen {
         }
     }
 
-    fn is_key_white(&self, key: Key) -> bool {
-        matches!(key.byte() % 12, 0 | 2 | 4 | 5 | 7 | 9 | 11)
-    }
+    fn draw_song_timeline(&self) {
+        let time_offset = self.engine.time_offset();
+        let vertical = self.layout() == Orientation::Vertical;
+
+        if vertical {
+            set_camera(&self.midi_target_cam);
+            clear_background(BLACK);
+        } else {
+            // drawn straight next to the keyboard, with the scene's camera
+            draw_rectangle(
+                self.white_piano_key_height,
+                0.,
+                screen_width(),
+                screen_height(),
+                BLACK,
+            );
+        }
 
-    fn calc_note_offset(&self, key: Key) -> f32 {
-        (self.white_piano_key_width + 3.)
-            * match key.byte() % 12 {
-                0 => 0.,    // C
-                1 => 0.75,  // C#
-                2 => 1.,    // D
-                3 => 1.75,  // D#
-                4 => 2.,    // E
-                5 => 3.,    // F
-                6 => 3.75,  // F#
-                7 => 4.,    // G
-                8 => 4.75,  // G#
-                9 => 5.,    // A
-                10 => 5.75, // A#
-                11 => 6.,   // B
-                _ => 0.,
+        for byte in (self.keys.low..=self.keys.high).filter(|key| key % 12 == 0) {
+            let (along, _) = self.key_span(Key::from_databyte(byte).unwrap());
+            self.draw_key_line(along, GRAY);
+        }
+
+        let length = self.keyboard_length();
+        if let Some(heatmap) = &self.heatmap {
+            for (from, to, heat) in heatmap.measures() {
+                if heat > 0. {
+                    let rect = self.timeline_rect(0., length, from, to);
+                    draw_rectangle(
+                        rect.x,
+                        rect.y,
+                        rect.w,
+                        rect.h,
+                        Color::new(1., 0., 0., 0.35 * heat),
+                    );
+                }
             }
-    }
+        }
 
-    fn render_inverse_text(&self, text: &str) -> Texture2D {
-        let text_size = measure_text(text, None, 16, 1.);
-        let text_render_target =
-            render_target((text_size.width) as u32, (text_size.height * 2.) as u32);
+        if let Some((from, to)) = self.engine.loop_range() {
+            let rect = self.timeline_rect(0., length, from, to);
+            draw_rectangle(
+                rect.x,
+                rect.y,
+                rect.w,
+                rect.h,
+                Color::new(0., 0.5, 1., 0.15),
+            );
+            self.draw_time_line(from, 2., SKYBLUE);
+            self.draw_time_line(to, 2., SKYBLUE);
+        }
 
-        let mut camera =
-            Camera2D::from_display_rect(Rect::new(0., 0., text_size.width, text_size.height * 2.));
-        camera.render_target = Some(text_render_target.clone());
+        // notes reach the hit line `latency` ahead of the song clock, so a key
+        // pressed as they touch it arrives right on time
+        self.draw_time_line(time_offset + self.engine.latency(), 2., WHITE);
 
-        push_camera_state();
+        let visible_length = if vertical {
+            screen_height()
+        } else {
+            screen_width()
+        };
+        let from_time = time_offset as u32 * 1_000_000;
+        let to_time =
+            from_time + (((visible_length / self.pixels_per_second) * 1_000_000.) * 1.5) as u32;
 
-        set_camera(&camera);
-        clear_background(BLACK);
-        draw_text(text, 0., 12., 16., RED);
+        let mut labels = vec![];
+        let mut fingers = vec![];
+        for chunk in self.engine.song().range(from_time, to_time) {
+            for block in chunk {
+                let (along, width) = self.key_span(block.key);
+                let rect = self.timeline_rect(
+                    along,
+                    width,
+                    block.start_time as f32 / 1_000_000.,
+                    block.stop_time.unwrap() as f32 / 1_000_000.,
+                );
 
-        set_default_camera();
-        pop_camera_state();
+                // spelled by the key signature, flats get the darker shade
+                let sharps = self.engine.song().sharps_at(block.start_time);
+                let (_, alteration) = notation::spell(block.key.byte(), sharps);
+                draw_rectangle(
+                    rect.x,
+                    rect.y,
+                    rect.w,
+                    rect.h,
+                    self.get_note_block_color(block.channel_number, alteration >= 0),
+                );
 
-        text_render_target.texture.clone()
-    }
+                // out of reach of the player's instrument
+                if !self.playable.contains(block.key.byte()) {
+                    draw_rectangle_lines(rect.x, rect.y, rect.w, rect.h, 3., RED);
+                }
 
-    fn draw_song_timeline(&mut self) {
-        set_camera(&self.midi_target_cam);
-        clear_background(BLACK);
+                if let Some(heatmap) = &self.heatmap {
+                    let heat = heatmap.note_heat(block.start_time, block.key.byte());
+                    if heat > 0. {
+                        draw_rectangle(
+                            rect.x,
+                            rect.y,
+                            rect.w,
+                            rect.h,
+                            Color::new(1., 0., 0., 0.3 + 0.6 * heat),
+                        );
+                    }
+                }
+
+                if let Some(naming) = self.labels {
+                    labels.push((
+                        self.to_screen(rect),
+                        naming.label(block.key.byte(), sharps, self.label_octaves),
+                    ));
+                }
+
+                if self.fingers
+                    && let Some(finger) = block.finger
+                {
+                    fingers.push((self.to_screen(rect), finger));
+                }
+            }
+        }
+
+        // what the player actually played, outlined over the song
+        for played in self.engine.performance().iter() {
+            let (along, width) = self.key_span(played.key);
+            let stop = played.stop.unwrap_or(time_offset);
+            let rect = self.timeline_rect(along, width, played.start, stop);
+            draw_rectangle_lines(rect.x, rect.y, rect.w, rect.h, 2., WHITE);
+        }
 
-        let c1_offset = (self.white_piano_key_width + 2.) * 2. + 1.;
-        let octave_w = (self.white_piano_key_width + 3.) * 7.;
+        set_default_camera();
 
-        for i in 0..8 {
-            draw_line(
-                c1_offset + i as f32 * octave_w,
+        if vertical {
+            draw_texture_ex(
+                &self.midi_render_target.texture,
                 0.,
-                c1_offset + i as f32 * octave_w,
-                screen_height(),
-                1.,
-                GRAY,
+                0.,
+                WHITE,
+                DrawTextureParams {
+                    dest_size: Some(vec2(
+                        screen_width() as f32,
+                        (screen_height() - self.white_piano_key_height) as f32,
+                    )),
+                    ..Default::default()
+                },
             );
         }
 
-        let from_time = self.time_offset as u32 * 1_000_000;
-        let to_time =
-            from_time + (((screen_height() / self.pixels_per_second) * 1_000_000.) * 1.5) as u32;
+        // on the end of the block that reaches the keyboard first
+        let font_size = self.label_font_size();
+        for (rect, label) in labels {
+            let (x, y) = match self.layout() {
+                Orientation::Vertical => (rect.x + 1., rect.bottom() - 3.),
+                Orientation::Horizontal => (rect.x + 2., rect.bottom() - 2.),
+            };
+            draw_text(label, x, y, font_size, BLACK);
+        }
 
-        for chunk in self.song.range(from_time, to_time) {
-            for block in chunk {
-                let octave_offset = (block.octave.value() - 1) as f32 * octave_w;
-                let note_offset = self.calc_note_offset(block.key);
+        // and on the far end, out of the way of the labels
+        for (rect, finger) in fingers {
+            let (x, y) = match self.layout() {
+                Orientation::Vertical => (rect.x + 1., rect.y + font_size * 0.8),
+                Orientation::Horizontal => (rect.right() - font_size * 0.6, rect.bottom() - 2.),
+            };
+            draw_text(finger.to_string(), x, y, font_size, BLACK);
+        }
 
-                let block_x = c1_offset + octave_offset + note_offset;
-                let block_y = ((block.start_time as f32) / 1_000_000.) * self.pixels_per_second;
-                let block_w = if block.key.is_sharp() {
-                    self.black_piano_key_width
-                } else {
-                    self.white_piano_key_width
-                };
-                let block_h = (((block.stop_time.unwrap() - block.start_time) as f32) / 1_000_000.)
-                    * self.pixels_per_second;
+        self.draw_hud();
+    }
 
-                draw_rectangle(
-                    block_x,
-                    block_y - self.time_offset_y,
-                    block_w,
-                    block_h,
-                    self.get_note_block_color(block.channel_number, !block.note.is_flat()),
-                );
+    /// Where a timeline rect ends up on the screen, the timeline texture
+    /// coming out upside down and the scene camera counting up from the
+    /// bottom.
+    fn to_screen(&self, rect: Rect) -> Rect {
+        let height = match self.layout() {
+            Orientation::Vertical => screen_height() - self.white_piano_key_height,
+            Orientation::Horizontal => screen_height(),
+        };
+        Rect::new(rect.x, height - rect.bottom(), rect.w, rect.h)
+    }
+
+    fn label_font_size(&self) -> f32 {
+        (self.white_piano_key_width * 0.8).clamp(10., 20.)
+    }
+
+    /// Sets how notes are labelled, none if `naming` is none.
+    pub fn set_labels(&mut self, naming: Option<NoteNaming>, octaves: bool) {
+        self.labels = naming;
+        self.label_octaves = octaves;
+    }
+
+    /// Goes from no labels through every naming and back.
+    pub fn cycle_labels(&mut self) {
+        self.labels = match self.labels {
+            None => Some(NoteNaming::Letter),
+            Some(NoteNaming::Letter) => Some(NoteNaming::Solfege),
+            Some(NoteNaming::Solfege) => Some(NoteNaming::German),
+            Some(NoteNaming::German) => None,
+        };
+    }
+
+    pub fn toggle_label_octaves(&mut self) {
+        self.label_octaves = !self.label_octaves;
+    }
 
-                if self.render_debug_extra {
-                    let line_y = ((block.start_time as f32) / 1_000_000.) * self.pixels_per_second;
-                    let line_xo = if block.key.is_sharp() {
-                        self.black_piano_key_width / 2.
+    pub fn toggle_fingers(&mut self) {
+        self.fingers = !self.fingers;
+    }
+
+    /// Names on the keys, spelled for the key the song is in right now.
+    fn draw_key_labels(&self) {
+        let Some(naming) = self.labels else {
+            return;
+        };
+        let time = (self.engine.time_offset() * 1_000_000.) as u32;
+        let sharps = self.engine.song().sharps_at(time);
+        let font_size = self.label_font_size();
+
+        for byte in self.keys.low..=self.keys.high {
+            let Ok(key) = Key::from_databyte(byte) else {
+                continue;
+            };
+            let black = !keyboard::is_white(byte);
+            let (along, width) = self.key_span(key);
+            let color = if black { WHITE } else { BLACK };
+            let label = naming.label(byte, sharps, self.label_octaves);
+            // near the player's end of the key, the keys being drawn with
+            // the scene camera counting up from the bottom
+            let (x, y) = match self.layout() {
+                Orientation::Vertical => {
+                    let end = if black {
+                        screen_height() - self.white_piano_key_height + self.black_piano_key_height
                     } else {
-                        self.white_piano_key_width / 2.
+                        screen_height()
                     };
-                    let line_x = c1_offset + octave_offset + note_offset + line_xo;
-                    let line_h = ((block.stop_time.unwrap() - block.start_time) as f32
-                        / 1_000_000.)
-                        * self.pixels_per_second;
-
-                    draw_line(
-                        line_x,
-                        line_y - self.time_offset_y,
-                        line_x,
-                        line_y - self.time_offset_y + line_h,
-                        2.,
-                        RED,
-                    );
-
-                    let texture_key: String = format!("{}", block.start_delta).to_string();
-                    if !self.text_texture_cache.contains_key(&texture_key) {
-                        let texttex = self.render_inverse_text(&format!(
-                            "{} / {}ms",
-                            block.start_delta,
-                            (block.start_time as f64 / 1_000.) as u32
-                        ));
-                        self.text_texture_cache
-                            .insert(texture_key.to_string(), texttex.clone());
-                    }
-                    let texttex = self.text_texture_cache.get(&texture_key).unwrap();
-                    draw_texture_ex(
-                        texttex,
-                        line_x + 5.,
-                        line_y - self.time_offset_y - 5.,
-                        WHITE,
-                        DrawTextureParams {
-                            ..Default::default()
-                        },
-                    );
+                    (along + 1., end - 6.)
                 }
-            }
+                Orientation::Horizontal => {
+                    let start = if black {
+                        self.white_piano_key_height - self.black_piano_key_height
+                    } else {
+                        0.
+                    };
+                    (
+                        start + 4.,
+                        screen_height() - along - width / 2. + font_size / 4.,
+                    )
+                }
+            };
+            draw_text(label, x, y, font_size, color);
         }
+    }
 
-        set_default_camera();
-
-        draw_texture_ex(
-            &self.midi_render_target.texture,
-            0.,
-            0.,
-            WHITE,
-            DrawTextureParams {
-                dest_size: Some(vec2(
-                    screen_width() as f32,
-                    (screen_height() - self.white_piano_key_height) as f32,
-                )),
-                ..Default::default()
-            },
+    /// Song time, mode, latency and outputs, top left.
+    fn draw_hud(&self) {
+        let x = match self.layout() {
+            Orientation::Vertical => 10.,
+            Orientation::Horizontal => self.white_piano_key_height + 10.,
+        };
+        draw_text(
+            format!(
+                "T: {}s x{:.2}",
+                self.engine.time_offset(),
+                self.engine.tempo()
+            ),
+            x,
+            40.,
+            32.,
+            RED,
         );
-
-        draw_text(&format!("T: {}s", self.time_offset), 10., 40., 32., RED);
         draw_text(
-            &format!("#pkd: {}", self.active_piano_keys.len()),
-            10.,
+            format!("#pkd: {}", self.engine.active_piano_keys().len()),
+            x,
             70.,
             32.,
             RED,
         );
         draw_text(
-            &format!("#pkdH: {}", self.active_piano_keys_history.len()),
-            10.,
+            format!("#pkdH: {}", self.engine.active_piano_keys_history().len()),
+            x,
             100.,
             32.,
             RED,
         );
 
         draw_text(
-            &format!(
-                "mode: {}",
-                match self.mode {
-                    GameMode::Play => "play",
-                    GameMode::LearnBlocking => "blocking-learn",
-                    GameMode::Unset => "unset",
-                }
-            ),
-            10.,
+            format!("mode: {}", self.engine.mode().name()),
+            x,
             130.,
             32.,
             RED,
         );
-    }
-
-    pub fn toggle_play(&mut self) {
-        self.play = !self.play;
-    }
 
-    pub fn update(&mut self, frame_time: f32) {
-        self.active_piano_keys_history.autoclean();
+        draw_text(
+            format!(
+                "latency: {:.0}ms, last hit: {}",
+                self.engine.latency() * 1_000.,
+                match self.engine.last_hit_offset() {
+                    Some(offset) => format!("{:+.0}ms", offset * 1_000.),
+                    None => "-".to_string(),
+                }
+            ),
+            x,
+            160.,
+            32.,
+            RED,
+        );
 
-        if self.mode == GameMode::LearnBlocking {
-            if (self.time_offset * 1_000_000.) as u32 > self.next_group.first().unwrap().start_time
-            {
-                // TODO: if we already know that the keys were recently pressed we might
-                // just continue without interruptions, for smoorther play
-                self.awaiting_piano_input = true;
-            }
+        if let Some(player) = self.engine.player() {
+            draw_text(format!("out: {}", player.describe()), x, 190., 32., RED);
         }
 
-        if self.play
-            && (self.mode == GameMode::Play
-                || (self.mode == GameMode::LearnBlocking && !self.awaiting_piano_input))
-        {
-            self.time_offset += frame_time;
-            self.time_offset_y += frame_time * self.pixels_per_second;
+        if self.engine.is_recording() {
+            draw_text("REC", screen_width() - 80., 40., 32., RED);
         }
+
+        self.draw_song_info();
+        self.draw_lyrics(x, 240.);
     }
 
-    pub fn on_piano_key_down(&mut self, key: Key) {
-        self.active_piano_keys.insert(key);
-        self.active_piano_keys_history.insert(key);
-        if self.awaiting_piano_input {
-            if self.active_piano_keys == self.awaiting_keys.clone().unwrap() {
-                self.awaiting_piano_input = false;
-                self.move_to_next_group();
-
-                // clear to make sure that successive piano key strokes
-                // won't be polluted with previous ones
-                self.active_piano_keys_history.clear();
-            }
+    /// Title and composer top center, the tracks and their notes down the
+    /// right, on a dark backing like the lyrics so they read over the sheet
+    /// music as well.
+    fn draw_song_info(&self) {
+        let song = self.engine.song();
+        let backed = |text: &str, x: f32, y: f32, font_size: f32, color: Color| {
+            let width = measure_text(text, None, font_size as u16, 1.).width;
+            draw_rectangle(
+                x - 6.,
+                y - font_size * 0.8,
+                width + 12.,
+                font_size * 1.1,
+                Color::new(0., 0., 0., 0.6),
+            );
+            draw_text(text, x, y, font_size, color);
+        };
+        let centered = |text: &str, y: f32, font_size: f32| {
+            let width = measure_text(text, None, font_size as u16, 1.).width;
+            backed(text, (screen_width() - width) / 2., y, font_size, WHITE);
+        };
+        if let Some(title) = song.title() {
+            centered(title, 40., 36.);
+        }
+        if let Some(composer) = song.composer() {
+            centered(composer, 70., 24.);
+        }
+
+        for (i, track) in song.tracks().iter().enumerate() {
+            let name = match (&track.name, &track.instrument) {
+                (Some(name), _) if !name.is_empty() => name.clone(),
+                (_, Some(instrument)) => instrument.clone(),
+                _ => format!("track {}", i + 1),
+            };
+            let text = format!("{}: {} notes", name, track.notes);
+            let width = measure_text(&text, None, 20, 1.).width;
+            backed(
+                &text,
+                screen_width() - width - 10.,
+                80. + i as f32 * 24.,
+                20.,
+                LIGHTGRAY,
+            );
         }
     }
 
-    pub fn on_piano_key_up(&mut self, key: Key) {
-        self.active_piano_keys.remove(&key);
+    /// The line of lyrics being sung, the syllables sung so far in orange
+    /// and the one being sung now in yellow.
+    fn draw_lyrics(&self, left: f32, y: f32) {
+        let time = (self.engine.time_offset() * 1_000_000.) as u32;
+        let Some((line, reached)) = self.engine.song().lyric_line_at(time) else {
+            return;
+        };
+        let font_size = 36.;
+        let widths: Vec<f32> = line
+            .iter()
+            .map(|lyric| measure_text(&lyric.text, None, font_size as u16, 1.).width)
+            .collect();
+        let width: f32 = widths.iter().sum();
+        let x = ((screen_width() - width) / 2.).max(left);
+
+        draw_rectangle(
+            x - 10.,
+            y - font_size,
+            width + 20.,
+            font_size * 1.4,
+            Color::new(0., 0., 0., 0.6),
+        );
+        let mut offset = x;
+        for (i, (lyric, width)) in line.iter().zip(widths).enumerate() {
+            let color = if i + 1 == reached {
+                YELLOW
+            } else if i + 1 < reached {
+                ORANGE
+            } else {
+                WHITE
+            };
+            draw_text(&lyric.text, offset, y, font_size, color);
+            offset += width;
+        }
     }
 
-    // TODO: zoom -/+ fns don't work correctly with current time_offset_y
     pub fn zoom_out(&mut self) {
         self.pixels_per_second -= 10.;
     }
@@ -412,73 +895,86 @@ impl PianoScreen {
     pub fn zoom_default(&mut self) {
         self.pixels_per_second = self.default_pixels_per_second;
     }
+}
 
-    pub fn set_mode(&mut self, mode: GameMode) {
-        if self.mode != mode {
-            self.mode = mode;
-        }
-        self.move_to_next_group();
-        if self.mode == GameMode::Play {
-            self.awaiting_piano_input = false;
-        }
-    }
-
-    fn move_to_prev_group(&mut self) {
-        self.next_group = match self.song.prev((self.time_offset * 1_000_000.) as u32) {
-            Some(v) => v.to_vec(),
-            None => vec![],
-        };
-        self.awaiting_keys = Some(
-            self.next_group
-                .iter()
-                .map(|b| b.key)
-                .collect::<HashSet<Key>>(),
-        );
-    }
-
-    fn move_to_next_group(&mut self) {
-        self.next_group = match self.song.next((self.time_offset * 1_000_000.) as u32) {
-            Some(v) => v.to_vec(),
-            None => vec![],
-        };
-        self.awaiting_keys = Some(
-            self.next_group
-                .iter()
-                .map(|b| b.key)
-                .collect::<HashSet<Key>>(),
-        );
-    }
-
-    pub fn skip_blocks(&mut self, amount: i32) {
-        self.play = false;
-
-        if amount < 0 {
-            for _ in 0..amount.abs() {
-                self.move_to_prev_group();
-            }
-        } else {
-            for _ in 0..amount {
-                self.move_to_next_group();
-            }
-        }
-        self.time_offset = self.next_group.first().unwrap().start_time as f32 / 1_000_000.;
-        self.time_offset_y = self.time_offset * self.pixels_per_second;
-    }
+/// Position of `key` in white key widths from the lowest C, black keys
+/// sitting in between.
+fn key_units(key: u8) -> f32 {
+    let units = match key % 12 {
+        0 => 0.,    // C
+        1 => 0.75,  // C#
+        2 => 1.,    // D
+        3 => 1.75,  // D#
+        4 => 2.,    // E
+        5 => 3.,    // F
+        6 => 3.75,  // F#
+        7 => 4.,    // G
+        8 => 4.75,  // G#
+        9 => 5.,    // A
+        10 => 5.75, // A#
+        11 => 6.,   // B
+        _ => 0.,
+    };
+    (key / 12) as f32 * 7. + units
 }
 
 impl Node for PianoScreen {
     fn ready(_node: RefMut<Self>) {}
 
-    fn draw(mut node: RefMut<Self>) {
+    fn draw(node: RefMut<Self>) {
+        if let Some(calibration) = &node.calibration {
+            set_default_camera();
+            calibration.draw();
+            return;
+        }
+        if let Some(picker) = &node.picker {
+            set_default_camera();
+            picker.draw();
+            return;
+        }
+        if let Some(progress) = &node.progress {
+            set_default_camera();
+            progress.draw();
+            return;
+        }
+        if let Some(statistics) = &node.statistics {
+            node.draw_weak_keys(statistics);
+            set_default_camera();
+            statistics.draw();
+            return;
+        }
+
         node.draw_piano_keyboard();
+        if let Some(sheet) = &node.sheet {
+            set_default_camera();
+            sheet.draw(
+                node.engine.time_offset(),
+                node.engine.latency(),
+                node.pixels_per_second,
+                screen_height() - node.white_piano_key_height,
+            );
+            node.draw_hud();
+            node.draw_key_labels();
+            return;
+        }
         node.draw_song_timeline();
+        node.draw_key_labels();
     }
 
     fn update(mut node: RefMut<Self>) {
-        if is_key_pressed(KeyCode::Space) {
-            node.toggle_play();
+        if is_key_pressed(KeyCode::Space) && !node.is_calibrating() && !node.is_picking() {
+            node.apply(SessionEvent::TogglePlay);
         }
 
-        node.update(get_frame_time());
+        let now = node.engine.now();
+        if let Some(calibration) = node.calibration.as_mut() {
+            calibration.update(now);
+            return;
+        }
+        node.engine.update();
+        let node = &mut *node;
+        if let Some(sheet) = node.sheet.as_mut() {
+            sheet.update_marks(&node.engine);
+        }
     }
 }
@@ -0,0 +1,298 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::engine::GameMode;
+
+/// Everything that changes the course of a practice session. Replaying
+/// these in order on the same song reproduces it.
+#[derive(Clone, Debug)]
+pub enum SessionEvent {
+    /// A raw message from the MIDI input, with its input timestamp.
+    Midi(u64, Vec<u8>),
+    TogglePlay,
+    Mode(GameMode),
+    Seek(i32),
+    Reset,
+    /// Song speed, 1 being the written tempo.
+    Tempo(f32),
+    /// Song time span to play over and over, in seconds, or none to stop.
+    Loop(Option<(f32, f32)>),
+    /// Input latency in seconds, as calibrated.
+    Latency(f32),
+}
+
+impl SessionEvent {
+    fn to_line(&self) -> String {
+        match self {
+            SessionEvent::Midi(stamp, message) => {
+                let bytes: Vec<String> = message.iter().map(|b| format!("{:02x}", b)).collect();
+                format!("midi {} {}", stamp, bytes.join(""))
+            }
+            SessionEvent::TogglePlay => "toggle-play".to_string(),
+            SessionEvent::Mode(mode) => format!("mode {}", mode.name()),
+            SessionEvent::Seek(amount) => format!("seek {}", amount),
+            SessionEvent::Reset => "reset".to_string(),
+            SessionEvent::Tempo(tempo) => format!("tempo {}", tempo),
+            SessionEvent::Loop(Some((from, to))) => format!("loop {} {}", from, to),
+            SessionEvent::Loop(None) => "loop off".to_string(),
+            SessionEvent::Latency(latency) => format!("latency {}", latency),
+        }
+    }
+
+    fn from_words(words: &[&str]) -> Option<Self> {
+        match words {
+            ["midi", stamp, hex] => {
+                let message = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                    .collect::<Option<Vec<u8>>>()?;
+                Some(SessionEvent::Midi(stamp.parse().ok()?, message))
+            }
+            ["toggle-play"] => Some(SessionEvent::TogglePlay),
+            ["mode", name] => Some(SessionEvent::Mode(GameMode::from_name(name)?)),
+            ["seek", amount] => Some(SessionEvent::Seek(amount.parse().ok()?)),
+            ["reset"] => Some(SessionEvent::Reset),
+            ["tempo", tempo] => Some(SessionEvent::Tempo(tempo.parse().ok()?)),
+            ["loop", "off"] => Some(SessionEvent::Loop(None)),
+            ["loop", from, to] => Some(SessionEvent::Loop(Some((
+                from.parse().ok()?,
+                to.parse().ok()?,
+            )))),
+            ["latency", latency] => Some(SessionEvent::Latency(latency.parse().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+/// What grading a session depends on besides its events, so a replay on
+/// another machine grades the same.
+#[derive(Clone, Debug)]
+pub struct SessionSettings {
+    /// Input latency in seconds when the session started.
+    pub latency: f32,
+    /// Number of keys on the instrument.
+    pub keys: u32,
+    pub fold_octaves: bool,
+}
+
+impl SessionSettings {
+    fn to_line(&self) -> String {
+        format!(
+            "settings latency {} keys {} fold-octaves {}",
+            self.latency, self.keys, self.fold_octaves
+        )
+    }
+
+    fn from_words(words: &[&str]) -> Option<Self> {
+        match words {
+            [
+                "settings",
+                "latency",
+                latency,
+                "keys",
+                keys,
+                "fold-octaves",
+                fold_octaves,
+            ] => Some(SessionSettings {
+                latency: latency.parse().ok()?,
+                keys: keys.parse().ok()?,
+                fold_octaves: fold_octaves.parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub struct LoggedEvent {
+    /// Microseconds since the session started.
+    pub time: u64,
+    pub event: SessionEvent,
+}
+
+/// Appends session events to a text file, one per line:
+/// `<time> <song time> <event> [args]`, after a line with the settings.
+pub struct SessionLog {
+    writer: BufWriter<File>,
+    started: Instant,
+}
+
+impl SessionLog {
+    pub fn create(path: &Path, song_path: &Path, settings: &SessionSettings) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "# pianotrainer session {}", song_path.display())?;
+        writeln!(writer, "{}", settings.to_line())?;
+        Ok(Self {
+            writer,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn log(&mut self, song_time: f32, event: &SessionEvent) {
+        let result = writeln!(
+            self.writer,
+            "{} {} {}",
+            self.started.elapsed().as_micros(),
+            (song_time * 1_000_000.) as u64,
+            event.to_line()
+        )
+        .and_then(|_| self.writer.flush());
+        if let Err(why) = result {
+            println!("could not write session log: {}", why);
+        }
+    }
+}
+
+/// Where a new session log goes: `<data dir>/sessions/<seconds since epoch>.log`.
+pub fn new_log_path() -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    config::data_dir()
+        .join("sessions")
+        .join(format!("{}.log", now))
+}
+
+fn parse_line(words: &[&str]) -> Option<LoggedEvent> {
+    match words {
+        // the song time column is there for whoever reads the log
+        [time, _song_time, rest @ ..] => Some(LoggedEvent {
+            time: time.parse().ok()?,
+            event: SessionEvent::from_words(rest)?,
+        }),
+        _ => None,
+    }
+}
+
+/// A session log read back: its settings, missing from older logs, and
+/// its events.
+pub struct LoggedSession {
+    pub settings: Option<SessionSettings>,
+    pub events: Vec<LoggedEvent>,
+}
+
+pub fn read_log(path: &Path) -> Result<LoggedSession, Box<dyn Error>> {
+    let content = fs::read_to_string(path)
+        .map_err(|why| format!("could not read {}: {}", path.display(), why))?;
+
+    let mut settings = None;
+    let mut events = vec![];
+    for (line_number, line) in content.lines().enumerate() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.first() == Some(&"settings") {
+            settings = SessionSettings::from_words(&words);
+            if settings.is_none() {
+                return Err(format!("{}:{}: bad settings", path.display(), line_number + 1).into());
+            }
+            continue;
+        }
+        match parse_line(&words) {
+            Some(event) => events.push(event),
+            None => {
+                return Err(format!("{}:{}: bad event", path.display(), line_number + 1).into());
+            }
+        }
+    }
+    Ok(LoggedSession { settings, events })
+}
+
+/// Hands out logged events once as much time has passed since the replay
+/// started as had passed in the original session.
+pub struct Replay {
+    events: Vec<LoggedEvent>,
+    next: usize,
+    started: Instant,
+}
+
+impl Replay {
+    pub fn new(events: Vec<LoggedEvent>) -> Self {
+        Self {
+            events,
+            next: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn due_events(&mut self) -> Vec<SessionEvent> {
+        let elapsed = self.started.elapsed().as_micros() as u64;
+        let mut due = vec![];
+        while self.next < self.events.len() && self.events[self.next].time <= elapsed {
+            due.push(self.events[self.next].event.clone());
+            self.next += 1;
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("piano1-{}-{}.log", name, std::process::id()))
+    }
+
+    #[test]
+    fn logged_sessions_read_back() {
+        let path = temp_path("round-trip");
+        let settings = SessionSettings {
+            latency: 0.025,
+            keys: 61,
+            fold_octaves: true,
+        };
+        let events = [
+            SessionEvent::Mode(GameMode::LearnBlocking),
+            SessionEvent::TogglePlay,
+            SessionEvent::Midi(1_234, vec![0x90, 60, 100]),
+            SessionEvent::Seek(-2),
+            SessionEvent::Tempo(0.75),
+            SessionEvent::Loop(Some((1.5, 3.))),
+            SessionEvent::Loop(None),
+            SessionEvent::Latency(0.04),
+            SessionEvent::Reset,
+        ];
+        let mut log = SessionLog::create(&path, Path::new("song.mid"), &settings).unwrap();
+        for event in events.iter() {
+            log.log(1., event);
+        }
+        drop(log);
+
+        let session = read_log(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let read_settings = session.settings.unwrap();
+        assert_eq!(read_settings.latency, 0.025);
+        assert_eq!(read_settings.keys, 61);
+        assert!(read_settings.fold_octaves);
+        let lines: Vec<String> = session.events.iter().map(|e| e.event.to_line()).collect();
+        let expected: Vec<String> = events.iter().map(SessionEvent::to_line).collect();
+        assert_eq!(lines, expected);
+        assert!(session.events.windows(2).all(|w| w[0].time <= w[1].time));
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        let path = temp_path("malformed");
+        fs::write(
+            &path,
+            "# pianotrainer session song.mid\n0 0 toggle-play\n10 0 midi 5 9g3c\n",
+        )
+        .unwrap();
+        let error = read_log(&path).err().unwrap().to_string();
+        assert!(error.ends_with(":3: bad event"), "{}", error);
+
+        fs::write(&path, "settings latency soon keys 88 fold-octaves false\n").unwrap();
+        let error = read_log(&path).err().unwrap().to_string();
+        fs::remove_file(&path).unwrap();
+        assert!(error.ends_with(":1: bad settings"), "{}", error);
+    }
+}
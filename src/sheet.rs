@@ -0,0 +1,688 @@
+use std::collections::HashMap;
+
+use macroquad::prelude::*;
+
+use crate::engine::Engine;
+use crate::grading::{HIT_WINDOW, Verdict};
+use crate::notation::{self, Accidental, NoteValue, Score, ScoreMeasure, ScoreNote, Staff};
+
+const HEADER_WIDTH: f32 = 140.;
+const INK: Color = BLACK;
+const PAPER: Color = Color::new(0.96, 0.94, 0.88, 1.);
+const HIT_COLOR: Color = Color::new(0., 0.6, 0., 1.);
+const MISS_COLOR: Color = RED;
+/// Staff positions of the sharps and flats of a key signature on the treble
+/// staff, in the order they are written. The bass staff has them two
+/// octaves lower.
+const SHARP_STEPS: [i32; 7] = [10, 7, 11, 8, 5, 9, 6];
+const FLAT_STEPS: [i32; 7] = [6, 9, 5, 8, 4, 7, 3];
+/// Seconds of song time regraded at most this often while nothing is
+/// played, so misses show up as the song passes them.
+const REGRADE_EVERY: f32 = 0.2;
+
+/// The song as scrolling grand-staff notation, with a cursor at the song
+/// time and the notes played so far colored by how they went.
+pub struct SheetView {
+    score: Score,
+    /// Hit (true) or missed (false) song notes by start time and key.
+    marks: HashMap<(u32, u8), bool>,
+    /// Song time and key of every wrong note played.
+    wrong: Vec<(f32, u8)>,
+    graded_notes: usize,
+    graded_at: f32,
+}
+
+/// One glyph of a note: the whole of it, or one of the values a length no
+/// single value has is split into.
+struct Piece<'a> {
+    note: &'a ScoreNote,
+    /// The first piece carries the note's accidental.
+    first: bool,
+    /// Tied to the piece after it, or over the bar line.
+    tied: bool,
+    /// Where the piece ends, and a tie from it with it.
+    end_x: f32,
+}
+
+/// Pieces of one staff starting together with the same value, sharing a
+/// stem.
+struct Chord<'a> {
+    x: f32,
+    /// Sixteenths from the start of the measure.
+    position: u32,
+    value: NoteValue,
+    dotted: bool,
+    /// Beat of the measure the chord starts on, chords on the same beat get
+    /// beamed together.
+    beat: u32,
+    pieces: Vec<Piece<'a>>,
+}
+
+impl<'a> Chord<'a> {
+    fn first(&self) -> &'a ScoreNote {
+        self.pieces[0].note
+    }
+
+    fn lowest(&self) -> i32 {
+        self.pieces.iter().map(|p| p.note.step).min().unwrap_or(0)
+    }
+
+    fn highest(&self) -> i32 {
+        self.pieces.iter().map(|p| p.note.step).max().unwrap_or(0)
+    }
+}
+
+fn middle_step(staff: Staff) -> i32 {
+    match staff {
+        Staff::Treble => 6,
+        Staff::Bass => -6,
+    }
+}
+
+impl SheetView {
+    pub fn new(engine: &Engine) -> Self {
+        Self {
+            score: Score::of(engine.song()),
+            marks: HashMap::new(),
+            wrong: vec![],
+            graded_notes: usize::MAX,
+            graded_at: 0.,
+        }
+    }
+
+    /// Regrades what was played when something new was played or the song
+    /// moved on.
+    pub fn update_marks(&mut self, engine: &Engine) {
+        let time_offset = engine.time_offset();
+        if engine.performance().len() == self.graded_notes
+            && (time_offset - self.graded_at).abs() < REGRADE_EVERY
+        {
+            return;
+        }
+        self.graded_notes = engine.performance().len();
+        self.graded_at = time_offset;
+
+        self.marks.clear();
+        self.wrong.clear();
+        for judgment in engine.grade_all().judgments {
+            match (judgment.verdict, judgment.note) {
+                (Verdict::Hit(_), Some(note)) => {
+                    self.marks.insert((note.start_time, note.key.byte()), true);
+                }
+                // not missed until it can't be hit anymore
+                (Verdict::Miss, Some(note)) if judgment.time < time_offset - HIT_WINDOW => {
+                    self.marks.insert((note.start_time, note.key.byte()), false);
+                }
+                (Verdict::Wrong, _) => self.wrong.push((judgment.time, judgment.key.byte())),
+                _ => (),
+            }
+        }
+    }
+
+    /// Draws the grand staff over the top `height` pixels of the screen,
+    /// `pixels_per_second` wide, with the cursor where notes are due to be
+    /// played at `time_offset`.
+    pub fn draw(&self, time_offset: f32, latency: f32, pixels_per_second: f32, height: f32) {
+        let space = (height / 36.).clamp(6., 14.);
+        let middle_c_y = height * 0.6;
+        let y_of = |step: i32| middle_c_y - step as f32 * space / 2.;
+        let cursor_x = HEADER_WIDTH + 60.;
+        let x_of = |time: f32| cursor_x + (time - time_offset - latency) * pixels_per_second;
+
+        draw_rectangle(0., 0., screen_width(), height, PAPER);
+
+        let staves = [Staff::Treble, Staff::Bass];
+        for staff in staves {
+            for line in 0..5 {
+                let y = y_of(middle_step(staff) - 4 + line * 2);
+                draw_line(0., y, screen_width(), y, 1., INK);
+            }
+        }
+        let top = y_of(10);
+        let bottom = y_of(-10);
+
+        // bar lines and notes, left of the header they scroll under it
+        let visible = |x: f32| x > HEADER_WIDTH - 40. && x < screen_width() + 40.;
+        for (i, measure) in self.score.measures.iter().enumerate() {
+            let x = x_of(measure.time as f32 / 1_000_000.);
+            if visible(x) {
+                draw_line(x, top, x, bottom, 1., INK);
+                draw_text(
+                    measure.number.to_string(),
+                    x + 2.,
+                    top - space * 2.,
+                    space * 1.6,
+                    DARKGRAY,
+                );
+                let mut signature_x = x + space;
+                if let Some(previous) = i.checked_sub(1).map(|i| &self.score.measures[i]) {
+                    if previous.sharps != measure.sharps {
+                        signature_x += draw_key_change(
+                            signature_x,
+                            previous.sharps,
+                            measure.sharps,
+                            &y_of,
+                            space,
+                        );
+                    }
+                    if (previous.numerator, previous.denominator)
+                        != (measure.numerator, measure.denominator)
+                    {
+                        self.draw_time_signature(
+                            signature_x,
+                            measure.numerator,
+                            measure.denominator,
+                            &y_of,
+                            space,
+                        );
+                    }
+                }
+            }
+            let end_x = x_of(measure.end_time as f32 / 1_000_000.);
+            if end_x < HEADER_WIDTH - 40. || x > screen_width() + 40. {
+                continue;
+            }
+
+            let x_at = |position: u32| x_of(measure.time_at(position) as f32 / 1_000_000.);
+            for staff in staves {
+                let chords = chords_of(measure, staff, &x_at, &x_of);
+                let chords: Vec<Chord> = chords.into_iter().filter(|c| visible(c.x)).collect();
+                self.draw_chords(staff, &chords, &y_of, space);
+                self.draw_ties(staff, &chords, &y_of, space);
+                draw_rests(measure, staff, &x_at, &y_of, space, &visible);
+            }
+        }
+
+        for (time, key) in self.wrong.iter() {
+            let x = x_of(*time);
+            if !visible(x) {
+                continue;
+            }
+            let sharps = self.score.sharps_at((*time * 1_000_000.) as u32);
+            let (letter, _) = notation::spell(*key, sharps);
+            let step = notation::step_of(letter, *key as i32 / 12 - 1);
+            let staff = if *key >= 60 {
+                Staff::Treble
+            } else {
+                Staff::Bass
+            };
+            draw_ledger_lines(staff, x, step, &y_of, space);
+            draw_ellipse_lines(
+                x,
+                y_of(step),
+                space * 0.65,
+                space * 0.45,
+                -20.,
+                2.,
+                MISS_COLOR,
+            );
+        }
+
+        // the header stays put, covering the notes that went by
+        draw_rectangle(0., 0., HEADER_WIDTH, height, PAPER);
+        for staff in staves {
+            for line in 0..5 {
+                let y = y_of(middle_step(staff) - 4 + line * 2);
+                draw_line(0., y, HEADER_WIDTH, y, 1., INK);
+            }
+        }
+        draw_line(8., top, 8., bottom, 2., INK);
+        // no music font to draw real clefs with, their letters stand in
+        draw_text("G", 14., y_of(4) + space * 1.5, space * 5.5, INK);
+        draw_text("F", 14., y_of(-4) + space * 1.6, space * 4.5, INK);
+        draw_circle(14. + space * 2.6, y_of(-3), space * 0.2, INK);
+        draw_circle(14. + space * 2.6, y_of(-5), space * 0.2, INK);
+
+        // the key and meter at the cursor
+        let now = ((time_offset + latency).max(0.) * 1_000_000.) as u32;
+        let width = draw_key_signature(14. + space * 3.5, self.score.sharps_at(now), &y_of, space);
+        let index = self.score.measures.partition_point(|m| m.time <= now);
+        if let Some(current) = self.score.measures.get(index.saturating_sub(1)) {
+            let x = 14. + space * 3.8 + width;
+            self.draw_time_signature(x, current.numerator, current.denominator, &y_of, space);
+        }
+
+        draw_line(
+            cursor_x,
+            top - space * 3.,
+            cursor_x,
+            bottom + space * 3.,
+            2.,
+            BLUE,
+        );
+    }
+
+    fn draw_time_signature(
+        &self,
+        x: f32,
+        numerator: u8,
+        denominator: u8,
+        y_of: &impl Fn(i32) -> f32,
+        space: f32,
+    ) {
+        for staff in [Staff::Treble, Staff::Bass] {
+            let middle = middle_step(staff);
+            draw_text(numerator.to_string(), x, y_of(middle), space * 2.8, INK);
+            draw_text(
+                denominator.to_string(),
+                x,
+                y_of(middle - 4),
+                space * 2.8,
+                INK,
+            );
+        }
+    }
+
+    fn note_color(&self, note: &ScoreNote) -> Color {
+        match self.marks.get(&(note.start_time, note.key)) {
+            Some(true) => HIT_COLOR,
+            Some(false) => MISS_COLOR,
+            None => INK,
+        }
+    }
+
+    /// Draws the chords of one staff in a measure, beaming the eighths and
+    /// shorter that share a beat.
+    fn draw_chords(&self, staff: Staff, chords: &[Chord], y_of: &impl Fn(i32) -> f32, space: f32) {
+        let stem_length = space * 3.5;
+        let head_width = space * 0.65;
+
+        let mut i = 0;
+        while i < chords.len() {
+            // chords beamed with this one
+            let mut end = i + 1;
+            if chords[i].value.flags() > 0 {
+                while end < chords.len()
+                    && chords[end].value.flags() > 0
+                    && chords[end].beat == chords[i].beat
+                {
+                    end += 1;
+                }
+            }
+            let group = &chords[i..end];
+
+            let total: i32 = group.iter().map(|c| c.lowest() + c.highest()).sum();
+            let up = total < middle_step(staff) * 2 * group.len() as i32;
+            let stem_x = |chord: &Chord| {
+                if up {
+                    chord.x + head_width - 1.
+                } else {
+                    chord.x - head_width + 1.
+                }
+            };
+            let beam_y = if up {
+                group
+                    .iter()
+                    .map(|c| y_of(c.highest()))
+                    .fold(f32::MAX, f32::min)
+                    - stem_length
+            } else {
+                group
+                    .iter()
+                    .map(|c| y_of(c.lowest()))
+                    .fold(f32::MIN, f32::max)
+                    + stem_length
+            };
+
+            for chord in group {
+                for piece in chord.pieces.iter() {
+                    self.draw_note(staff, chord, piece, y_of, space);
+                }
+                let value = chord.value;
+                if !value.has_stem() {
+                    continue;
+                }
+                let color = self.note_color(chord.first());
+                let from = if up {
+                    y_of(chord.lowest())
+                } else {
+                    y_of(chord.highest())
+                };
+                let to = if group.len() > 1 {
+                    beam_y
+                } else if up {
+                    y_of(chord.highest()) - stem_length
+                } else {
+                    y_of(chord.lowest()) + stem_length
+                };
+                let x = stem_x(chord);
+                draw_line(x, from, x, to, 1.5, color);
+
+                if group.len() == 1 {
+                    for flag in 0..value.flags() {
+                        let y = to + flag as f32 * space * 0.8 * if up { 1. } else { -1. };
+                        let end_y = y + space * 1.5 * if up { 1. } else { -1. };
+                        draw_line(x, y, x + space, end_y, 2., color);
+                    }
+                }
+            }
+
+            if group.len() > 1 {
+                let direction = if up { 1. } else { -1. };
+                let first_x = stem_x(&group[0]);
+                let last_x = stem_x(&group[group.len() - 1]);
+                draw_line(first_x, beam_y, last_x, beam_y, space * 0.5, INK);
+                // a second beam between neighbouring sixteenths
+                for pair in group.windows(2) {
+                    if pair[0].value.flags() > 1 && pair[1].value.flags() > 1 {
+                        let y = beam_y + direction * space * 0.8;
+                        draw_line(stem_x(&pair[0]), y, stem_x(&pair[1]), y, space * 0.5, INK);
+                    }
+                }
+            }
+
+            i = end;
+        }
+    }
+
+    /// Arcs from tied pieces to where they end, on the side of the head
+    /// away from the middle of the staff.
+    fn draw_ties(&self, staff: Staff, chords: &[Chord], y_of: &impl Fn(i32) -> f32, space: f32) {
+        const SEGMENTS: usize = 8;
+        for chord in chords {
+            for piece in chord.pieces.iter().filter(|piece| piece.tied) {
+                let from_x = chord.x + space * 0.7;
+                let to_x = piece.end_x - space * 0.7;
+                if to_x <= from_x {
+                    continue;
+                }
+                let direction = if piece.note.step >= middle_step(staff) {
+                    -1.
+                } else {
+                    1.
+                };
+                let base_y = y_of(piece.note.step) + direction * space * 0.6;
+                let height = space * 0.8;
+                let point = |t: f32| {
+                    (
+                        from_x + (to_x - from_x) * t,
+                        base_y + direction * height * 4. * t * (1. - t),
+                    )
+                };
+                for segment in 0..SEGMENTS {
+                    let (x1, y1) = point(segment as f32 / SEGMENTS as f32);
+                    let (x2, y2) = point((segment + 1) as f32 / SEGMENTS as f32);
+                    draw_line(x1, y1, x2, y2, 1.5, self.note_color(piece.note));
+                }
+            }
+        }
+    }
+
+    fn draw_note(
+        &self,
+        staff: Staff,
+        chord: &Chord,
+        piece: &Piece,
+        y_of: &impl Fn(i32) -> f32,
+        space: f32,
+    ) {
+        let note = piece.note;
+        let x = chord.x;
+        let y = y_of(note.step);
+        let color = self.note_color(note);
+        draw_ledger_lines(staff, x, note.step, y_of, space);
+
+        if chord.value.is_filled() {
+            draw_ellipse(x, y, space * 0.65, space * 0.45, -20., color);
+        } else {
+            draw_ellipse_lines(x, y, space * 0.65, space * 0.45, -20., 2., color);
+        }
+        if chord.dotted {
+            draw_circle(x + space * 1.2, y - space * 0.2, space * 0.15, color);
+        }
+        if piece.first
+            && let Some(accidental) = note.accidental
+        {
+            let symbol = match accidental {
+                Accidental::Sharp => "#",
+                Accidental::Flat => "b",
+                Accidental::Natural => "n",
+            };
+            draw_text(symbol, x - space * 2.2, y + space * 0.5, space * 2., color);
+        }
+    }
+}
+
+/// Short lines for notes above or below their staff, and for middle C.
+fn draw_ledger_lines(staff: Staff, x: f32, step: i32, y_of: &impl Fn(i32) -> f32, space: f32) {
+    let middle = middle_step(staff);
+    let (above, below) = (middle + 6, middle - 6);
+    let ledger = |s: i32| {
+        draw_line(x - space, y_of(s), x + space, y_of(s), 1., INK);
+    };
+    let mut s = above;
+    while s <= step {
+        ledger(s);
+        s += 2;
+    }
+    let mut s = below;
+    while s >= step {
+        ledger(s);
+        s -= 2;
+    }
+}
+
+/// The notes of one staff in a measure as chords, each note split into the
+/// values that add up to its length. Pieces go where their sixteenth falls,
+/// the first one where the note was played.
+fn chords_of<'a>(
+    measure: &'a ScoreMeasure,
+    staff: Staff,
+    x_at: &impl Fn(u32) -> f32,
+    x_of: &impl Fn(f32) -> f32,
+) -> Vec<Chord<'a>> {
+    let mut glyphs = vec![];
+    for note in measure.notes.iter().filter(|n| n.staff == staff) {
+        let pieces = note.pieces();
+        let mut position = note.position;
+        for (i, (value, dotted)) in pieces.iter().enumerate() {
+            let end = position + value.sixteenths_dotted(*dotted);
+            let x = if i == 0 {
+                x_of(note.start_time as f32 / 1_000_000.)
+            } else {
+                x_at(position)
+            };
+            let piece = Piece {
+                note,
+                first: i == 0,
+                tied: i + 1 < pieces.len() || note.tie_start,
+                end_x: x_at(end),
+            };
+            glyphs.push((position, *value, *dotted, x, piece));
+            position = end;
+        }
+    }
+    glyphs.sort_by_key(|(position, value, dotted, _, piece)| {
+        (*position, value.sixteenths_dotted(*dotted), piece.note.key)
+    });
+
+    let mut chords: Vec<Chord> = vec![];
+    for (position, value, dotted, x, piece) in glyphs {
+        match chords.last_mut() {
+            Some(chord)
+                if chord.position == position && (chord.value, chord.dotted) == (value, dotted) =>
+            {
+                chord.pieces.push(piece)
+            }
+            _ => chords.push(Chord {
+                x,
+                position,
+                value,
+                dotted,
+                beat: measure.beat_at(position),
+                pieces: vec![piece],
+            }),
+        }
+    }
+    chords
+}
+
+/// Rests where a staff has nothing sounding in a measure, a whole rest when
+/// it has nothing at all.
+fn draw_rests(
+    measure: &ScoreMeasure,
+    staff: Staff,
+    x_at: &impl Fn(u32) -> f32,
+    y_of: &impl Fn(i32) -> f32,
+    space: f32,
+    visible: &impl Fn(f32) -> bool,
+) {
+    let end = measure.sixteenths();
+    let mut sounding = vec![false; end as usize];
+    for note in measure.notes.iter().filter(|n| n.staff == staff) {
+        for position in note.position.min(end)..(note.position + note.length).min(end) {
+            sounding[position as usize] = true;
+        }
+    }
+
+    if sounding.iter().all(|s| !s) {
+        let x = (x_at(0) + x_at(end)) / 2.;
+        if visible(x) {
+            draw_rest(staff, x, NoteValue::Whole, false, y_of, space);
+        }
+        return;
+    }
+
+    let mut position = 0;
+    while position < end {
+        if sounding[position as usize] {
+            position += 1;
+            continue;
+        }
+        let gap = sounding[position as usize..]
+            .iter()
+            .take_while(|s| !**s)
+            .count() as u32;
+        for (value, dotted) in NoteValue::split(gap) {
+            let x = x_at(position) + space;
+            if visible(x) {
+                draw_rest(staff, x, value, dotted, y_of, space);
+            }
+            position += value.sixteenths_dotted(dotted);
+        }
+    }
+}
+
+/// No music font to draw rests with, so they are drawn as shapes close
+/// enough to be told apart.
+fn draw_rest(
+    staff: Staff,
+    x: f32,
+    value: NoteValue,
+    dotted: bool,
+    y_of: &impl Fn(i32) -> f32,
+    space: f32,
+) {
+    let middle = y_of(middle_step(staff));
+    match value {
+        // hanging from the fourth line
+        NoteValue::Whole => draw_rectangle(
+            x - space * 0.6,
+            middle - space,
+            space * 1.2,
+            space * 0.5,
+            INK,
+        ),
+        // sitting on the middle line
+        NoteValue::Half => draw_rectangle(
+            x - space * 0.6,
+            middle - space * 0.5,
+            space * 1.2,
+            space * 0.5,
+            INK,
+        ),
+        NoteValue::Quarter => {
+            let points = [
+                (x - space * 0.3, middle - space * 1.5),
+                (x + space * 0.3, middle - space * 0.6),
+                (x - space * 0.3, middle + space * 0.2),
+                (x + space * 0.3, middle + space * 1.),
+                (x - space * 0.2, middle + space * 1.5),
+            ];
+            for pair in points.windows(2) {
+                draw_line(pair[0].0, pair[0].1, pair[1].0, pair[1].1, 2., INK);
+            }
+        }
+        NoteValue::Eighth | NoteValue::Sixteenth => {
+            draw_line(
+                x + space * 0.5,
+                middle - space,
+                x - space * 0.1,
+                middle + space * 1.5,
+                1.5,
+                INK,
+            );
+            for flag in 0..value.flags() {
+                let y = middle - space * 0.7 + flag as f32 * space;
+                draw_circle(x - space * 0.3, y, space * 0.25, INK);
+                draw_line(
+                    x - space * 0.3,
+                    y,
+                    x + space * 0.5 - flag as f32 * space * 0.25,
+                    y - space * 0.2,
+                    1.5,
+                    INK,
+                );
+            }
+        }
+    }
+    if dotted {
+        draw_circle(x + space, middle - space * 0.5, space * 0.15, INK);
+    }
+}
+
+/// Draws the sharps or flats of a key signature on both staves from `x`,
+/// returning how wide they are.
+fn draw_key_signature(x: f32, sharps: i8, y_of: &impl Fn(i32) -> f32, space: f32) -> f32 {
+    let (symbol, steps) = if sharps >= 0 {
+        ("#", SHARP_STEPS)
+    } else {
+        ("b", FLAT_STEPS)
+    };
+    draw_accidentals(
+        x,
+        symbol,
+        &steps[..notation::key_signature_letters(sharps).len()],
+        y_of,
+        space,
+    )
+}
+
+/// A key signature written in the middle of the music, naturals cancelling
+/// the old one when the new key has no sharps or flats.
+fn draw_key_change(x: f32, from: i8, to: i8, y_of: &impl Fn(i32) -> f32, space: f32) -> f32 {
+    if to != 0 {
+        return draw_key_signature(x, to, y_of, space);
+    }
+    let steps = if from >= 0 { SHARP_STEPS } else { FLAT_STEPS };
+    draw_accidentals(
+        x,
+        "n",
+        &steps[..notation::key_signature_letters(from).len()],
+        y_of,
+        space,
+    )
+}
+
+fn draw_accidentals(
+    x: f32,
+    symbol: &str,
+    steps: &[i32],
+    y_of: &impl Fn(i32) -> f32,
+    space: f32,
+) -> f32 {
+    for (i, step) in steps.iter().enumerate() {
+        let x = x + i as f32 * space * 0.9;
+        // the bass staff has them two octaves lower
+        for offset in [0, -14] {
+            draw_text(
+                symbol,
+                x,
+                y_of(step + offset) + space * 0.5,
+                space * 2.,
+                INK,
+            );
+        }
+    }
+    steps.len() as f32 * space * 0.9
+}
@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+pub const META_COPYRIGHT: u8 = 0x02;
+pub const META_TRACK_NAME: u8 = 0x03;
+pub const META_LYRIC: u8 = 0x05;
+pub const META_MARKER: u8 = 0x06;
+pub const META_END_OF_TRACK: u8 = 0x2F;
+pub const META_TEMPO: u8 = 0x51;
+pub const META_TIME_SIGNATURE: u8 = 0x58;
+pub const META_KEY_SIGNATURE: u8 = 0x59;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// A single track holding everything.
+    SingleTrack,
+    /// Several tracks played at the same time, the first one usually
+    /// carrying only tempo and other meta events.
+    MultiTrack,
+}
+
+/// One track of a Standard MIDI File, with events kept at absolute ticks
+/// until the file is written.
+#[derive(Clone, Default)]
+pub struct Track {
+    events: Vec<(u32, Vec<u8>)>,
+}
+
+impl Track {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a channel message, as it would be sent to a port.
+    pub fn push(&mut self, tick: u32, message: &[u8]) {
+        self.events.push((tick, message.to_vec()));
+    }
+
+    pub fn push_meta(&mut self, tick: u32, meta_type: u8, data: &[u8]) {
+        let mut event = vec![0xFF, meta_type];
+        write_varlen(&mut event, data.len() as u32);
+        event.extend_from_slice(data);
+        self.events.push((tick, event));
+    }
+
+    pub fn push_tempo(&mut self, tick: u32, micros_per_quarter_note: u32) {
+        self.push_meta(
+            tick,
+            META_TEMPO,
+            &micros_per_quarter_note.to_be_bytes()[1..],
+        );
+    }
+
+    fn merge(&mut self, other: &Track) {
+        self.events.extend(other.events.iter().cloned());
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut events = self.events.clone();
+        // stable, so events on the same tick keep the order they were pushed in
+        events.sort_by_key(|(tick, _)| *tick);
+
+        let mut data = vec![];
+        let mut last_tick = 0;
+        for (tick, event) in events.iter() {
+            if event.len() >= 2 && event[0] == 0xFF && event[1] == META_END_OF_TRACK {
+                continue;
+            }
+            write_varlen(&mut data, tick - last_tick);
+            data.extend_from_slice(event);
+            last_tick = *tick;
+        }
+        write_varlen(&mut data, 0);
+        data.extend_from_slice(&[0xFF, META_END_OF_TRACK, 0x00]);
+
+        let mut chunk = b"MTrk".to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        chunk.extend_from_slice(&data);
+        chunk
+    }
+}
+
+fn write_varlen(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push(((value & 0x7F) as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    out.extend_from_slice(&bytes);
+}
+
+/// Writes the tracks as a Standard MIDI File. With `Format::SingleTrack`
+/// the tracks are merged into one.
+pub fn write(
+    path: &Path,
+    format: Format,
+    ticks_per_quarter_note: u16,
+    tracks: &[Track],
+) -> io::Result<()> {
+    let tracks = match format {
+        Format::SingleTrack => {
+            let mut merged = Track::new();
+            for track in tracks {
+                merged.merge(track);
+            }
+            vec![merged]
+        }
+        Format::MultiTrack => tracks.to_vec(),
+    };
+
+    let mut bytes = b"MThd".to_vec();
+    bytes.extend_from_slice(&6u32.to_be_bytes());
+    bytes.extend_from_slice(
+        &match format {
+            Format::SingleTrack => 0u16,
+            Format::MultiTrack => 1u16,
+        }
+        .to_be_bytes(),
+    );
+    bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(&ticks_per_quarter_note.to_be_bytes());
+    for track in tracks.iter() {
+        bytes.extend_from_slice(&track.to_bytes());
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(&bytes)
+}
@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
@@ -7,16 +8,48 @@ use std::path::Path;
 use midix::prelude::MetaMessage::*;
 use midix::prelude::*;
 
+use crate::abc;
+use crate::keyboard::KeyRange;
+use crate::musicxml;
+use crate::smf;
+
+const MIDDLE_C: u8 = 60;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub enum Hand {
+    Left,
+    Right,
+}
+
+impl Hand {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hand::Left => "left",
+            Hand::Right => "right",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Hand::Left, Hand::Right]
+            .into_iter()
+            .find(|hand| hand.name() == name)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct NoteBlock {
     pub octave: Octave,
     pub note: Note,
     pub key: Key,
+    pub velocity: u8,
+    pub hand: Hand,
     pub start_delta: u32,
     pub stop_delta: Option<u32>,
     pub start_time: u32,
     pub stop_time: Option<u32>,
     pub channel_number: u32,
+    /// 1 for the thumb to 5 for the little finger.
+    pub finger: Option<u8>,
 }
 
 impl fmt::Display for NoteBlock {
@@ -29,14 +62,214 @@ impl fmt::Display for NoteBlock {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct ProgramChange {
+    pub delta: u32,
+    pub time: u32,
+    pub channel_number: u32,
+    pub program: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct ControlChange {
+    pub delta: u32,
+    pub time: u32,
+    pub channel_number: u32,
+    pub controller: u8,
+    pub value: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct TempoChange {
+    pub delta: u32,
+    pub time: u32,
+    pub micros_per_quarter_note: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct TimeSignatureChange {
+    pub delta: u32,
+    pub time: u32,
+    pub numerator: u8,
+    pub denominator: u8,
+    pub clocks_per_click: u8,
+    pub notated_32nds_per_quarter: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct KeySignatureChange {
+    pub delta: u32,
+    pub time: u32,
+    /// Sharps when positive, flats when negative.
+    pub sharps: i8,
+    pub minor: bool,
+}
+
+impl KeySignatureChange {
+    pub fn name(&self) -> String {
+        const MAJOR: [&str; 15] = [
+            "Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#",
+        ];
+        const MINOR: [&str; 15] = [
+            "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#", "G#", "D#", "A#",
+        ];
+        let index = (self.sharps.clamp(-7, 7) + 7) as usize;
+        if self.minor {
+            format!("{} minor", MINOR[index])
+        } else {
+            format!("{} major", MAJOR[index])
+        }
+    }
+}
+
+/// Time signature of a measure `ticks` long that the meter doesn't fit,
+/// like a pickup: counted in the meter's beats when it is a whole number of
+/// them, otherwise in halves, quarters and so on of them. None when the
+/// measure is as long as the meter.
+pub fn odd_measure_signature(
+    ticks: u32,
+    ticks_per_quarter_note: u32,
+    meter: (u8, u8),
+) -> Option<(u8, u8)> {
+    let whole_note = ticks_per_quarter_note * 4;
+    let mut denominator = meter.1.max(1) as u32;
+    if ticks == 0 || ticks * denominator == whole_note * meter.0 as u32 {
+        return None;
+    }
+    while denominator <= 64 && whole_note.is_multiple_of(denominator) {
+        let beat = whole_note / denominator;
+        if ticks.is_multiple_of(beat) {
+            let numerator = u8::try_from(ticks / beat).ok()?;
+            return Some((numerator, denominator as u8));
+        }
+        denominator *= 2;
+    }
+    None
+}
+
+/// Where a measure starts and the time signature it is in.
+#[derive(Clone, Debug)]
+pub struct Measure {
+    pub number: u32,
+    pub delta: u32,
+    pub time: u32,
+    pub numerator: u8,
+    pub denominator: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct Marker {
+    pub delta: u32,
+    pub time: u32,
+    pub text: String,
+}
+
+/// A track of the file, or a part or voice of a score.
+#[derive(Clone, Debug, Default)]
+pub struct Track {
+    pub name: Option<String>,
+    pub instrument: Option<String>,
+    pub notes: usize,
+}
+
+/// A syllable of the lyrics, sung from `time` on.
+#[derive(Clone, Debug)]
+pub struct Lyric {
+    pub delta: u32,
+    pub time: u32,
+    pub text: String,
+    /// Starts a new line of the lyrics.
+    pub new_line: bool,
+}
+
+/// Karaoke files start lines with `/` and paragraphs with `\`, lyric events
+/// end them with a carriage return or a line feed instead.
+fn push_lyric(lyrics: &mut Vec<Lyric>, delta: u32, text: &str, line_ended: &mut bool) {
+    let new_line = *line_ended || text.starts_with(['/', '\\']);
+    *line_ended = text.ends_with(['\r', '\n']);
+    let text = text
+        .trim_start_matches(['/', '\\'])
+        .trim_end_matches(['\r', '\n']);
+    if text.is_empty() {
+        // a line break of its own carries over to the next syllable
+        *line_ended |= new_line;
+        return;
+    }
+    lyrics.push(Lyric {
+        delta,
+        time: 0,
+        text: text.to_string(),
+        new_line,
+    });
+}
+
+struct TempoMap {
+    ticks_per_quarter_note: u32,
+    tempo_changes: Vec<TempoChange>,
+}
+
+impl TempoMap {
+    /// Microseconds from the start of the song to `delta` ticks.
+    fn time_at(&self, delta: u32) -> u32 {
+        let mut time: u64 = 0;
+        let mut last_delta = 0;
+        let mut micros_per_quarter_note = meta::Tempo::default().micros_per_quarter_note() as u64;
+        for tc in self.tempo_changes.iter() {
+            if tc.delta >= delta {
+                break;
+            }
+            time += (tc.delta - last_delta) as u64 * micros_per_quarter_note
+                / self.ticks_per_quarter_note as u64;
+            last_delta = tc.delta;
+            micros_per_quarter_note = tc.micros_per_quarter_note as u64;
+        }
+        time += (delta - last_delta) as u64 * micros_per_quarter_note
+            / self.ticks_per_quarter_note as u64;
+        time as u32
+    }
+}
+
 pub struct Channel {
-    current_delta: u32,
-    current_time: u32,
     note_blocks: Vec<NoteBlock>,
 }
 
 pub struct Song {
     note_blocks: Vec<Vec<NoteBlock>>,
+    program_changes: Vec<ProgramChange>,
+    control_changes: Vec<ControlChange>,
+    ticks_per_quarter_note: u16,
+    tempo_changes: Vec<TempoChange>,
+    time_signatures: Vec<TimeSignatureChange>,
+    markers: Vec<Marker>,
+    key_signatures: Vec<KeySignatureChange>,
+    measure_numbers: Vec<(u32, u32)>,
+    lyrics: Vec<Lyric>,
+    title: Option<String>,
+    composer: Option<String>,
+    copyright: Option<String>,
+    tracks: Vec<Track>,
+}
+
+/// What a song is made of, at the ticks things happen at, for
+/// `Song::from_parts` to work out the times of. Notes come with their hands.
+#[derive(Default)]
+pub struct SongParts {
+    pub ticks_per_quarter_note: u16,
+    pub note_blocks: Vec<NoteBlock>,
+    pub program_changes: Vec<ProgramChange>,
+    pub control_changes: Vec<ControlChange>,
+    pub tempo_changes: Vec<TempoChange>,
+    pub time_signatures: Vec<TimeSignatureChange>,
+    pub markers: Vec<Marker>,
+    pub key_signatures: Vec<KeySignatureChange>,
+    /// Ticks of the measures whose number doesn't follow on from the
+    /// measure before, as with a pickup measure numbered 0, and their number.
+    pub measure_numbers: Vec<(u32, u32)>,
+    pub lyrics: Vec<Lyric>,
+    pub title: Option<String>,
+    pub composer: Option<String>,
+    pub copyright: Option<String>,
+    pub tracks: Vec<Track>,
 }
 
 impl Song {
@@ -72,8 +305,207 @@ impl Song {
 
         match (from_ix, to_ix) {
             (Some(from), Some(to)) => &self.note_blocks[from..to],
-            _ => &[]
+            _ => &[],
+        }
+    }
+
+    pub fn note_blocks(&self) -> impl Iterator<Item = &NoteBlock> {
+        self.note_blocks.iter().flatten()
+    }
+
+    /// Note blocks grouped by start time, in start order.
+    pub fn note_groups(&self) -> &[Vec<NoteBlock>] {
+        &self.note_blocks
+    }
+
+    pub fn note_blocks_mut(&mut self) -> impl Iterator<Item = &mut NoteBlock> {
+        self.note_blocks.iter_mut().flatten()
+    }
+
+    /// Time at which the last note stops sounding.
+    pub fn duration(&self) -> u32 {
+        self.note_blocks()
+            .map(|b| b.stop_time.unwrap_or(b.start_time))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Every measure up to the end of the song. A time signature change
+    /// always starts a new measure.
+    pub fn measures(&self) -> Vec<Measure> {
+        let tempo_map = TempoMap {
+            ticks_per_quarter_note: self.ticks_per_quarter_note as u32,
+            tempo_changes: self.tempo_changes.clone(),
+        };
+        let duration = self.duration();
+
+        let mut measures: Vec<Measure> = vec![];
+        let mut delta = 0;
+        let mut signature = (4, 4);
+        let mut next_signature = 0;
+        let mut number = 1;
+        loop {
+            while let Some(ts) = self.time_signatures.get(next_signature)
+                && ts.delta <= delta
+            {
+                signature = (ts.numerator, ts.denominator);
+                next_signature += 1;
+            }
+
+            let time = tempo_map.time_at(delta);
+            if time > duration && !measures.is_empty() {
+                break;
+            }
+            if let Some((_, renumbered)) = self.measure_numbers.iter().find(|(d, _)| *d == delta) {
+                number = *renumbered;
+            }
+            measures.push(Measure {
+                number,
+                delta,
+                time,
+                numerator: signature.0,
+                denominator: signature.1,
+            });
+            number += 1;
+
+            let measure_ticks = (self.ticks_per_quarter_note as u32 * 4 * signature.0 as u32
+                / signature.1.max(1) as u32)
+                .max(1);
+            delta = match self.time_signatures.get(next_signature) {
+                Some(ts) if ts.delta < delta + measure_ticks => ts.delta,
+                _ => delta + measure_ticks,
+            };
+        }
+        measures
+    }
+
+    /// Microseconds from the start of the song to `delta` ticks.
+    pub fn time_at(&self, delta: u32) -> u32 {
+        TempoMap {
+            ticks_per_quarter_note: self.ticks_per_quarter_note as u32,
+            tempo_changes: self.tempo_changes.clone(),
+        }
+        .time_at(delta)
+    }
+
+    /// Times at which the measures start, up to the end of the song.
+    pub fn measure_starts(&self) -> Vec<u32> {
+        self.measures().iter().map(|m| m.time).collect()
+    }
+
+    /// How many notes fall outside `range`.
+    pub fn notes_outside(&self, range: &KeyRange) -> usize {
+        self.note_blocks()
+            .filter(|b| !range.contains(b.key.byte()))
+            .count()
+    }
+
+    /// Moves the notes outside `range` by octaves until they fit, for
+    /// keyboards too small for the song. Returns how many notes moved.
+    pub fn fold_into(&mut self, range: &KeyRange) -> usize {
+        if range.high - range.low < 11 {
+            return 0;
+        }
+        let mut folded = 0;
+        for block in self.note_blocks.iter_mut().flatten() {
+            let mut key = block.key.byte();
+            if range.contains(key) {
+                continue;
+            }
+            while key < range.low {
+                key += 12;
+            }
+            while key > range.high {
+                key -= 12;
+            }
+            if let Ok(new_key) = Key::from_databyte(key) {
+                block.key = new_key;
+                block.note = new_key.note();
+                block.octave = new_key.octave();
+                folded += 1;
+            }
+        }
+        folded
+    }
+
+    pub fn note_count(&self) -> usize {
+        self.note_blocks().count()
+    }
+
+    /// Name of the first track, where sequencers put the song title, or the
+    /// karaoke title.
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Who wrote the song, as scores and karaoke files name them, or the
+    /// copyright notice, which is where MIDI files credit the composer.
+    pub fn composer(&self) -> Option<&str> {
+        self.composer.as_deref().or(self.copyright())
+    }
+
+    pub fn copyright(&self) -> Option<&str> {
+        self.copyright.as_deref()
+    }
+
+    /// The tracks with notes in them. The first track's name is the
+    /// sequence name, which is the title, so it has none of its own.
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    pub fn ticks_per_quarter_note(&self) -> u16 {
+        self.ticks_per_quarter_note
+    }
+
+    pub fn tempo_changes(&self) -> &[TempoChange] {
+        &self.tempo_changes
+    }
+
+    pub fn key_signatures(&self) -> &[KeySignatureChange] {
+        &self.key_signatures
+    }
+
+    /// The key signature in effect at `time`, the first one before it.
+    pub fn key_signature_at(&self, time: u32) -> Option<&KeySignatureChange> {
+        self.key_signatures
+            .iter()
+            .rev()
+            .find(|ks| ks.time <= time)
+            .or(self.key_signatures.first())
+    }
+
+    /// Sharps (flats when negative) of the key signature in effect at
+    /// `time`.
+    pub fn sharps_at(&self, time: u32) -> i8 {
+        self.key_signature_at(time).map_or(0, |ks| ks.sharps)
+    }
+
+    /// The line of lyrics being sung at `time` and how many of its
+    /// syllables have been reached, the first line before the song starts.
+    pub fn lyric_line_at(&self, time: u32) -> Option<(&[Lyric], usize)> {
+        if self.lyrics.is_empty() {
+            return None;
         }
+        let reached = self.lyrics.partition_point(|l| l.time <= time);
+        let last = reached.saturating_sub(1);
+        let start = self.lyrics[1..=last]
+            .iter()
+            .rposition(|l| l.new_line)
+            .map_or(0, |p| p + 1);
+        let end = self.lyrics[last + 1..]
+            .iter()
+            .position(|l| l.new_line)
+            .map_or(self.lyrics.len(), |p| last + 1 + p);
+        Some((&self.lyrics[start..end], reached.saturating_sub(start)))
+    }
+
+    pub fn program_changes(&self) -> &[ProgramChange] {
+        &self.program_changes
+    }
+
+    pub fn control_changes(&self) -> &[ControlChange] {
+        &self.control_changes
     }
 
     fn time_offset_to_index(&self, from_time: u32) -> i32 {
@@ -93,7 +525,7 @@ impl Song {
     pub fn next(&self, from_time: u32) -> Option<&[NoteBlock]> {
         let index = self.time_offset_to_index(from_time);
 
-        if index > 0 {
+        if index >= 0 {
             Some(&self.note_blocks[index as usize])
         } else {
             None
@@ -102,42 +534,66 @@ impl Song {
 
     pub fn prev(&self, from_time: u32) -> Option<&[NoteBlock]> {
         let index = std::cmp::max(0, self.time_offset_to_index(from_time) - 2);
-        Some(&self.note_blocks[index as usize])
+        self.note_blocks.get(index as usize).map(Vec::as_slice)
     }
 
-    pub fn load(path: &Path) -> Self {
+    /// Reads a MIDI file, or a MusicXML score or ABC tune going by the
+    /// extension.
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if musicxml::is_musicxml(path) {
+            return musicxml::read(path);
+        }
+        if abc::is_abc(path) {
+            return abc::read(path);
+        }
+
         let display = path.display();
 
-        let mut file = match File::open(path) {
-            Err(why) => panic!("could not open {}: {}", display, why),
-            Ok(file) => file,
-        };
+        let mut file =
+            File::open(path).map_err(|why| format!("could not open {}: {}", display, why))?;
 
-        let mut cur_us_per_quarter_note = meta::Tempo::default().micros_per_quarter_note();
         let mut ticks_per_quarter_note = 48;
+        let mut track_delta = 0;
 
         let mut channels: HashMap<u32, Channel> = HashMap::new();
+        let mut program_changes = vec![];
+        let mut control_changes = vec![];
+        let mut tempo_changes = vec![];
+        let mut time_signatures = vec![];
+        let mut markers = vec![];
+        let mut key_signatures = vec![];
+        let mut title = None;
+        let mut karaoke_titles = vec![];
+        let mut karaoke = false;
+        let mut lyrics = vec![];
+        let mut lyrics_line_ended = false;
+        let mut karaoke_text = vec![];
+        let mut karaoke_line_ended = false;
+        let mut copyright = None;
+        let mut tracks: Vec<Track> = vec![];
 
         let mut buf: Vec<u8> = vec![];
 
-        if let Err(why) = file.read_to_end(&mut buf) {
-            panic!("error reading from file {}: {}", display, why);
-        }
+        file.read_to_end(&mut buf)
+            .map_err(|why| format!("error reading from file {}: {}", display, why))?;
 
         let mut reader = Reader::from_byte_slice(&buf);
 
         loop {
             match reader.read_event() {
-                Err(why) => panic!("failed to process event from midi: {}", why),
+                Err(why) => {
+                    return Err(format!("failed to process event from {}: {}", display, why).into());
+                }
                 Ok(FileEvent::Header(header)) => {
                     ticks_per_quarter_note =
                         header.timing().ticks_per_quarter_note().unwrap_or(48) as u32
                 }
-                Ok(FileEvent::Track(_)) => {}
+                Ok(FileEvent::Track(_)) => {
+                    track_delta = 0;
+                    tracks.push(Track::default());
+                }
                 Ok(FileEvent::TrackEvent(track_event)) => {
-                    let dt = track_event.delta_ticks();
-                    let dt2 = dt
-                        * ((cur_us_per_quarter_note as f32 / ticks_per_quarter_note as f32) as u32);
+                    track_delta += track_event.delta_ticks();
 
                     match track_event.event() {
                         TrackMessage::ChannelVoice(cv) => {
@@ -145,41 +601,58 @@ impl Song {
 
                             let channel_obj =
                                 &mut channels.entry(channel as u32).or_insert(Channel {
-                                    current_delta: 0,
-                                    current_time: 0,
                                     note_blocks: vec![],
                                 });
-                            channel_obj.current_delta += dt;
-                            channel_obj.current_time += dt2;
 
                             match cv.event() {
-                                VoiceEvent::NoteOn { key, .. } => {
+                                VoiceEvent::NoteOn { key, velocity } if velocity.byte() > 0 => {
+                                    if let Some(track) = tracks.last_mut() {
+                                        track.notes += 1;
+                                    }
                                     channel_obj.note_blocks.push(NoteBlock {
                                         octave: key.octave(),
                                         note: key.note(),
-                                        key: key.clone(),
-                                        start_delta: channel_obj.current_delta,
+                                        key: *key,
+                                        velocity: velocity.byte(),
+                                        hand: Hand::Right,
+                                        start_delta: track_delta,
                                         stop_delta: None,
-                                        start_time: channel_obj.current_time,
+                                        start_time: 0,
                                         stop_time: None,
                                         channel_number: channel as u32,
+                                        finger: None,
                                     });
-                                    // NoteOn w/ velocity=0 is NoteOff?
                                 }
-                                VoiceEvent::NoteOff { key, .. } => {
+                                // NoteOn w/ velocity=0 is NoteOff
+                                VoiceEvent::NoteOn { key, .. }
+                                | VoiceEvent::NoteOff { key, .. } => {
                                     for block in channel_obj.note_blocks.iter_mut().rev() {
                                         if block.stop_delta.is_none()
                                             && block.octave == key.octave()
                                             && block.note == key.note()
                                         {
-                                            block.stop_delta = Some(channel_obj.current_delta);
-                                            block.stop_time = Some(channel_obj.current_time);
+                                            block.stop_delta = Some(track_delta);
                                         }
                                     }
                                 }
                                 VoiceEvent::Aftertouch { .. } => {}
-                                VoiceEvent::ControlChange { .. } => (),
-                                VoiceEvent::ProgramChange { .. } => (),
+                                VoiceEvent::ControlChange { controller, value } => {
+                                    control_changes.push(ControlChange {
+                                        delta: track_delta,
+                                        time: 0,
+                                        channel_number: channel as u32,
+                                        controller: controller.byte(),
+                                        value: value.value(),
+                                    });
+                                }
+                                VoiceEvent::ProgramChange { program } => {
+                                    program_changes.push(ProgramChange {
+                                        delta: track_delta,
+                                        time: 0,
+                                        channel_number: channel as u32,
+                                        program: program.byte(),
+                                    });
+                                }
                                 VoiceEvent::ChannelPressureAfterTouch { .. } => (),
                                 VoiceEvent::PitchBend { .. } => (),
                             }
@@ -187,16 +660,84 @@ impl Song {
                         TrackMessage::SystemExclusive(_) => {}
                         TrackMessage::Meta(meta_event) => match meta_event {
                             Tempo(tempo_event) => {
-                                cur_us_per_quarter_note = tempo_event.micros_per_quarter_note();
+                                tempo_changes.push(TempoChange {
+                                    delta: track_delta,
+                                    time: 0,
+                                    micros_per_quarter_note: tempo_event.micros_per_quarter_note(),
+                                });
                             }
                             TimeSignature(time_signature_event) => {
-                                println!(
-                                    "Time signature = num={} den={} cpc={} 32s={}",
-                                    time_signature_event.num(),
-                                    time_signature_event.den(),
-                                    time_signature_event.clocks_per_click(),
-                                    time_signature_event.notated_32nds_per_24_clocks()
-                                );
+                                // a denominator past 2^7 doesn't fit a byte and
+                                // can't be a real meter, so it is left out
+                                let Some(denominator) =
+                                    1u8.checked_shl(time_signature_event.den() as u32)
+                                else {
+                                    continue;
+                                };
+                                time_signatures.push(TimeSignatureChange {
+                                    delta: track_delta,
+                                    time: 0,
+                                    numerator: time_signature_event.num(),
+                                    denominator,
+                                    clocks_per_click: time_signature_event.clocks_per_click(),
+                                    notated_32nds_per_quarter: time_signature_event
+                                        .notated_32nds_per_24_clocks(),
+                                });
+                            }
+                            KeySignature(key_signature) => {
+                                key_signatures.push(KeySignatureChange {
+                                    delta: track_delta,
+                                    time: 0,
+                                    sharps: key_signature.sharp_flat_count(),
+                                    minor: key_signature.minor_key(),
+                                });
+                            }
+                            TrackName(text) if tracks.len() == 1 => {
+                                title.get_or_insert_with(|| text.as_str().trim().to_string());
+                            }
+                            TrackName(text) => {
+                                if let Some(track) = tracks.last_mut() {
+                                    track
+                                        .name
+                                        .get_or_insert_with(|| text.as_str().trim().to_string());
+                                }
+                            }
+                            InstrumentName(text) => {
+                                if let Some(track) = tracks.last_mut() {
+                                    track
+                                        .instrument
+                                        .get_or_insert_with(|| text.as_str().trim().to_string());
+                                }
+                            }
+                            Copyright(text) => {
+                                copyright.get_or_insert_with(|| text.as_str().trim().to_string());
+                            }
+                            // karaoke files put their title and artist in
+                            // "@T" text events
+                            Text(text) if text.as_str().starts_with("@T") => {
+                                karaoke = true;
+                                karaoke_titles.push(text.as_str()[2..].trim().to_string());
+                            }
+                            // the rest of the "@" texts say what the file is
+                            Text(text) if text.as_str().starts_with('@') => karaoke = true,
+                            Text(text) => push_lyric(
+                                &mut karaoke_text,
+                                track_delta,
+                                text.as_str(),
+                                &mut karaoke_line_ended,
+                            ),
+                            Lyric(text) => push_lyric(
+                                &mut lyrics,
+                                track_delta,
+                                text.as_str(),
+                                &mut lyrics_line_ended,
+                            ),
+                            Marker(text) => {
+                                markers.push(Marker {
+                                    delta: track_delta,
+                                    time: 0,
+                                    text: text.as_str().to_string(),
+                                });
                             }
                             _ => (),
                         },
@@ -207,20 +748,367 @@ impl Song {
             }
         }
 
-        let mut groups = vec![];
+        let right_hand_channel = Self::guess_right_hand_channel(&channels);
+        let mut note_blocks = vec![];
+        for (channel_number, ch) in channels.into_iter() {
+            note_blocks.extend(ch.note_blocks.into_iter().map(|mut block| {
+                block.hand = match right_hand_channel {
+                    Some(right) if right == channel_number => Hand::Right,
+                    Some(_) => Hand::Left,
+                    None if block.key.byte() >= MIDDLE_C => Hand::Right,
+                    None => Hand::Left,
+                };
+                block
+            }));
+        }
 
-        for ch in channels.values() {
-            groups.extend(ch.note_blocks.to_vec());
+        let title = title
+            .filter(|title| !title.is_empty())
+            .or(karaoke_titles.first().cloned());
+        // and the artist in the next one
+        let composer = karaoke_titles.get(1).cloned();
+        tracks.retain(|track| track.notes > 0);
+        // other files use text events for comments, karaoke files for the
+        // words when they have no lyric events
+        if lyrics.is_empty() && karaoke {
+            lyrics = karaoke_text;
         }
 
-        groups.sort_by(|a, b| a.start_delta.partial_cmp(&b.start_delta).unwrap());
-        let chunk_by = groups.chunk_by(|a, b| a.start_delta == b.start_delta);
+        Ok(Self::from_parts(SongParts {
+            ticks_per_quarter_note: ticks_per_quarter_note as u16,
+            note_blocks,
+            program_changes,
+            control_changes,
+            tempo_changes,
+            time_signatures,
+            markers,
+            key_signatures,
+            measure_numbers: vec![],
+            lyrics,
+            title,
+            composer,
+            copyright,
+            tracks,
+        }))
+    }
+
+    /// Works out when everything in `parts` happens and groups the notes
+    /// starting together. Tempo usually lives in the first track only, but
+    /// applies to all of them, so times can only be worked out once
+    /// everything is read.
+    pub fn from_parts(parts: SongParts) -> Self {
+        let SongParts {
+            ticks_per_quarter_note,
+            mut note_blocks,
+            mut program_changes,
+            mut control_changes,
+            mut tempo_changes,
+            mut time_signatures,
+            mut markers,
+            mut key_signatures,
+            mut measure_numbers,
+            mut lyrics,
+            title,
+            composer,
+            copyright,
+            tracks,
+        } = parts;
+
+        tempo_changes.sort_by_key(|tc: &TempoChange| tc.delta);
+        let tempo_map = TempoMap {
+            ticks_per_quarter_note: ticks_per_quarter_note as u32,
+            tempo_changes: tempo_changes.clone(),
+        };
+        for tc in tempo_changes.iter_mut() {
+            tc.time = tempo_map.time_at(tc.delta);
+        }
+        for block in note_blocks.iter_mut() {
+            block.start_time = tempo_map.time_at(block.start_delta);
+            block.stop_time = block.stop_delta.map(|delta| tempo_map.time_at(delta));
+        }
+        for pc in program_changes.iter_mut() {
+            pc.time = tempo_map.time_at(pc.delta);
+        }
+        for cc in control_changes.iter_mut() {
+            cc.time = tempo_map.time_at(cc.delta);
+        }
+        for ts in time_signatures.iter_mut() {
+            ts.time = tempo_map.time_at(ts.delta);
+        }
+        for marker in markers.iter_mut() {
+            marker.time = tempo_map.time_at(marker.delta);
+        }
+        for ks in key_signatures.iter_mut() {
+            ks.time = tempo_map.time_at(ks.delta);
+        }
+        for lyric in lyrics.iter_mut() {
+            lyric.time = tempo_map.time_at(lyric.delta);
+        }
+
+        program_changes.sort_by_key(|pc| pc.delta);
+        control_changes.sort_by_key(|cc| cc.delta);
+        time_signatures.sort_by_key(|ts| ts.delta);
+        markers.sort_by_key(|marker| marker.delta);
+        key_signatures.sort_by_key(|ks| ks.delta);
+        measure_numbers.sort_by_key(|(delta, _)| *delta);
+        lyrics.sort_by_key(|lyric| lyric.delta);
+
+        note_blocks.sort_by(|a, b| a.start_delta.partial_cmp(&b.start_delta).unwrap());
+        let chunk_by = note_blocks.chunk_by(|a, b| a.start_delta == b.start_delta);
 
         Song {
             note_blocks: chunk_by
                 .map(|x| x.to_vec())
                 .filter(|v| !v.is_empty())
                 .collect(),
+            program_changes,
+            control_changes,
+            ticks_per_quarter_note,
+            tempo_changes,
+            time_signatures,
+            markers,
+            key_signatures,
+            measure_numbers,
+            lyrics,
+            title,
+            composer,
+            copyright,
+            tracks,
+        }
+    }
+
+    /// Writes the song as a type 1 Standard MIDI File: tempo, time
+    /// signatures, markers and lyrics in the first track, then one track per
+    /// channel. Everything is written at the ticks it was read at, so a
+    /// loaded file comes back with the same timings.
+    pub fn write_midi(&self, path: &Path) -> std::io::Result<()> {
+        let mut conductor = smf::Track::new();
+        if let Some(title) = &self.title {
+            conductor.push_meta(0, smf::META_TRACK_NAME, title.as_bytes());
+        }
+        if let Some(copyright) = &self.copyright {
+            conductor.push_meta(0, smf::META_COPYRIGHT, copyright.as_bytes());
+        }
+        for ks in self.key_signatures.iter() {
+            conductor.push_meta(
+                ks.delta,
+                smf::META_KEY_SIGNATURE,
+                &[ks.sharps as u8, ks.minor as u8],
+            );
+        }
+        for tc in self.tempo_changes.iter() {
+            conductor.push_tempo(tc.delta, tc.micros_per_quarter_note);
+        }
+        for ts in self.time_signatures.iter() {
+            conductor.push_meta(
+                ts.delta,
+                smf::META_TIME_SIGNATURE,
+                &[
+                    ts.numerator,
+                    ts.denominator.trailing_zeros() as u8,
+                    ts.clocks_per_click,
+                    ts.notated_32nds_per_quarter,
+                ],
+            );
+        }
+        for marker in self.markers.iter() {
+            conductor.push_meta(marker.delta, smf::META_MARKER, marker.text.as_bytes());
+        }
+        for (i, lyric) in self.lyrics.iter().enumerate() {
+            let mut text = lyric.text.clone();
+            if self.lyrics.get(i + 1).is_some_and(|next| next.new_line) {
+                text.push('\r');
+            }
+            conductor.push_meta(lyric.delta, smf::META_LYRIC, text.as_bytes());
+        }
+
+        // (tick, order within the tick, message); releases go before program
+        // and controller changes, which go before new notes
+        let mut channels: BTreeMap<u32, Vec<(u32, u8, Vec<u8>)>> = BTreeMap::new();
+        for block in self.note_blocks() {
+            let channel = block.channel_number as u8;
+            let events = channels.entry(block.channel_number).or_default();
+            events.push((
+                block.start_delta,
+                3,
+                vec![0x90 | channel, block.key.byte(), block.velocity],
+            ));
+            if let Some(stop_delta) = block.stop_delta {
+                events.push((stop_delta, 0, vec![0x80 | channel, block.key.byte(), 0]));
+            }
+        }
+        for pc in self.program_changes.iter() {
+            channels.entry(pc.channel_number).or_default().push((
+                pc.delta,
+                1,
+                vec![0xC0 | pc.channel_number as u8, pc.program],
+            ));
+        }
+        for cc in self.control_changes.iter() {
+            channels.entry(cc.channel_number).or_default().push((
+                cc.delta,
+                2,
+                vec![0xB0 | cc.channel_number as u8, cc.controller, cc.value],
+            ));
+        }
+
+        let mut tracks = vec![conductor];
+        for (_, mut events) in channels {
+            events.sort_by_key(|(tick, order, _)| (*tick, *order));
+            let mut track = smf::Track::new();
+            for (tick, _, message) in events {
+                track.push(tick, &message);
+            }
+            tracks.push(track);
+        }
+
+        smf::write(
+            path,
+            smf::Format::MultiTrack,
+            self.ticks_per_quarter_note,
+            &tracks,
+        )
+    }
+
+    /// Piano files usually keep each hand on its own channel, the right one
+    /// being the higher-pitched. With a single channel there's nothing to
+    /// guess from, so hands get split at middle C instead.
+    fn guess_right_hand_channel(channels: &HashMap<u32, Channel>) -> Option<u32> {
+        let mut best = None;
+        let mut best_avg = 0.;
+        for (channel_number, ch) in channels.iter() {
+            if ch.note_blocks.is_empty() {
+                continue;
+            }
+            let avg = ch
+                .note_blocks
+                .iter()
+                .map(|b| b.key.byte() as f32)
+                .sum::<f32>()
+                / ch.note_blocks.len() as f32;
+            if best.is_none() || avg > best_avg {
+                best = Some(*channel_number);
+                best_avg = avg;
+            }
+        }
+
+        if channels
+            .values()
+            .filter(|ch| !ch.note_blocks.is_empty())
+            .count()
+            > 1
+        {
+            best
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub const TICKS_PER_QUARTER_NOTE: u16 = 480;
+
+    /// A right hand note on the first channel, `start` to `stop` in ticks.
+    pub fn note(key: u8, start: u32, stop: u32) -> NoteBlock {
+        let key = Key::from_databyte(key).unwrap();
+        NoteBlock {
+            octave: key.octave(),
+            note: key.note(),
+            key,
+            velocity: 80,
+            hand: Hand::Right,
+            start_delta: start,
+            stop_delta: Some(stop),
+            start_time: 0,
+            stop_time: None,
+            channel_number: 0,
+            finger: None,
+        }
+    }
+
+    /// A song of `notes` in 4/4, with tempo changes as (tick, microseconds
+    /// per quarter note).
+    pub fn song(notes: Vec<NoteBlock>, tempos: &[(u32, u32)]) -> Song {
+        Song::from_parts(parts(notes, tempos))
+    }
+
+    /// The parts of `song`, for tests that need more than notes and tempo.
+    pub fn parts(notes: Vec<NoteBlock>, tempos: &[(u32, u32)]) -> SongParts {
+        SongParts {
+            ticks_per_quarter_note: TICKS_PER_QUARTER_NOTE,
+            note_blocks: notes,
+            tempo_changes: tempos
+                .iter()
+                .map(|(delta, micros_per_quarter_note)| TempoChange {
+                    delta: *delta,
+                    time: 0,
+                    micros_per_quarter_note: *micros_per_quarter_note,
+                })
+                .collect(),
+            time_signatures: vec![TimeSignatureChange {
+                delta: 0,
+                time: 0,
+                numerator: 4,
+                denominator: 4,
+                clocks_per_click: 24,
+                notated_32nds_per_quarter: 8,
+            }],
+            ..Default::default()
         }
     }
+
+    fn timings(song: &Song) -> Vec<(u8, u32, Option<u32>)> {
+        let mut timings: Vec<_> = song
+            .note_blocks()
+            .map(|b| (b.key.byte(), b.start_time, b.stop_time))
+            .collect();
+        timings.sort();
+        timings
+    }
+
+    #[test]
+    fn written_midi_reads_back_with_the_same_timings() {
+        let original = song(
+            vec![
+                note(60, 0, 480),
+                note(64, 0, 960),
+                note(67, 720, 1200),
+                note(72, 1440, 2400),
+                note(48, 2000, 3000),
+            ],
+            &[(0, 500_000), (960, 250_000), (1920, 1_000_000)],
+        );
+        let path =
+            std::env::temp_dir().join(format!("piano1-{}-roundtrip.mid", std::process::id()));
+        original.write_midi(&path).unwrap();
+        let read = Song::read(&path);
+        let _ = std::fs::remove_file(&path);
+        let read = read.unwrap();
+
+        assert_eq!(timings(&read), timings(&original));
+        assert_eq!(read.tempo_changes().len(), 3);
+        // two quarters at 120 bpm and one at 240 bpm in, held for one more
+        // at 240 bpm and one at 60 bpm
+        assert!(timings(&read).contains(&(72, 1_250_000, Some(2_500_000))));
+    }
+
+    #[test]
+    fn odd_measures_get_a_signature_that_fits() {
+        let tpq = TICKS_PER_QUARTER_NOTE as u32;
+        assert_eq!(odd_measure_signature(tpq * 4, tpq, (4, 4)), None);
+        assert_eq!(odd_measure_signature(tpq * 3, tpq, (4, 4)), Some((3, 4)));
+        // an eighth note pickup in 4/4, a sixteenth one in 6/8
+        assert_eq!(odd_measure_signature(tpq / 2, tpq, (4, 4)), Some((1, 8)));
+        assert_eq!(odd_measure_signature(tpq / 4, tpq, (6, 8)), Some((1, 16)));
+    }
+
+    #[test]
+    fn measures_survive_a_zero_denominator() {
+        let mut song = song(vec![note(60, 0, 4000)], &[(0, 500_000)]);
+        song.time_signatures[0].denominator = 0;
+        assert!(!song.measures().is_empty());
+    }
 }
@@ -0,0 +1,57 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustysynth::{SoundFont, Synthesizer, SynthesizerSettings};
+
+use crate::audio::Render;
+use crate::player::MidiSink;
+
+/// Plays notes with the samples of an SF2 SoundFont. Program changes pick
+/// the General MIDI instrument per channel, like on any GM device.
+pub struct SoundFontSynth {
+    synthesizer: Synthesizer,
+    left: Vec<f32>,
+    right: Vec<f32>,
+}
+
+impl SoundFontSynth {
+    pub fn load(path: &Path, sample_rate: u32) -> Result<Self, Box<dyn Error>> {
+        let mut file = File::open(path)
+            .map_err(|why| format!("could not open {}: {}", path.display(), why))?;
+        let sound_font = Arc::new(SoundFont::new(&mut file)?);
+        let settings = SynthesizerSettings::new(sample_rate as i32);
+
+        Ok(Self {
+            synthesizer: Synthesizer::new(&sound_font, &settings)?,
+            left: vec![],
+            right: vec![],
+        })
+    }
+}
+
+impl MidiSink for SoundFontSynth {
+    fn send(&mut self, message: &[u8]) {
+        if message.is_empty() || message[0] >= 0xF0 {
+            return;
+        }
+        self.synthesizer.process_midi_message(
+            (message[0] & 0x0F) as i32,
+            (message[0] & 0xF0) as i32,
+            message.get(1).copied().unwrap_or(0) as i32,
+            message.get(2).copied().unwrap_or(0) as i32,
+        );
+    }
+}
+
+impl Render for SoundFontSynth {
+    fn render(&mut self, out: &mut [f32]) {
+        self.left.resize(out.len(), 0.);
+        self.right.resize(out.len(), 0.);
+        self.synthesizer.render(&mut self.left, &mut self.right);
+        for ((sample, left), right) in out.iter_mut().zip(&self.left).zip(&self.right) {
+            *sample = (left + right) / 2.;
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use macroquad::prelude::*;
+use midix::prelude::Key;
+
+use crate::song::Hand;
+use crate::weakness::{Category, Tally, Weaknesses};
+
+const MARGIN: f32 = 60.;
+const LINE_HEIGHT: f32 = 36.;
+/// Categories with fewer notes than this say more about luck than skill.
+const MIN_NOTES: u32 = 5;
+const WORST_SHOWN: usize = 3;
+
+/// Weaknesses across all songs, as sentences. The keyboard below them is
+/// heat-colored by the screen.
+pub struct StatisticsView {
+    weaknesses: Weaknesses,
+}
+
+fn key_name(key: u8) -> String {
+    match Key::from_databyte(key) {
+        Ok(key) => format!("{}{}", key.note(), key.octave()),
+        Err(_) => key.to_string(),
+    }
+}
+
+fn timing(tally: &Tally) -> String {
+    match tally.mean_offset() {
+        Some(offset) if offset >= 0. => format!("late on average by {:.0}ms", offset * 1_000.),
+        Some(offset) => format!("early on average by {:.0}ms", -offset * 1_000.),
+        None => "never hit".to_string(),
+    }
+}
+
+impl StatisticsView {
+    pub fn new(weaknesses: Weaknesses) -> Self {
+        Self { weaknesses }
+    }
+
+    /// How often the key was missed or played late, from 0 to 1, if it was
+    /// ever played.
+    pub fn key_heat(&self, key: Key) -> Option<f32> {
+        let tally = self.weaknesses.get(&Category::Key(key.byte()))?;
+        (tally.notes > 0).then(|| (tally.misses + tally.late) as f32 / tally.notes as f32)
+    }
+
+    /// Categories worth talking about, worst first by `badness`.
+    fn worst(
+        &self,
+        filter: impl Fn(&Category) -> bool,
+        badness: impl Fn(&Tally) -> f32,
+    ) -> Vec<(&Category, &Tally)> {
+        let mut worst: Vec<_> = self
+            .weaknesses
+            .tallies()
+            .filter(|(category, tally)| filter(category) && tally.notes >= MIN_NOTES)
+            .collect();
+        worst.sort_by(|(_, a), (_, b)| badness(b).total_cmp(&badness(a)));
+        worst.truncate(WORST_SHOWN);
+        worst
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let mut lines = vec![];
+
+        for (category, tally) in self.worst(|c| matches!(c, Category::Key(_)), Tally::miss_rate) {
+            if let Category::Key(key) = category {
+                lines.push(format!(
+                    "you miss {} {:.0}% of the time",
+                    key_name(*key),
+                    tally.miss_rate() * 100.
+                ));
+            }
+        }
+
+        for hand in [Hand::Left, Hand::Right] {
+            if let Some(tally) = self.weaknesses.get(&Category::Hand(hand)) {
+                lines.push(format!(
+                    "{} hand: {:.0}% missed, {}",
+                    hand.name(),
+                    tally.miss_rate() * 100.,
+                    timing(tally)
+                ));
+            }
+        }
+
+        let jumps = self.worst(
+            |c| matches!(c, Category::Jump(..)),
+            |t| t.mean_offset().unwrap_or(0.).abs() + t.miss_rate(),
+        );
+        for (category, tally) in jumps {
+            if let Category::Jump(hand, jump) = category {
+                lines.push(format!(
+                    "{}-hand {} are {}, {:.0}% missed",
+                    hand.name(),
+                    jump.describe(),
+                    timing(tally),
+                    tally.miss_rate() * 100.
+                ));
+            }
+        }
+
+        let chords = self.worst(|c| matches!(c, Category::Chord(_)), Tally::miss_rate);
+        for (category, tally) in chords {
+            if let Category::Chord(shape) = category {
+                lines.push(format!(
+                    "chords shaped {}: {:.0}% of their notes missed",
+                    shape,
+                    tally.miss_rate() * 100.
+                ));
+            }
+        }
+
+        lines
+    }
+
+    pub fn draw(&self) {
+        if self.weaknesses.is_empty() {
+            draw_text(
+                "nothing practiced yet, S to go back",
+                MARGIN,
+                MARGIN,
+                32.,
+                WHITE,
+            );
+            return;
+        }
+
+        draw_text(
+            "weaknesses across all songs, S to go back",
+            MARGIN,
+            MARGIN,
+            32.,
+            WHITE,
+        );
+        for (i, line) in self.lines().iter().enumerate() {
+            draw_text(
+                line,
+                MARGIN,
+                MARGIN + LINE_HEIGHT * (i + 2) as f32,
+                28.,
+                LIGHTGRAY,
+            );
+        }
+    }
+}
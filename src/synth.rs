@@ -0,0 +1,224 @@
+use std::f32::consts::PI;
+
+use crate::audio::Render;
+use crate::player::{self, MidiSink};
+
+const MAX_VOICES: usize = 64;
+const SUSTAIN_PEDAL: u8 = 64;
+const ALL_NOTES_OFF: u8 = 123;
+const MASTER_GAIN: f32 = 0.25;
+
+// relative amplitude and extra decay rate (per second) of the first partials,
+// higher partials die out sooner, which is what makes a struck string sound
+const PARTIALS: [(f32, f32); 6] = [
+    (1.0, 0.0),
+    (0.5, 0.6),
+    (0.25, 1.2),
+    (0.15, 1.8),
+    (0.08, 2.4),
+    (0.04, 3.0),
+];
+
+#[derive(Clone, Copy)]
+pub struct Adsr {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl Adsr {
+    pub fn piano() -> Self {
+        Self {
+            attack: 0.005,
+            decay: 1.5,
+            sustain: 0.35,
+            release: 0.25,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Done,
+}
+
+struct Voice {
+    channel: u8,
+    key: u8,
+    frequency: f32,
+    gain: f32,
+    time: f32,
+    stage: Stage,
+    level: f32,
+    release_level: f32,
+    release_time: f32,
+    held_by_pedal: bool,
+}
+
+impl Voice {
+    fn release(&mut self) {
+        if self.stage != Stage::Release && self.stage != Stage::Done {
+            self.stage = Stage::Release;
+            self.release_level = self.level;
+            self.release_time = 0.;
+        }
+    }
+
+    fn next_sample(&mut self, adsr: &Adsr, dt: f32) -> f32 {
+        self.level = match self.stage {
+            Stage::Attack => {
+                if self.time >= adsr.attack {
+                    self.stage = Stage::Decay;
+                }
+                (self.time / adsr.attack).min(1.)
+            }
+            Stage::Decay => {
+                let t = self.time - adsr.attack;
+                if t >= adsr.decay {
+                    self.stage = Stage::Sustain;
+                }
+                1. - (1. - adsr.sustain) * (t / adsr.decay).min(1.)
+            }
+            Stage::Sustain => adsr.sustain,
+            Stage::Release => {
+                self.release_time += dt;
+                if self.release_time >= adsr.release {
+                    self.stage = Stage::Done;
+                }
+                self.release_level * (1. - self.release_time / adsr.release).max(0.)
+            }
+            Stage::Done => 0.,
+        };
+
+        let mut sample = 0.;
+        for (i, (amplitude, decay)) in PARTIALS.iter().enumerate() {
+            let harmonic = (i + 1) as f32;
+            // partials past the Nyquist frequency would alias down into
+            // audible, unrelated tones
+            if self.frequency * harmonic >= 0.5 / dt {
+                break;
+            }
+            sample += amplitude
+                * (-decay * self.time).exp()
+                * (2. * PI * self.frequency * harmonic * self.time).sin();
+        }
+
+        self.time += dt;
+        sample * self.level * self.gain
+    }
+}
+
+/// A small polyphonic additive synth with a piano-ish envelope. It is driven
+/// by raw MIDI messages, the same ones the `SongPlayer` sends to a port.
+pub struct Synth {
+    sample_rate: u32,
+    adsr: Adsr,
+    voices: Vec<Voice>,
+    sustain: [bool; 16],
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            adsr: Adsr::piano(),
+            voices: vec![],
+            sustain: [false; 16],
+        }
+    }
+
+    pub fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        if self.voices.len() >= MAX_VOICES {
+            self.voices.remove(0);
+        }
+        let velocity = velocity as f32 / 127.;
+        self.voices.push(Voice {
+            channel,
+            key,
+            frequency: 440. * 2f32.powf((key as f32 - 69.) / 12.),
+            gain: velocity * velocity,
+            time: 0.,
+            stage: Stage::Attack,
+            level: 0.,
+            release_level: 0.,
+            release_time: 0.,
+            held_by_pedal: false,
+        });
+    }
+
+    pub fn note_off(&mut self, channel: u8, key: u8) {
+        let sustain = self.sustain[channel as usize];
+        for voice in self.voices.iter_mut() {
+            if voice.channel == channel && voice.key == key && !voice.held_by_pedal {
+                if sustain {
+                    voice.held_by_pedal = true;
+                } else {
+                    voice.release();
+                }
+            }
+        }
+    }
+
+    pub fn set_sustain(&mut self, channel: u8, down: bool) {
+        self.sustain[channel as usize] = down;
+        if !down {
+            for voice in self.voices.iter_mut() {
+                if voice.channel == channel && voice.held_by_pedal {
+                    voice.held_by_pedal = false;
+                    voice.release();
+                }
+            }
+        }
+    }
+
+    pub fn all_notes_off(&mut self, channel: u8) {
+        self.sustain[channel as usize] = false;
+        for voice in self.voices.iter_mut() {
+            if voice.channel == channel {
+                voice.held_by_pedal = false;
+                voice.release();
+            }
+        }
+    }
+}
+
+impl Render for Synth {
+    /// Mixes all voices into `out`, a mono buffer.
+    fn render(&mut self, out: &mut [f32]) {
+        let dt = 1. / self.sample_rate as f32;
+        for sample in out.iter_mut() {
+            let mut mixed = 0.;
+            for voice in self.voices.iter_mut() {
+                mixed += voice.next_sample(&self.adsr, dt);
+            }
+            *sample = (mixed * MASTER_GAIN).tanh();
+        }
+        self.voices.retain(|v| v.stage != Stage::Done);
+    }
+}
+
+impl MidiSink for Synth {
+    fn send(&mut self, message: &[u8]) {
+        if message.len() < 2 {
+            return;
+        }
+        let channel = message[0] & 0x0F;
+        match (message[0] & 0xF0, message.get(2)) {
+            (player::NOTE_ON, Some(0)) | (player::NOTE_OFF, Some(_)) => {
+                self.note_off(channel, message[1])
+            }
+            (player::NOTE_ON, Some(velocity)) => self.note_on(channel, message[1], *velocity),
+            (player::CONTROL_CHANGE, Some(value)) => match message[1] {
+                SUSTAIN_PEDAL => self.set_sustain(channel, *value >= 64),
+                ALL_NOTES_OFF => self.all_notes_off(channel),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::config;
+use crate::grading::{Grade, LATE_THRESHOLD, Verdict};
+use crate::song::{Hand, Song};
+
+/// How far a hand moved from its previous note.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub enum Jump {
+    /// Up to a whole tone.
+    Step,
+    /// Up to a fifth.
+    Skip,
+    /// Up to an octave.
+    Leap,
+    OverOctave,
+}
+
+impl Jump {
+    fn of(interval: u8) -> Self {
+        match interval {
+            0..=2 => Jump::Step,
+            3..=7 => Jump::Skip,
+            8..=12 => Jump::Leap,
+            _ => Jump::OverOctave,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Jump::Step => "step",
+            Jump::Skip => "skip",
+            Jump::Leap => "leap",
+            Jump::OverOctave => "octave+",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Jump::Step, Jump::Skip, Jump::Leap, Jump::OverOctave]
+            .into_iter()
+            .find(|jump| jump.name() == name)
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Jump::Step => "steps",
+            Jump::Skip => "skips up to a fifth",
+            Jump::Leap => "leaps up to an octave",
+            Jump::OverOctave => "jumps over an octave",
+        }
+    }
+}
+
+/// What a note's mistakes are counted under, besides the note itself.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub enum Category {
+    Key(u8),
+    Hand(Hand),
+    Jump(Hand, Jump),
+    /// Semitones between neighbouring chord notes, from the bottom up,
+    /// e.g. `4+3` for a major triad.
+    Chord(String),
+}
+
+impl Category {
+    fn to_words(&self) -> String {
+        match self {
+            Category::Key(key) => format!("key {}", key),
+            Category::Hand(hand) => format!("hand {}", hand.name()),
+            Category::Jump(hand, jump) => format!("jump {} {}", hand.name(), jump.name()),
+            Category::Chord(shape) => format!("chord {}", shape),
+        }
+    }
+
+    fn from_words(words: &[&str]) -> Option<Self> {
+        match words {
+            ["key", key] => Some(Category::Key(key.parse().ok()?)),
+            ["hand", hand] => Some(Category::Hand(Hand::from_name(hand)?)),
+            ["jump", hand, jump] => Some(Category::Jump(
+                Hand::from_name(hand)?,
+                Jump::from_name(jump)?,
+            )),
+            ["chord", shape] => Some(Category::Chord(shape.to_string())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Tally {
+    pub notes: u32,
+    pub misses: u32,
+    pub late: u32,
+    pub hits: u32,
+    /// Sum of the hit offsets, in seconds.
+    pub offset_sum: f32,
+}
+
+impl Tally {
+    pub fn miss_rate(&self) -> f32 {
+        if self.notes == 0 {
+            return 0.;
+        }
+        self.misses as f32 / self.notes as f32
+    }
+
+    /// Average distance of the hits from their notes, late when positive.
+    pub fn mean_offset(&self) -> Option<f32> {
+        (self.hits > 0).then(|| self.offset_sum / self.hits as f32)
+    }
+
+    fn merge(&mut self, other: &Tally) {
+        self.notes += other.notes;
+        self.misses += other.misses;
+        self.late += other.late;
+        self.hits += other.hits;
+        self.offset_sum += other.offset_sum;
+    }
+}
+
+/// Mistakes across every song practiced.
+#[derive(Default)]
+pub struct Weaknesses {
+    tallies: BTreeMap<Category, Tally>,
+}
+
+impl Weaknesses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tallies.is_empty()
+    }
+
+    pub fn get(&self, category: &Category) -> Option<&Tally> {
+        self.tallies.get(category)
+    }
+
+    pub fn tallies(&self) -> impl Iterator<Item = (&Category, &Tally)> {
+        self.tallies.iter()
+    }
+
+    pub fn merge(&mut self, other: &Weaknesses) {
+        for (category, tally) in other.tallies.iter() {
+            self.tallies
+                .entry(category.clone())
+                .or_default()
+                .merge(tally);
+        }
+    }
+
+    /// Counts every judged song note under its key, hand, the jump that led
+    /// to it and the chord it is part of.
+    pub fn add_grade(&mut self, song: &Song, grade: &Grade) {
+        // keys starting together, per hand and start time
+        let mut groups: BTreeMap<(Hand, u32), Vec<u8>> = BTreeMap::new();
+        for block in song.note_blocks() {
+            groups
+                .entry((block.hand, block.start_time))
+                .or_default()
+                .push(block.key.byte());
+        }
+        for keys in groups.values_mut() {
+            keys.sort();
+        }
+
+        for judgment in grade.judgments.iter() {
+            let Some(note) = &judgment.note else {
+                continue;
+            };
+            let key = note.key.byte();
+
+            let mut categories = vec![Category::Key(key), Category::Hand(note.hand)];
+            if let Some((_, previous)) = groups
+                .range((note.hand, 0)..(note.hand, note.start_time))
+                .next_back()
+                && let Some(interval) = previous.iter().map(|k| k.abs_diff(key)).min()
+            {
+                categories.push(Category::Jump(note.hand, Jump::of(interval)));
+            }
+            if let Some(chord) = groups.get(&(note.hand, note.start_time))
+                && chord.len() > 1
+            {
+                let shape: Vec<String> = chord
+                    .windows(2)
+                    .map(|pair| (pair[1] - pair[0]).to_string())
+                    .collect();
+                categories.push(Category::Chord(shape.join("+")));
+            }
+
+            for category in categories {
+                let tally = self.tallies.entry(category).or_default();
+                tally.notes += 1;
+                match judgment.verdict {
+                    Verdict::Hit(offset) => {
+                        tally.hits += 1;
+                        tally.offset_sum += offset;
+                        if offset > LATE_THRESHOLD {
+                            tally.late += 1;
+                        }
+                    }
+                    Verdict::Miss => tally.misses += 1,
+                    Verdict::Wrong => (),
+                }
+            }
+        }
+    }
+}
+
+/// `<data dir>/weaknesses.tsv`, one category per line with its counts.
+fn weaknesses_file() -> PathBuf {
+    config::data_dir().join("weaknesses.tsv")
+}
+
+pub fn load() -> Weaknesses {
+    let mut weaknesses = Weaknesses::new();
+    let Ok(content) = fs::read_to_string(weaknesses_file()) else {
+        return weaknesses;
+    };
+
+    for line in content.lines() {
+        let Some((category, counts)) = line.split_once('\t') else {
+            continue;
+        };
+        let words: Vec<&str> = category.split_whitespace().collect();
+        let counts: Vec<&str> = counts.split('\t').collect();
+        if let Some(category) = Category::from_words(&words)
+            && let [notes, misses, late, hits, offset_sum] = counts[..]
+            && let (Ok(notes), Ok(misses), Ok(late), Ok(hits), Ok(offset_sum)) = (
+                notes.parse(),
+                misses.parse(),
+                late.parse(),
+                hits.parse(),
+                offset_sum.parse(),
+            )
+        {
+            weaknesses.tallies.insert(
+                category,
+                Tally {
+                    notes,
+                    misses,
+                    late,
+                    hits,
+                    offset_sum,
+                },
+            );
+        }
+    }
+    weaknesses
+}
+
+/// Adds `weaknesses` to what is already stored.
+pub fn save(weaknesses: &Weaknesses) -> io::Result<()> {
+    let mut all = load();
+    all.merge(weaknesses);
+
+    let content: String = all
+        .tallies
+        .iter()
+        .map(|(category, tally)| {
+            format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\n",
+                category.to_words(),
+                tally.notes,
+                tally.misses,
+                tally.late,
+                tally.hits,
+                tally.offset_sum
+            )
+        })
+        .collect();
+
+    fs::create_dir_all(config::data_dir())?;
+    fs::write(weaknesses_file(), content)
+}